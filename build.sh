@@ -5,7 +5,7 @@ set -e
 export CHANNEL="release"
 build_sysroot="clif"
 target_dir='build'
-oldbe=''
+features=''
 while [[ $# != 0 ]]; do
     case $1 in
         "--debug")
@@ -19,12 +19,12 @@ while [[ $# != 0 ]]; do
             target_dir=$2
             shift
             ;;
-        "--oldbe")
-            oldbe='--features oldbe'
+        "--llvm-fallback")
+            features="$features --features llvm_fallback"
             ;;
         *)
             echo "Unknown flag '$1'"
-            echo "Usage: ./build.sh [--debug] [--sysroot none|clif|llvm] [--target-dir DIR] [--oldbe]"
+            echo "Usage: ./build.sh [--debug] [--sysroot none|clif|llvm] [--target-dir DIR] [--llvm-fallback]"
             exit 1
             ;;
     esac
@@ -43,10 +43,11 @@ else
    echo "Unsupported os"
    exit 1
 fi
+# The jit_driver example is used by scripts/tests.sh to test embedding the jit.
 if [[ "$CHANNEL" == "release" ]]; then
-    cargo build $oldbe --release
+    cargo build $features --release --lib --bins --example jit_driver
 else
-    cargo build $oldbe
+    cargo build $features --lib --bins --example jit_driver
 fi
 
 source scripts/ext_config.sh
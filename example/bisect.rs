@@ -0,0 +1,11 @@
+// scripts/tests.sh makes cg_clif replace `broken` with a trap and checks that scripts/bisect.sh
+// finds it.
+
+#[inline(never)]
+fn broken(a: u32) -> u32 {
+    a + 1
+}
+
+fn main() {
+    assert_eq!(broken(1), 2);
+}
@@ -0,0 +1,22 @@
+// Test for the function cache: `get_x` has to be compiled again when the offset of `x` changes,
+// even though its MIR doesn't. scripts/tests.sh changes the type of `First` between two
+// incremental compilations.
+
+type First = u8;
+
+#[repr(C)]
+struct Foo {
+    first: First,
+    x: u32,
+}
+
+#[inline(never)]
+fn get_x(foo: &Foo) -> u32 {
+    foo.x
+}
+
+fn main() {
+    let foo = Foo { first: 1, x: 42 };
+    assert_eq!(foo.first, 1);
+    assert_eq!(get_x(&foo), 42);
+}
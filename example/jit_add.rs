@@ -0,0 +1,6 @@
+// JIT compiled as library by examples/jit_driver.rs, which calls `add`.
+
+#[no_mangle]
+pub extern "C" fn add(a: u32, b: u32) -> u32 {
+    a + b
+}
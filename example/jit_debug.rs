@@ -0,0 +1,11 @@
+// scripts/tests.sh runs this in gdb and expects the backtrace of the abort to point into this
+// file, which only works when the jitted functions are registered with gdb.
+
+#[inline(never)]
+fn inner() {
+    std::process::abort();
+}
+
+fn main() {
+    inner();
+}
@@ -0,0 +1,11 @@
+// The static library is built by scripts/tests.sh. Only the member defining `used_fn` may be
+// linked, the other member has a constructor which aborts and references an undefined symbol.
+
+#[link(name = "jit_static_lib", kind = "static")]
+extern "C" {
+    fn used_fn() -> i32;
+}
+
+fn main() {
+    assert_eq!(unsafe { used_fn() }, 42);
+}
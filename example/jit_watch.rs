@@ -0,0 +1,22 @@
+// scripts/tests.sh runs a copy of this in watch mode and changes `value` to return 2 once the
+// program is waiting for it. The program exits as soon as the reloaded `value` is called.
+
+use std::time::{Duration, Instant};
+
+#[inline(never)]
+fn value() -> u32 {
+    1
+}
+
+fn main() {
+    println!("waiting for reload");
+    let start = Instant::now();
+    while value() != 2 {
+        assert!(
+            start.elapsed() < Duration::from_secs(60),
+            "`value` was never reloaded"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    println!("reloaded");
+}
@@ -0,0 +1,42 @@
+// Test that codegen units using `llvm_asm!` are compiled using the LLVM backend when
+// `CG_CLIF_LLVM_FALLBACK=1` is set and can call and be called from code compiled by cg_clif.
+//
+// When run with the `trap` argument, a function in a codegen unit without `llvm_asm!` traps.
+// scripts/tests.sh checks that the trap handler of cg_clif reports it, which only happens when
+// that codegen unit and the one of `main` were compiled by cg_clif.
+
+#![feature(core_intrinsics, llvm_asm)]
+
+mod with_asm {
+    #[inline(never)]
+    pub fn add_llvm_asm(a: u64, b: u64) -> u64 {
+        let c: u64;
+        unsafe {
+            llvm_asm!("lea ($1, $2), $0" : "=r"(c) : "r"(a), "r"(b));
+        }
+        c
+    }
+}
+
+mod without_asm {
+    #[inline(never)]
+    pub fn add(a: u64, b: u64) -> u64 {
+        super::with_asm::add_llvm_asm(a, 0) + b
+    }
+
+    #[inline(never)]
+    pub fn trap() {
+        unsafe {
+            std::intrinsics::abort();
+        }
+    }
+}
+
+fn main() {
+    assert_eq!(with_asm::add_llvm_asm(1, 2), 3);
+    assert_eq!(without_asm::add(40, 2), 42);
+
+    if std::env::args().nth(1).as_deref() == Some("trap") {
+        without_asm::trap();
+    }
+}
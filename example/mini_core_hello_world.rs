@@ -292,18 +292,18 @@ struct ExternTypeWrapper {
     #[cfg(not(jit))]
     test_tls();
 
-    #[cfg(all(not(jit), target_os = "linux"))]
+    #[cfg(target_os = "linux")]
     unsafe {
         global_asm_test();
     }
 }
 
-#[cfg(all(not(jit), target_os = "linux"))]
+#[cfg(target_os = "linux")]
 extern "C" {
     fn global_asm_test();
 }
 
-#[cfg(all(not(jit), target_os = "linux"))]
+#[cfg(target_os = "linux")]
 global_asm! {
     "
     .global global_asm_test
@@ -1,4 +1,4 @@
-#![feature(core_intrinsics, generators, generator_trait, is_sorted)]
+#![feature(core_intrinsics, generators, generator_trait, is_sorted, asm)]
 
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
@@ -15,8 +15,6 @@ fn main() {
     let stderr = ::std::io::stderr();
     let mut stderr = stderr.lock();
 
-    // FIXME support lazy jit when multi threading
-    #[cfg(not(lazy_jit))]
     std::thread::spawn(move || {
         println!("Hello from another thread!");
     });
@@ -105,6 +103,11 @@ fn main() {
         test_simd();
     }
 
+    test_atomics();
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    test_asm_reg_classes();
+
     Box::pin(move |mut _task_context| {
         yield ();
     }).as_mut().resume(0);
@@ -327,6 +330,105 @@ fn test_checked_mul() {
     assert_eq!(i64::MIN.checked_mul(i64::MIN), None);
 }
 
+fn test_atomics() {
+    use std::sync::atomic::*;
+
+    let a = AtomicU8::new(200);
+    assert_eq!(a.fetch_add(100, Ordering::SeqCst), 200);
+    assert_eq!(a.fetch_sub(1, Ordering::Relaxed), 44);
+    assert_eq!(a.fetch_nand(0xf0, Ordering::AcqRel), 43);
+    assert_eq!(a.load(Ordering::Acquire), !(43 & 0xf0));
+    assert_eq!(a.compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst), Err(!(43 & 0xf0)));
+    assert_eq!(a.compare_exchange(!(43 & 0xf0), 1, Ordering::SeqCst, Ordering::SeqCst), Ok(!(43 & 0xf0)));
+
+    let b = AtomicI64::new(-5);
+    assert_eq!(b.fetch_max(3, Ordering::SeqCst), -5);
+    assert_eq!(b.fetch_min(-10, Ordering::SeqCst), 3);
+    assert_eq!(b.swap(7, Ordering::Release), -10);
+    assert_eq!(b.fetch_and(6, Ordering::SeqCst), 7);
+    assert_eq!(b.fetch_or(1, Ordering::SeqCst), 6);
+    assert_eq!(b.fetch_xor(2, Ordering::SeqCst), 7);
+    assert_eq!(b.load(Ordering::SeqCst), 5);
+
+    let c = AtomicUsize::new(usize::MAX);
+    assert_eq!(c.fetch_min(1, Ordering::SeqCst), usize::MAX);
+    assert_eq!(c.fetch_max(2, Ordering::SeqCst), 1);
+    c.store(0, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+    compiler_fence(Ordering::SeqCst);
+    assert_eq!(c.load(Ordering::SeqCst), 0);
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn test_asm_reg_classes() {
+    let a = 5u64;
+    let b = 7u64;
+    let c: u64;
+    unsafe {
+        asm!("lea {}, [{} + {}]", out(reg) c, in(reg) a, in(reg) b);
+    }
+    assert_eq!(c, 12);
+
+    let mut x = 0x1234u32;
+    unsafe {
+        asm!("shl {0:e}, 4", "add {0:e}, {1:e}", inout(reg) x, in(reg_abcd) 1u32);
+    }
+    assert_eq!(x, 0x12341);
+
+    let mut y = 0x12u16;
+    unsafe {
+        asm!("xchg {0:l}, {0:h}", inout(reg_abcd) y);
+    }
+    assert_eq!(y, 0x1200);
+
+    let z: u8;
+    unsafe {
+        asm!("mov {}, 42", out(reg_byte) z);
+    }
+    assert_eq!(z, 42);
+
+    unsafe {
+        test_asm_xmm_reg();
+    }
+
+    // Explicit registers and register classes mixed, the allocator has to avoid rax.
+    let q: u64;
+    unsafe {
+        asm!("mov {}, rax", out(reg) q, in("rax") 3u64);
+    }
+    assert_eq!(q, 3);
+
+    static mut ASM_STATIC: u64 = 0;
+    extern "C" fn asm_sym_fn(a: u64) -> u64 {
+        a * 3
+    }
+    let r: u64;
+    unsafe {
+        asm!(
+            "mov qword ptr [rip + {static}], {k}",
+            "mov rdi, {k} + 1",
+            "call {f}",
+            static = sym ASM_STATIC,
+            f = sym asm_sym_fn,
+            k = const 4,
+            out("rdi") _, out("rax") r,
+            // Caller saved registers
+            out("rcx") _, out("rdx") _, out("rsi") _, out("r8") _, out("r9") _, out("r10") _,
+            out("r11") _,
+        );
+        assert_eq!(ASM_STATIC, 4);
+    }
+    assert_eq!(r, 15);
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[target_feature(enable = "sse")]
+unsafe fn test_asm_xmm_reg() {
+    let f: f64;
+    asm!("addsd {0}, {1}", inout(xmm_reg) 1.5f64 => f, in(xmm_reg) 2.25f64);
+    assert_eq!(f, 3.75);
+}
+
 #[derive(PartialEq)]
 enum LoopState {
     Continue(()),
@@ -0,0 +1,29 @@
+// Run using the libtest harness in JIT mode. `filtered_out` is skipped using a test filter.
+// scripts/tests.sh checks the test summary to make sure the harness arguments have been applied.
+// It passes `--cfg jit_test_arg="a\x1fb"` to check that the rustc arguments reach the processes
+// running the tests unchanged.
+
+#[test]
+fn it_works() {
+    let v = (1..=4).collect::<Vec<u32>>();
+    assert_eq!(v.iter().sum::<u32>(), 10);
+}
+
+#[test]
+#[should_panic(expected = "expected panic")]
+fn it_panics() {
+    panic!("expected panic");
+}
+
+#[test]
+fn it_runs_in_a_test_process() {
+    // With `-Cpanic=abort` every test runs in a separate process, which JIT compiles the test
+    // harness again.
+    assert!(std::env::var_os("__RUST_TEST_INVOKE").is_some());
+    assert!(cfg!(jit_test_arg = "a\x1fb"));
+}
+
+#[test]
+fn filtered_out() {
+    panic!("this test should have been filtered out by the test harness arguments");
+}
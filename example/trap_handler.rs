@@ -0,0 +1,8 @@
+#![feature(core_intrinsics)]
+
+fn main() {
+    println!("before trap");
+    unsafe {
+        std::intrinsics::abort();
+    }
+}
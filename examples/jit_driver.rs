@@ -0,0 +1,29 @@
+//! A minimal program embedding the jit using the rustc driver API. It takes rustc arguments, JIT
+//! compiles the given library crate, calls its `add` function and prints the result. This is used
+//! by scripts/tests.sh to test the `jit_library_callback` of the codegen backend.
+
+#![feature(rustc_private)]
+
+extern crate rustc_driver;
+
+fn main() {
+    rustc_driver::install_ice_hook();
+    let exit_code = rustc_driver::catch_with_exit_code(|| {
+        let args = std::env::args().collect::<Vec<_>>();
+        let mut callbacks = rustc_driver::TimePassesCallbacks::default();
+        let mut run_compiler = rustc_driver::RunCompiler::new(&args, &mut callbacks);
+        run_compiler.set_make_codegen_backend(Some(Box::new(move |_| {
+            Box::new(rustc_codegen_cranelift::CraneliftCodegenBackend {
+                config: None,
+                jit_library_callback: Some(Box::new(|jit_handle| {
+                    let add = jit_handle.get_symbol("add").expect("`add` is not exported");
+                    let add: extern "C" fn(u32, u32) -> u32 = unsafe { std::mem::transmute(add) };
+                    println!("add(1, 2) = {}", add(1, 2));
+                    unsafe { jit_handle.free() };
+                })),
+            })
+        })));
+        run_compiler.run()
+    });
+    std::process::exit(exit_code)
+}
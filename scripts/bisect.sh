@@ -0,0 +1,77 @@
+#!/bin/bash
+# Finds the function cg_clif miscompiles, see src/driver/bisect.rs.
+#
+# Usage: scripts/bisect.sh <crate name> <build command> <test command>
+#
+# The build command has to compile the crate again every time it runs and has to pass
+# `-Clink-dead-code`, for example:
+#
+#     touch src/main.rs && RUSTFLAGS=-Clink-dead-code ../cargo.sh build
+#
+# The test command has to exit with a non-zero status when the miscompilation is observed.
+
+set -e
+
+if [[ $# -ne 3 ]]; then
+    echo "Usage: $0 <crate name> <build command> <test command>"
+    exit 1
+fi
+
+export CG_CLIF_BISECT_CRATE="$1"
+build_cmd="$2"
+test_cmd="$3"
+
+export CG_CLIF_BISECT_DIR
+CG_CLIF_BISECT_DIR=$(mktemp -d)
+# Functions of a different range must not be reused from the incremental cache.
+export CARGO_INCREMENTAL=0
+
+# Compile the functions `$1..$2` using cg_clif and everything else using LLVM and run the test.
+# Succeeds if the test passes.
+function test_range() {
+    rm -f "$CG_CLIF_BISECT_DIR"/*.clif
+    echo "[BISECT] compiling functions $1..$2 using cg_clif"
+    if ! CG_CLIF_BISECT_RANGE="$1..$2" bash -c "$build_cmd" > /dev/null; then
+        echo "The build command failed"
+        exit 1
+    fi
+    bash -c "$test_cmd" > /dev/null 2>&1
+}
+
+if ! test_range 0 0; then
+    echo "The test fails when compiling everything using LLVM"
+    exit 1
+fi
+count=$(wc -l < "$CG_CLIF_BISECT_DIR/functions.txt")
+if test_range 0 "$count"; then
+    echo "The test passes when compiling all $count functions using cg_clif"
+    exit 1
+fi
+
+start=0
+end=$count
+while [[ $((end - start)) -gt 1 ]]; do
+    mid=$(((start + end) / 2))
+    if ! test_range "$start" "$mid"; then
+        end=$mid
+    elif ! test_range "$mid" "$end"; then
+        start=$mid
+    else
+        echo "The test only fails when compiling functions from both $start..$mid and $mid..$end" \
+            "using cg_clif:"
+        sed -n "$((start + 1)),${end}p" "$CG_CLIF_BISECT_DIR/functions.txt"
+        exit 1
+    fi
+done
+
+test_range "$start" "$end" || true
+culprit=$(sed -n "$((start + 1))p" "$CG_CLIF_BISECT_DIR/functions.txt")
+symbol=$(echo "$culprit" | cut -f2)
+echo
+echo "The test fails when compiling $(echo "$culprit" | cut -f3) using cg_clif"
+echo
+awk -v symbol="; symbol $symbol" '
+    /^function / { text = ""; in_func = 1 }
+    in_func { text = text $0 "\n" }
+    /^}/ { if (in_func && index(text, symbol)) printf "%s", text; in_func = 0 }
+' "$CG_CLIF_BISECT_DIR"/*.clif
@@ -9,10 +9,36 @@ TOOLCHAIN=$(cat "$dir/rust-toolchain")
 cmd=$1
 shift || true
 
+if [[ "$cmd" = "jit" || "$cmd" = "lazy-jit" || "$cmd" = "watch-jit" ]]; then
+# Arguments after `--` are passed to the jitted program.
+cargo_args=()
+while [[ $# -gt 0 && "$1" != "--" ]]; do
+    cargo_args+=("$1")
+    shift
+done
+shift || true
+CG_CLIF_JIT_ENCODED_ARGS=$(IFS=$'\x1f'; printf '%s' "$*")
+export CG_CLIF_JIT_ENCODED_ARGS
+
+jit_args=()
 if [[ "$cmd" = "jit" ]]; then
-cargo "+${TOOLCHAIN}" rustc "$@" -- -Cllvm-args=mode=jit -Cprefer-dynamic
-elif [[ "$cmd" = "lazy-jit" ]]; then
-cargo "+${TOOLCHAIN}" rustc "$@" -- -Cllvm-args=mode=jit-lazy -Cprefer-dynamic
+    mode=jit
+else
+    mode=jit-lazy
+fi
+if [[ "$cmd" = "watch-jit" ]]; then
+    jit_args=(-Cllvm-args=jit-watch)
+fi
+
+# Load libstd as static library where supported. This is necessary for the jitted program to see
+# its own arguments through `std::env::args()`.
+if [[ "$(rustc -vV | grep host)" = "host: x86_64-unknown-linux-gnu" ]]; then
+    prefer_dynamic=()
+else
+    prefer_dynamic=(-Cprefer-dynamic)
+fi
+
+cargo "+${TOOLCHAIN}" rustc "${cargo_args[@]}" -- -Cllvm-args=mode=$mode "${jit_args[@]}" "${prefer_dynamic[@]}"
 else
 cargo "+${TOOLCHAIN}" "$cmd" "$@"
 fi
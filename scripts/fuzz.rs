@@ -0,0 +1,997 @@
+#!/bin/bash
+#![forbid(unsafe_code)]/* This line is ignored by bash
+# This block is ignored by rustc
+cd $(dirname "$0")/../
+source build/config.sh
+ITERATIONS=$1 SEED=$2 exec $RUSTC $RUSTFLAGS -Cllvm-args=mode=jit -Cprefer-dynamic $0
+#*/
+
+//! This program generates random programs, compiles them using both cg_clif and the LLVM backend
+//! and compares their output.
+//!
+//! Usage: scripts/fuzz.rs [<number of programs> [<seed>]]
+//!
+//! Every program consists of independent tests exercising integer and float arithmetic including
+//! 128bit integers, casts, enums with niches, slices and trait objects. Only operations which are
+//! well-defined for all inputs are used, so every difference in output is a bug in either backend.
+//! All inputs are passed through `opaque` to prevent constant folding. When the output differs,
+//! the tests causing the difference are saved as `example/fuzz_<seed>.rs`, which compares its own
+//! output with the output of the LLVM backend. `scripts/tests.sh` runs all of them.
+//!
+//! This file is specially crafted to be both a valid bash script and valid rust source file. If
+//! executed as bash script this will run the rust source using cg_clif in JIT mode.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of tests in every program.
+const TESTS_PER_PROGRAM: usize = 8;
+
+/// The maximum nesting depth of generated expressions.
+const MAX_DEPTH: u32 = 4;
+
+const PRELUDE: &str = "
+#![allow(warnings)]
+
+use std::fmt::Write;
+
+/// Prevents constant folding of the operations on `x`.
+#[inline(never)]
+fn opaque<T: Copy>(x: T) -> T {
+    unsafe { std::ptr::read_volatile(&x) }
+}
+";
+
+fn main() {
+    let iterations = match std::env::var("ITERATIONS") {
+        Ok(iterations) if !iterations.is_empty() => {
+            iterations.parse().expect("invalid number of programs")
+        }
+        _ => 100,
+    };
+    let first_seed = match std::env::var("SEED") {
+        Ok(seed) if !seed.is_empty() => seed.parse().expect("invalid seed"),
+        _ => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64,
+    };
+    let work_dir = Path::new("target/fuzz");
+    std::fs::create_dir_all(work_dir).unwrap();
+    println!(
+        "[FUZZ] {} programs starting with seed {}",
+        iterations, first_seed
+    );
+
+    let mut failures = 0;
+    for i in 0..iterations {
+        let seed = first_seed.wrapping_add(i);
+        let tests = (0..TESTS_PER_PROGRAM).collect::<Vec<_>>();
+        let mismatch = match check(work_dir, seed, &tests) {
+            Ok(None) => continue,
+            Ok(Some(mismatch)) => mismatch,
+            Err(err) => {
+                eprintln!(
+                    "[FUZZ] seed {}: generated an invalid program: {}",
+                    seed, err
+                );
+                std::process::exit(1);
+            }
+        };
+        failures += 1;
+        match &mismatch.clif {
+            Ok(_) => println!("[FUZZ] seed {}: the output differs from LLVM", seed),
+            Err(err) => println!("[FUZZ] seed {}: cg_clif {}", seed, err),
+        }
+        let reproducer = save_reproducer(work_dir, seed, &tests, mismatch);
+        println!(
+            "[FUZZ] seed {}: saved reproducer {}",
+            seed,
+            reproducer.display()
+        );
+    }
+
+    println!(
+        "[FUZZ] {} of {} programs behaved differently",
+        failures, iterations
+    );
+    if failures != 0 {
+        std::process::exit(1);
+    }
+}
+
+/// The output of a program compiled by LLVM and the output or error of the same program compiled
+/// by cg_clif, which differ.
+struct Mismatch {
+    llvm: String,
+    clif: Result<String, String>,
+}
+
+/// Compile and run `tests` of the program with `seed` using both backends.
+///
+/// Returns an error if the program doesn't work with LLVM, which is a bug in the generator.
+fn check(work_dir: &Path, seed: u64, tests: &[usize]) -> Result<Option<Mismatch>, String> {
+    let source_file = work_dir.join(format!("fuzz_{}.rs", seed));
+    std::fs::write(&source_file, generate_program(seed, tests, None)).unwrap();
+
+    let llvm_exe = work_dir.join(format!("fuzz_{}_llvm", seed));
+    let llvm = compile_and_run(&source_file, &llvm_exe, "rustc", "")
+        .map_err(|err| format!("`{}` {}", source_file.display(), err))?;
+
+    let clif_exe = work_dir.join(format!("fuzz_{}_clif", seed));
+    let clif = compile_and_run(
+        &source_file,
+        &clif_exe,
+        &std::env::var("RUSTC").unwrap(),
+        &std::env::var("RUSTFLAGS").unwrap_or_default(),
+    );
+
+    if clif.as_ref() == Ok(&llvm) {
+        Ok(None)
+    } else {
+        Ok(Some(Mismatch { llvm, clif }))
+    }
+}
+
+/// Returns the stdout of the program on success.
+fn compile_and_run(
+    source_file: &Path,
+    exe: &Path,
+    rustc: &str,
+    rustflags: &str,
+) -> Result<String, String> {
+    let output = Command::new(rustc)
+        .args(rustflags.split_whitespace())
+        .arg("--edition=2018")
+        .arg(source_file)
+        .arg("-o")
+        .arg(exe)
+        .output()
+        .map_err(|err| format!("failed to run `{}`: {}", rustc, err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "failed to compile the program:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let output = Command::new(exe)
+        .output()
+        .map_err(|err| format!("failed to run `{}`: {}", exe.display(), err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "failed running the program with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Write the tests of the program with `seed` which behave differently to `example/` and return the
+/// path of the reproducer.
+fn save_reproducer(work_dir: &Path, seed: u64, tests: &[usize], mismatch: Mismatch) -> PathBuf {
+    let mut failing = differing_tests(&mismatch);
+    if failing.is_empty() {
+        // cg_clif failed to compile or run the program, so try every test on its own.
+        failing = tests
+            .iter()
+            .copied()
+            .filter(|&test| matches!(check(work_dir, seed, &[test]), Ok(Some(_))))
+            .collect();
+    }
+
+    let reduced = if failing.is_empty() {
+        None
+    } else {
+        check(work_dir, seed, &failing).ok().flatten()
+    };
+    let (tests, expected) = match reduced {
+        Some(reduced) => (failing, reduced.llvm),
+        // The difference only shows up together with other tests.
+        None => (tests.to_vec(), mismatch.llvm),
+    };
+    let reproducer = PathBuf::from(format!("example/fuzz_{}.rs", seed));
+    std::fs::write(&reproducer, generate_program(seed, &tests, Some(&expected))).unwrap();
+    reproducer
+}
+
+/// The tests which printed different lines. Every line starts with the name of the test printing
+/// it.
+fn differing_tests(mismatch: &Mismatch) -> Vec<usize> {
+    let clif = match &mismatch.clif {
+        Ok(clif) => lines_by_test(clif),
+        Err(_) => return Vec::new(),
+    };
+    let llvm = lines_by_test(&mismatch.llvm);
+    llvm.keys()
+        .copied()
+        .filter(|test| llvm.get(test) != clif.get(test))
+        .collect()
+}
+
+fn lines_by_test(output: &str) -> BTreeMap<usize, Vec<&str>> {
+    let mut lines = BTreeMap::<usize, Vec<&str>>::new();
+    for line in output.lines() {
+        let test = line
+            .strip_prefix('t')
+            .and_then(|line| line.split(':').next())
+            .and_then(|test| test.parse().ok());
+        if let Some(test) = test {
+            lines.entry(test).or_default().push(line);
+        }
+    }
+    lines
+}
+
+/// Generate a program consisting of `tests`. When `expected` is given, the program panics if its
+/// output differs.
+fn generate_program(seed: u64, tests: &[usize], expected: Option<&str>) -> String {
+    let mut program = String::new();
+    if expected.is_some() {
+        writeln!(
+            program,
+            "// Reproducer found by scripts/fuzz.rs using seed {}.",
+            seed
+        )
+        .unwrap();
+        program.push_str("// The expected output is the output when compiled using LLVM.\n");
+    } else {
+        writeln!(
+            program,
+            "// Generated by scripts/fuzz.rs using seed {}.",
+            seed
+        )
+        .unwrap();
+    }
+    program.push_str(PRELUDE);
+
+    for &test in tests {
+        // Every test has its own rng, so it doesn't change when other tests are left out.
+        let mut rng = Rng(seed ^ (test as u64 + 1).wrapping_mul(0x2545_f491_4f6c_dd1d));
+        program.push('\n');
+        program.push_str(&match rng.below(10) {
+            0..=3 => arithmetic_test(&mut rng, test),
+            4 | 5 => enum_test(&mut rng, test),
+            6 | 7 => slice_test(&mut rng, test),
+            _ => trait_object_test(&mut rng, test),
+        });
+    }
+
+    program.push_str("\nfn main() {\n    let mut out = String::new();\n");
+    for &test in tests {
+        writeln!(program, "    t{}(&mut out);", test).unwrap();
+    }
+    program.push_str("    print!(\"{}\", out);\n");
+    if let Some(expected) = expected {
+        writeln!(program, "    assert_eq!(out, {:?});", expected).unwrap();
+    }
+    program.push_str("}\n");
+    program
+}
+
+/// A function computing a random expression from its arguments, which is called with a few random
+/// arguments.
+fn arithmetic_test(rng: &mut Rng, test: usize) -> String {
+    let param_tys = (0..1 + rng.below(4))
+        .map(|_| rng.choose(&ALL_TYS))
+        .collect::<Vec<_>>();
+    let param_names = (0..param_tys.len())
+        .map(|i| format!("p{}", i))
+        .collect::<Vec<_>>();
+    let vars = param_names
+        .iter()
+        .map(String::as_str)
+        .zip(param_tys.iter().copied())
+        .collect::<Vec<_>>();
+    let ret_ty = rng.choose(&ALL_TYS);
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "#[inline(never)]\nfn f{}({}) -> {} {{\n    {}\n}}\n",
+        test,
+        vars.iter()
+            .map(|(name, ty)| format!("{}: {}", name, ty.name()))
+            .collect::<Vec<_>>()
+            .join(", "),
+        ret_ty.name(),
+        expr(rng, &vars, ret_ty, MAX_DEPTH),
+    )
+    .unwrap();
+
+    writeln!(code, "fn t{}(out: &mut String) {{", test).unwrap();
+    for _ in 0..4 {
+        let args = param_tys
+            .iter()
+            .map(|&ty| format!("opaque({})", literal(rng, ty)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            code,
+            "    writeln!(out, \"t{0}: {{:?}}\", f{0}({1})).unwrap();",
+            test, args
+        )
+        .unwrap();
+    }
+    code.push_str("}\n");
+    code
+}
+
+/// An enum whose variants have payloads with niches, which is constructed from and matched on
+/// integers.
+fn enum_test(rng: &mut Rng, test: usize) -> String {
+    let payloads = (0..1 + rng.below(5))
+        .map(|_| {
+            if rng.below(5) < 2 {
+                None
+            } else {
+                Some(rng.choose(&PAYLOADS))
+            }
+        })
+        .collect::<Vec<_>>();
+    let fieldless = payloads.iter().all(Option::is_none);
+
+    let mut code = String::from("#[derive(Debug, Clone, Copy, PartialEq)]\n");
+    // Explicit discriminants are only allowed for fieldless enums.
+    let discriminants = if fieldless && rng.below(2) == 0 {
+        let repr = rng.choose(&["u8", "i8", "u16", "i32", "i64", "isize"]);
+        writeln!(code, "#[repr({})]", repr).unwrap();
+        let start = rng.below(4) as i64 - if repr.starts_with('i') { 3 } else { 0 };
+        Some((start, 1 + rng.below(4) as i64))
+    } else {
+        None
+    };
+    writeln!(code, "enum E{} {{", test).unwrap();
+    // The constructor, pattern and value of every variant.
+    let mut variants = Vec::new();
+    for (i, payload) in payloads.iter().enumerate() {
+        let variant = format!("E{}::V{}", test, i);
+        match (payload, discriminants) {
+            (None, Some((start, step))) => {
+                writeln!(code, "    V{} = {},", i, start + step * i as i64).unwrap();
+                variants.push((variant.clone(), variant, format!("{}", 100 + i)));
+            }
+            (None, None) => {
+                writeln!(code, "    V{},", i).unwrap();
+                variants.push((variant.clone(), variant, format!("{}", 100 + i)));
+            }
+            (Some(payload), _) if rng.below(2) == 0 => {
+                writeln!(code, "    V{}({}),", i, payload.ty()).unwrap();
+                variants.push((
+                    format!("{}({})", variant, payload.from_x()),
+                    format!("{}(p)", variant),
+                    format!("{} + {}", payload.to_i64(), 100 + i),
+                ));
+            }
+            (Some(payload), _) => {
+                writeln!(code, "    V{} {{ f: {} }},", i, payload.ty()).unwrap();
+                variants.push((
+                    format!("{} {{ f: {} }}", variant, payload.from_x()),
+                    format!("{} {{ f: p }}", variant),
+                    format!("{} + {}", payload.to_i64(), 100 + i),
+                ));
+            }
+        }
+    }
+    code.push_str("}\n\n");
+
+    writeln!(
+        code,
+        "#[inline(never)]\nfn m{}(x: u32) -> Option<E{0}> {{",
+        test
+    )
+    .unwrap();
+    writeln!(code, "    match x % {} {{", variants.len() + 1).unwrap();
+    for (i, (constructor, _, _)) in variants.iter().enumerate() {
+        writeln!(code, "        {} => Some({}),", i, constructor).unwrap();
+    }
+    code.push_str("        _ => None,\n    }\n}\n\n");
+
+    writeln!(
+        code,
+        "#[inline(never)]\nfn v{}(e: Option<E{0}>) -> i64 {{",
+        test
+    )
+    .unwrap();
+    code.push_str("    match e {\n");
+    for (_, pattern, value) in &variants {
+        writeln!(code, "        Some({}) => {},", pattern, value).unwrap();
+    }
+    code.push_str("        None => -1,\n    }\n}\n\n");
+
+    writeln!(code, "fn t{}(out: &mut String) {{", test).unwrap();
+    code.push_str("    for &x in &[0u32, 1, 2, 3, 4, 5, 6, 7, 255, 256, 65537, 4294967295] {\n");
+    writeln!(code, "        let e = m{}(opaque(x));", test).unwrap();
+    writeln!(
+        code,
+        "        writeln!(out, \"t{0}: {{:?}} {{}} {{}}\", e, v{0}(e), e == m{0}(opaque(x / 2)))\
+         .unwrap();",
+        test
+    )
+    .unwrap();
+    if fieldless {
+        code.push_str("        if let Some(e) = e {\n");
+        writeln!(
+            code,
+            "            writeln!(out, \"t{}: {{}}\", e as i64).unwrap();",
+            test
+        )
+        .unwrap();
+        code.push_str("        }\n");
+    }
+    code.push_str("    }\n}\n");
+    code
+}
+
+/// A function using a slice of random numbers in various ways.
+fn slice_test(rng: &mut Rng, test: usize) -> String {
+    let elem_ty = rng.choose(&NUM_TYS);
+    let len = rng.below(9);
+    let elems = (0..len)
+        .map(|_| literal(rng, elem_ty))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let lit = literal(rng, elem_ty);
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "#[inline(never)]\nfn s{}(s: &[{}], i: usize, out: &mut String) {{",
+        test,
+        elem_ty.name()
+    )
+    .unwrap();
+    let mut print = |args: &str| {
+        writeln!(
+            code,
+            "    writeln!(out, \"t{}: {{:?}}\", {}).unwrap();",
+            test, args
+        )
+        .unwrap();
+    };
+    print("(s.len(), s.get(i), s.first(), s.last())");
+    print(&format!("s.iter().rev().position(|&x| x > {})", lit));
+    print("s.windows(2).filter(|w| w[0] < w[1]).count()");
+    print(&format!(
+        "s.chunks({}).map(|c| c.len()).collect::<Vec<_>>()",
+        1 + rng.below(3)
+    ));
+    print("s.split_at(i.min(s.len()))");
+    print("match s { [] => None, [x] => Some((*x, *x)), [x, .., y] => Some((*x, *y)) }");
+    if elem_ty.is_float() {
+        print(&format!("s.iter().fold({}, |acc, &x| acc * 0.5 + x)", lit));
+        print(&format!(
+            "s.iter().map(|x| x.abs()).sum::<{}>()",
+            elem_ty.name()
+        ));
+    } else {
+        print(&format!(
+            "s.iter().fold({}, |acc, &x| acc.wrapping_mul(31).wrapping_add(x))",
+            lit
+        ));
+        print("s.iter().max()");
+        code.push_str("    let mut v = s.to_vec();\n    v.sort_unstable();\n");
+        writeln!(
+            code,
+            "    writeln!(out, \"t{}: {{:?}} {{:?}}\", v, v.binary_search(&{}).is_ok()).unwrap();",
+            test, lit
+        )
+        .unwrap();
+    }
+    code.push_str("}\n\n");
+
+    writeln!(code, "fn t{}(out: &mut String) {{", test).unwrap();
+    writeln!(
+        code,
+        "    let a: [{}; {}] = [{}];",
+        elem_ty.name(),
+        len,
+        elems
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "    s{}(opaque(&a[..]), opaque({}), out);",
+        test,
+        rng.below(len + 2)
+    )
+    .unwrap();
+    code.push_str("}\n");
+    code
+}
+
+/// A trait with a default method and several implementations, which are called through trait
+/// objects.
+fn trait_object_test(rng: &mut Rng, test: usize) -> String {
+    let ty = rng.choose(&ALL_TYS);
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "trait Tr{0}: std::fmt::Debug {{\n    fn apply(&self, x: {1}) -> {1};\n\n    \
+         fn twice(&self, x: {1}) -> {1} {{\n        self.apply(self.apply(x))\n    }}\n}}\n",
+        test,
+        ty.name()
+    )
+    .unwrap();
+
+    let mut objects = Vec::new();
+    for i in 0..1 + rng.below(4) {
+        let field_ty = rng.choose(&ALL_TYS);
+        let body = expr(rng, &[("self.0", field_ty), ("x", ty)], ty, MAX_DEPTH - 1);
+        writeln!(
+            code,
+            "#[derive(Debug)]\nstruct S{0}x{1}({2});\n\nimpl Tr{0} for S{0}x{1} {{\n    \
+             fn apply(&self, x: {3}) -> {3} {{\n        {4}\n    }}\n}}\n",
+            test,
+            i,
+            field_ty.name(),
+            ty.name(),
+            body
+        )
+        .unwrap();
+        objects.push(format!(
+            "Box::new(S{}x{}(opaque({}))) as Box<dyn Tr{0}>",
+            test,
+            i,
+            literal(rng, field_ty)
+        ));
+    }
+
+    writeln!(code, "fn t{}(out: &mut String) {{", test).unwrap();
+    writeln!(code, "    let objects = vec![{}];", objects.join(", ")).unwrap();
+    writeln!(code, "    let mut acc = opaque({});", literal(rng, ty)).unwrap();
+    writeln!(
+        code,
+        "    for o in &objects {{\n        acc = o.apply(acc);\n        \
+         writeln!(out, \"t{}: {{:?}} {{:?}}\", o, acc).unwrap();\n    }}",
+        test
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "    let o: &dyn Tr{} = &*objects[opaque({}) % objects.len()];",
+        test,
+        rng.below(8)
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "    writeln!(out, \"t{}: {{:?}}\", o.twice(acc)).unwrap();",
+        test
+    )
+    .unwrap();
+    code.push_str("}\n");
+    code
+}
+
+/// A random expression of type `ty` using the variables `vars`. Every expression other than a
+/// variable or literal is parenthesized or a method call, so it can be used as operand of any
+/// other expression.
+fn expr(rng: &mut Rng, vars: &[(&str, Ty)], ty: Ty, depth: u32) -> String {
+    if depth == 0 || rng.below(4) == 0 {
+        let same_ty = vars
+            .iter()
+            .filter(|&&(_, var_ty)| var_ty == ty)
+            .collect::<Vec<_>>();
+        // Variables of other types are cast if possible.
+        let castable = vars
+            .iter()
+            .filter(|&&(_, var_ty)| var_ty != ty && ty != Ty::Bool)
+            .filter(|&&(_, var_ty)| !(var_ty == Ty::Bool && ty.is_float()))
+            .collect::<Vec<_>>();
+        return match rng.below(3) {
+            0 if !same_ty.is_empty() => rng.choose(&same_ty).0.to_string(),
+            1 if !castable.is_empty() => format!("({} as {})", rng.choose(&castable).0, ty.name()),
+            _ => literal(rng, ty),
+        };
+    }
+
+    let operand = |rng: &mut Rng, ty| expr(rng, vars, ty, depth - 1);
+    let name = ty.name();
+    match ty {
+        Ty::Bool => match rng.below(6) {
+            0 | 1 => {
+                let operand_ty = rng.choose(&NUM_TYS);
+                let lhs = operand(rng, operand_ty);
+                let op = rng.choose(&["==", "!=", "<", "<=", ">", ">="]);
+                format!("({} {} {})", lhs, op, operand(rng, operand_ty))
+            }
+            2 => format!("(!{})", operand(rng, ty)),
+            3 => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["&&", "||", "^"]);
+                format!("({} {} {})", lhs, op, operand(rng, ty))
+            }
+            4 => {
+                let float_ty = rng.choose(&[Ty::F32, Ty::F64]);
+                let value = operand(rng, float_ty);
+                let method = rng.choose(&["is_nan", "is_infinite", "is_finite", "is_normal"]);
+                format!("{}.{}()", value, method)
+            }
+            _ => {
+                let int_ty = rng.choose(&INT_TYS);
+                format!("({}.count_ones() % 2 == 0)", operand(rng, int_ty))
+            }
+        },
+        // The sign and payload of NaNs are not guaranteed and `min`, `max` and `powi` are not
+        // deterministic, so they are not used.
+        Ty::F32 | Ty::F64 => match rng.below(9) {
+            0..=3 => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["+", "-", "*", "/", "%"]);
+                format!("({} {} {})", lhs, op, operand(rng, ty))
+            }
+            4 => format!("(-{})", operand(rng, ty)),
+            5 => {
+                let from_ty = rng.choose(&NUM_TYS);
+                format!("({} as {})", operand(rng, from_ty), name)
+            }
+            6 => {
+                let value = operand(rng, ty);
+                let method =
+                    rng.choose(&["abs", "signum", "floor", "ceil", "trunc", "round", "sqrt"]);
+                format!("{}.{}()", value, method)
+            }
+            7 => {
+                let value = operand(rng, ty);
+                let a = operand(rng, ty);
+                format!("{}.mul_add({}, {})", value, a, operand(rng, ty))
+            }
+            _ => {
+                let cond = operand(rng, Ty::Bool);
+                let then = operand(rng, ty);
+                format!(
+                    "(if {} {{ {} }} else {{ {} }})",
+                    cond,
+                    then,
+                    operand(rng, ty)
+                )
+            }
+        },
+        _ => match rng.below(14) {
+            0 => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["add", "sub", "mul"]);
+                format!("{}.wrapping_{}({})", lhs, op, operand(rng, ty))
+            }
+            1 => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["div", "rem"]);
+                format!("{}.wrapping_{}({} | 1)", lhs, op, operand(rng, ty))
+            }
+            2 => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["add", "sub", "mul", "div", "rem"]);
+                let rhs = operand(rng, ty);
+                format!(
+                    "{}.checked_{}({}).unwrap_or({})",
+                    lhs,
+                    op,
+                    rhs,
+                    literal(rng, ty)
+                )
+            }
+            3 => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["add", "sub", "mul"]);
+                format!("{}.saturating_{}({})", lhs, op, operand(rng, ty))
+            }
+            4 => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["&", "|", "^"]);
+                format!("({} {} {})", lhs, op, operand(rng, ty))
+            }
+            5 => {
+                let value = operand(rng, ty);
+                let method = rng.choose(&[
+                    "wrapping_shl",
+                    "wrapping_shr",
+                    "rotate_left",
+                    "rotate_right",
+                ]);
+                format!("{}.{}({})", value, method, operand(rng, Ty::U32))
+            }
+            6 => {
+                let value = operand(rng, ty);
+                match rng.below(4) {
+                    0 => format!("(!{})", value),
+                    1 => format!("{}.wrapping_neg()", value),
+                    2 => format!("{}.swap_bytes()", value),
+                    _ => format!("{}.reverse_bits()", value),
+                }
+            }
+            7 => {
+                let value = operand(rng, ty);
+                let method = rng.choose(&["count_ones", "leading_zeros", "trailing_zeros"]);
+                format!("({}.{}() as {})", value, method, name)
+            }
+            8 | 9 => {
+                let from_ty = rng.choose(&ALL_TYS);
+                format!("({} as {})", operand(rng, from_ty), name)
+            }
+            10 => {
+                let lhs = operand(rng, ty);
+                let method = rng.choose(&["min", "max"]);
+                format!("{}.{}({})", lhs, method, operand(rng, ty))
+            }
+            11 => {
+                let value = operand(rng, ty);
+                format!("{}.wrapping_pow({})", value, operand(rng, Ty::U32))
+            }
+            12 => {
+                let cond = operand(rng, Ty::Bool);
+                let then = operand(rng, ty);
+                format!(
+                    "(if {} {{ {} }} else {{ {} }})",
+                    cond,
+                    then,
+                    operand(rng, ty)
+                )
+            }
+            _ => {
+                let lhs = operand(rng, ty);
+                let op = rng.choose(&["add", "sub", "mul"]);
+                format!(
+                    "({{ let (v, o) = {}.overflowing_{}({}); if o {{ !v }} else {{ v }} }})",
+                    lhs,
+                    op,
+                    operand(rng, ty)
+                )
+            }
+        },
+    }
+}
+
+/// A random literal of type `ty`, preferring edge cases.
+fn literal(rng: &mut Rng, ty: Ty) -> String {
+    let name = ty.name();
+    match ty {
+        Ty::Bool => rng.choose(&["true", "false"]).to_string(),
+        Ty::F32 | Ty::F64 => match rng.below(8) {
+            0 => format!("{}{}", rng.choose(&["0.0", "1.0", "0.5", "3.0"]), name),
+            1 => format!("(-{}{})", rng.choose(&["0.0", "1.0", "2.5", "1e10"]), name),
+            2 => {
+                let constant = rng.choose(&[
+                    "INFINITY",
+                    "NEG_INFINITY",
+                    "NAN",
+                    "MIN",
+                    "MAX",
+                    "MIN_POSITIVE",
+                    "EPSILON",
+                ]);
+                format!("{}::{}", name, constant)
+            }
+            3 | 4 => format!("{}.{}{}", rng.below(100_000), rng.below(1000), name),
+            _ if ty == Ty::F32 => format!("f32::from_bits({:#x})", rng.next() as u32),
+            _ => format!("f64::from_bits({:#x})", rng.next()),
+        },
+        _ => {
+            match rng.below(8) {
+                0 => return format!("{}::MIN", name),
+                1 => return format!("{}::MAX", name),
+                _ => {}
+            }
+            let bits = ty.literal_bits();
+            let value = match rng.below(4) {
+                0 => rng.below(4) as u128,
+                1 => rng.below(256) as u128,
+                2 => 1 << rng.below(bits.into()),
+                _ => (rng.next() as u128) << 64 | rng.next() as u128,
+            };
+            // Truncate to `bits` and sign extend for signed types.
+            let shift = 128 - bits;
+            if ty.is_signed() && ((value << shift) as i128) < 0 {
+                let value = ((value << shift) as i128) >> shift;
+                format!("(-{}{})", (value as u128).wrapping_neg(), name)
+            } else {
+                format!("{}{}", value << shift >> shift, name)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Ty {
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    Isize,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    Usize,
+    F32,
+    F64,
+}
+
+const INT_TYS: [Ty; 12] = [
+    Ty::I8,
+    Ty::I16,
+    Ty::I32,
+    Ty::I64,
+    Ty::I128,
+    Ty::Isize,
+    Ty::U8,
+    Ty::U16,
+    Ty::U32,
+    Ty::U64,
+    Ty::U128,
+    Ty::Usize,
+];
+
+const NUM_TYS: [Ty; 14] = [
+    Ty::I8,
+    Ty::I16,
+    Ty::I32,
+    Ty::I64,
+    Ty::I128,
+    Ty::Isize,
+    Ty::U8,
+    Ty::U16,
+    Ty::U32,
+    Ty::U64,
+    Ty::U128,
+    Ty::Usize,
+    Ty::F32,
+    Ty::F64,
+];
+
+const ALL_TYS: [Ty; 15] = [
+    Ty::Bool,
+    Ty::I8,
+    Ty::I16,
+    Ty::I32,
+    Ty::I64,
+    Ty::I128,
+    Ty::Isize,
+    Ty::U8,
+    Ty::U16,
+    Ty::U32,
+    Ty::U64,
+    Ty::U128,
+    Ty::Usize,
+    Ty::F32,
+    Ty::F64,
+];
+
+impl Ty {
+    fn name(self) -> &'static str {
+        match self {
+            Ty::Bool => "bool",
+            Ty::I8 => "i8",
+            Ty::I16 => "i16",
+            Ty::I32 => "i32",
+            Ty::I64 => "i64",
+            Ty::I128 => "i128",
+            Ty::Isize => "isize",
+            Ty::U8 => "u8",
+            Ty::U16 => "u16",
+            Ty::U32 => "u32",
+            Ty::U64 => "u64",
+            Ty::U128 => "u128",
+            Ty::Usize => "usize",
+            Ty::F32 => "f32",
+            Ty::F64 => "f64",
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, Ty::F32 | Ty::F64)
+    }
+
+    fn is_signed(self) -> bool {
+        matches!(
+            self,
+            Ty::I8 | Ty::I16 | Ty::I32 | Ty::I64 | Ty::I128 | Ty::Isize
+        )
+    }
+
+    /// The number of bits of integer literals of this type. Literals of `isize` and `usize` have
+    /// to fit on 32bit targets too.
+    fn literal_bits(self) -> u32 {
+        match self {
+            Ty::I8 | Ty::U8 => 8,
+            Ty::I16 | Ty::U16 => 16,
+            Ty::I32 | Ty::U32 | Ty::Isize | Ty::Usize => 32,
+            Ty::I64 | Ty::U64 => 64,
+            Ty::I128 | Ty::U128 => 128,
+            Ty::Bool | Ty::F32 | Ty::F64 => unreachable!(),
+        }
+    }
+}
+
+/// The payload of an enum variant. All of them except for integers have a niche.
+#[derive(Copy, Clone)]
+enum Payload {
+    Bool,
+    U8,
+    I16,
+    Char,
+    NonZero,
+    Pair,
+    Str,
+    OptionRef,
+}
+
+const PAYLOADS: [Payload; 8] = [
+    Payload::Bool,
+    Payload::U8,
+    Payload::I16,
+    Payload::Char,
+    Payload::NonZero,
+    Payload::Pair,
+    Payload::Str,
+    Payload::OptionRef,
+];
+
+impl Payload {
+    fn ty(self) -> &'static str {
+        match self {
+            Payload::Bool => "bool",
+            Payload::U8 => "u8",
+            Payload::I16 => "i16",
+            Payload::Char => "char",
+            Payload::NonZero => "std::num::NonZeroU8",
+            Payload::Pair => "(bool, u8)",
+            Payload::Str => "&'static str",
+            Payload::OptionRef => "Option<&'static u16>",
+        }
+    }
+
+    /// An expression computing a payload from `x: u32`.
+    fn from_x(self) -> &'static str {
+        match self {
+            Payload::Bool => "x % 2 == 0",
+            Payload::U8 => "x as u8",
+            Payload::I16 => "x as i16",
+            Payload::Char => "std::char::from_u32(x % 0x11_0000).unwrap_or('?')",
+            Payload::NonZero => "std::num::NonZeroU8::new(x as u8 | 1).unwrap()",
+            Payload::Pair => "(x % 3 == 0, x as u8)",
+            Payload::Str => "[\"\", \"a\", \"bc\"][x as usize % 3]",
+            Payload::OptionRef => "if x % 2 == 0 { None } else { Some(&7) }",
+        }
+    }
+
+    /// An expression computing an `i64` from the payload `p`.
+    fn to_i64(self) -> &'static str {
+        match self {
+            Payload::Bool | Payload::U8 | Payload::I16 | Payload::Char => "(p as i64)",
+            Payload::NonZero => "(p.get() as i64)",
+            Payload::Pair => "(p.0 as i64 + p.1 as i64)",
+            Payload::Str => "(p.len() as i64)",
+            Payload::OptionRef => "p.map_or(-1, |r| *r as i64)",
+        }
+    }
+}
+
+/// SplitMix64
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn choose<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u64) as usize]
+    }
+}
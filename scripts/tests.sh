@@ -43,7 +43,81 @@ function base_sysroot_tests() {
         $MY_RUSTC -Cllvm-args=mode=jit -Cprefer-dynamic example/std_example.rs --target "$HOST_TRIPLE"
 
         echo "[JIT-lazy] std_example"
-        $MY_RUSTC -Cllvm-args=mode=jit-lazy -Cprefer-dynamic example/std_example.rs --cfg lazy_jit --target "$HOST_TRIPLE"
+        $MY_RUSTC -Cllvm-args=mode=jit-lazy -Cprefer-dynamic example/std_example.rs --target "$HOST_TRIPLE"
+
+        echo "[JIT-lazy] std_example (tiered)"
+        $MY_RUSTC -Cllvm-args=mode=jit-lazy -Cllvm-args=jit-tier-up-threshold=2 -Cprefer-dynamic example/std_example.rs --target "$HOST_TRIPLE"
+
+        echo "[JIT] jit_add (embedded jit)"
+        target/"${CHANNEL:-release}"/examples/jit_driver $RUSTFLAGS --sysroot "$(dirname "$RUSTC")/.." -Cpanic=abort -Cllvm-args=mode=jit -Cprefer-dynamic \
+            example/jit_add.rs --crate-type dylib --out-dir target/out --target "$HOST_TRIPLE" > target/out/jit_add.stdout
+        grep -q "add(1, 2) = 3" target/out/jit_add.stdout
+
+        if [[ "$HOST_TRIPLE" = "x86_64-unknown-linux-gnu" ]]; then
+            echo "[JIT] std_example (static libstd)"
+            $MY_RUSTC -Cllvm-args=mode=jit example/std_example.rs --target "$HOST_TRIPLE"
+
+            echo "[JIT] test_harness"
+            CG_CLIF_JIT_ENCODED_ARGS=$'it_\x1f--test-threads\x1f1' $MY_RUSTC -Cllvm-args=mode=jit --test example/test_harness.rs --cfg $'jit_test_arg="a\x1fb"' --target "$HOST_TRIPLE" > target/out/test_harness.stdout \
+                || (cat target/out/test_harness.stdout; false)
+            grep -q "test result: ok. 3 passed; 0 failed; 0 ignored; 0 measured; 1 filtered out" target/out/test_harness.stdout
+
+            echo "[JIT] jit_static_lib"
+            printf 'int used_fn(void) { return 42; }\n' > target/out/jit_static_lib_used.c
+            printf '#include <stdlib.h>\nint missing_fn(void);\n__attribute__((constructor)) static void ctor(void) { abort(); }\nint unused_fn(void) { return missing_fn(); }\n' > target/out/jit_static_lib_unused.c
+            for member in used unused; do
+                cc -fPIC -c target/out/jit_static_lib_$member.c -o target/out/jit_static_lib_$member.o
+            done
+            rm -f target/out/libjit_static_lib.a
+            ar rcs target/out/libjit_static_lib.a target/out/jit_static_lib_used.o target/out/jit_static_lib_unused.o
+            $MY_RUSTC -Cllvm-args=mode=jit example/jit_static_lib.rs -L native=target/out --target "$HOST_TRIPLE"
+            $MY_RUSTC -Cllvm-args=mode=jit-lazy example/jit_static_lib.rs -L native=target/out --target "$HOST_TRIPLE"
+
+            if command -v gdb > /dev/null; then
+                echo "[JIT] jit_debug (gdb)"
+                gdb -q -batch -ex run -ex bt --args $MY_RUSTC -Cllvm-args=mode=jit -Cprefer-dynamic example/jit_debug.rs --target "$HOST_TRIPLE" > target/out/jit_debug.gdb 2>&1 \
+                    || (cat target/out/jit_debug.gdb; false)
+                grep -q "jit_debug.rs:6" target/out/jit_debug.gdb || (cat target/out/jit_debug.gdb; false)
+            else
+                echo "[JIT] jit_debug (gdb) (skipped)"
+            fi
+
+            echo "[JIT-lazy] std_example (perf map and jitdump)"
+            CG_CLIF_JIT_PERF=map,jitdump $MY_RUSTC -Cllvm-args=mode=jit-lazy -Cprefer-dynamic example/std_example.rs --target "$HOST_TRIPLE" &
+            perf_pid=$!
+            wait $perf_pid
+            grep -q " std_example::main$" /tmp/perf-$perf_pid.map
+            [[ -s /tmp/jit-$perf_pid.dump ]]
+            rm /tmp/perf-$perf_pid.map /tmp/jit-$perf_pid.dump
+
+            echo "[JIT-lazy] jit_watch"
+            cp example/jit_watch.rs target/out/jit_watch.rs
+            $MY_RUSTC -Cllvm-args=mode=jit-lazy -Cllvm-args=jit-watch -Cprefer-dynamic target/out/jit_watch.rs --target "$HOST_TRIPLE" > target/out/jit_watch.stdout &
+            watch_pid=$!
+            # Only change the source once the program runs, so the change has to be hot patched.
+            while ! grep -qs "waiting for reload" target/out/jit_watch.stdout; do
+                kill -0 $watch_pid
+                sleep 0.1
+            done
+            sed -i 's/^    1$/    2/' target/out/jit_watch.rs
+            wait $watch_pid
+            grep -q "reloaded" target/out/jit_watch.stdout
+
+            echo "[JIT] std_example (code cache)"
+            rm -rf target/out/jit_cache
+            for _ in 1 2; do
+                CG_CLIF_JIT_CACHE_DIR=target/out/jit_cache $MY_RUSTC -Cllvm-args=mode=jit -Cprefer-dynamic example/std_example.rs --target "$HOST_TRIPLE"
+                CG_CLIF_JIT_CACHE_DIR=target/out/jit_cache $MY_RUSTC -Cllvm-args=mode=jit-lazy -Cprefer-dynamic example/std_example.rs --target "$HOST_TRIPLE"
+            done
+
+            echo "[JIT] fn_cache_layout (code cache)"
+            rm -rf target/out/jit_cache_layout
+            cp example/fn_cache_layout.rs target/out/fn_cache_layout_jit.rs
+            for _ in 1 2; do
+                CG_CLIF_JIT_CACHE_DIR=target/out/jit_cache_layout $MY_RUSTC -Cllvm-args=mode=jit -Cprefer-dynamic target/out/fn_cache_layout_jit.rs --target "$HOST_TRIPLE"
+                sed -i 's/^type First = u8;$/type First = u64;/' target/out/fn_cache_layout_jit.rs
+            done
+        fi
     else
         echo "[JIT] std_example (skipped)"
     fi
@@ -57,6 +131,59 @@ function base_sysroot_tests() {
     $MY_RUSTC example/std_example.rs --crate-type bin --target "$TARGET_TRIPLE"
     $RUN_WRAPPER ./target/out/std_example arg
 
+    echo "[AOT] std_example (--emit=asm)"
+    $MY_RUSTC example/std_example.rs --crate-type bin --emit=asm --target "$TARGET_TRIPLE"
+    grep -q "^_ZN11std_example4main" target/out/std_example.s
+
+    echo "[AOT] std_example (--emit=llvm-ir)"
+    $MY_RUSTC example/std_example.rs --crate-type bin --emit=llvm-ir --target "$TARGET_TRIPLE"
+    grep -q "^test compile" target/out/std_example.clif
+
+    echo "[AOT] std_example (CG_CLIF_DUMP_FILTER)"
+    rm -rf target/out/dump_filter.clif
+    CG_CLIF_DUMP_FILTER='dump_filter::main' $MY_RUSTC example/std_example.rs --crate-name dump_filter --crate-type bin --target "$TARGET_TRIPLE"
+    ls target/out/dump_filter.clif/_ZN11dump_filter4main17h*.opt.clif > /dev/null
+    if ls target/out/dump_filter.clif | grep -v "^_ZN11dump_filter4main17h"; then
+        echo "CG_CLIF_DUMP_FILTER should only dump main"
+        false
+    fi
+
+    echo "[AOT] std_example (CG_CLIF_DUMP_DIFF)"
+    rm -rf target/out/dump_diff.clif
+    CG_CLIF_DUMP_FILTER='dump_diff::main' CG_CLIF_DUMP_DIFF=1 $MY_RUSTC example/std_example.rs --crate-name dump_diff --crate-type bin --target "$TARGET_TRIPLE"
+    grep -q "^@@ " target/out/dump_diff.clif/_ZN9dump_diff4main17h*.diff
+
+    echo "[AOT] std_example (CG_CLIF_REDUCE)"
+    rm -rf target/out/reduce.clif
+    if CG_CLIF_REDUCE=1 CG_CLIF_REDUCE_INJECT_ERROR='reduce::main' $MY_RUSTC example/std_example.rs --crate-name reduce --crate-type bin --target "$TARGET_TRIPLE" 2> target/out/reduce.stderr; then
+        echo "The injected verifier error should fail the compilation"
+        false
+    fi
+    grep -q "a reduced test case has been written to" target/out/reduce.stderr || (cat target/out/reduce.stderr; false)
+    grep -q "iadd" target/out/reduce.clif/_ZN6reduce4main17h*.reduced.clif
+
+    if [[ "$TARGET_TRIPLE" != *"apple"* && "$TARGET_TRIPLE" != *"windows"* ]]; then
+        echo "[AOT] std_example (function cache)"
+        rm -rf target/out/fn_cache_incr
+        cp example/std_example.rs target/out/fn_cache_example.rs
+        for _ in 1 2; do
+            CG_CLIF_FN_CACHE=1 $MY_RUSTC target/out/fn_cache_example.rs --crate-type bin -Cincremental=target/out/fn_cache_incr --target "$TARGET_TRIPLE"
+            $RUN_WRAPPER ./target/out/fn_cache_example arg
+            # Change the codegen unit without changing any existing function.
+            echo "fn _added_fn() -> u8 { 42 }" >> target/out/fn_cache_example.rs
+        done
+
+        echo "[AOT] fn_cache_layout (function cache)"
+        rm -rf target/out/fn_cache_layout_incr
+        cp example/fn_cache_layout.rs target/out/fn_cache_layout.rs
+        for _ in 1 2; do
+            CG_CLIF_FN_CACHE=1 $MY_RUSTC target/out/fn_cache_layout.rs --crate-type bin -Cincremental=target/out/fn_cache_layout_incr --target "$TARGET_TRIPLE"
+            $RUN_WRAPPER ./target/out/fn_cache_layout
+            # Change the offset of a field accessed through a reference.
+            sed -i 's/^type First = u8;$/type First = u64;/' target/out/fn_cache_layout.rs
+        done
+    fi
+
     echo "[AOT] subslice-patterns-const-eval"
     $MY_RUSTC example/subslice-patterns-const-eval.rs --crate-type bin -Cpanic=abort --target "$TARGET_TRIPLE"
     $RUN_WRAPPER ./target/out/subslice-patterns-const-eval
@@ -69,6 +196,56 @@ function base_sysroot_tests() {
     $MY_RUSTC example/mod_bench.rs --crate-type bin --target "$TARGET_TRIPLE"
     $RUN_WRAPPER ./target/out/mod_bench
 
+    # Reproducers saved by scripts/fuzz.rs
+    for reproducer in example/fuzz_*.rs; do
+        [[ -e "$reproducer" ]] || continue
+        name=$(basename "$reproducer" .rs)
+        echo "[AOT] $name"
+        $MY_RUSTC "$reproducer" --crate-type bin --target "$TARGET_TRIPLE"
+        $RUN_WRAPPER ./target/out/"$name"
+    done
+
+    if [[ "$TARGET_TRIPLE" = "x86_64"* ]]; then
+        if [[ "$CG_CLIF_TEST_LLVM_FALLBACK" = "1" ]]; then
+            echo "[AOT] llvm_fallback"
+            CG_CLIF_LLVM_FALLBACK=1 $MY_RUSTC example/llvm_fallback.rs --crate-type bin -Ccodegen-units=3 --target "$TARGET_TRIPLE"
+            $RUN_WRAPPER ./target/out/llvm_fallback
+            if [[ "$TARGET_TRIPLE" = "x86_64-unknown-linux-gnu" ]]; then
+                if $RUN_WRAPPER ./target/out/llvm_fallback trap 2> target/out/llvm_fallback.stderr; then
+                    echo "llvm_fallback should have trapped"
+                    false
+                fi
+                grep -q "trap: Called intrinsic::abort. (user1)" target/out/llvm_fallback.stderr
+
+                echo "[AOT] bisect"
+                CG_CLIF_INJECT_TRAP='bisect::broken' scripts/bisect.sh bisect \
+                    "$MY_RUSTC example/bisect.rs --crate-type bin -Clink-dead-code --target $TARGET_TRIPLE" \
+                    "./target/out/bisect" > target/out/bisect.stdout || (cat target/out/bisect.stdout; false)
+                grep -q "The test fails when compiling .*broken using cg_clif" target/out/bisect.stdout
+            fi
+        else
+            echo "[AOT] llvm_fallback (skipped)"
+        fi
+
+        echo "[AOT] llvm_fallback (unsupported report)"
+        if $MY_RUSTC example/llvm_fallback.rs --crate-type bin -Cllvm-args=unsupported-report --target "$TARGET_TRIPLE" 2>/dev/null; then
+            echo "llvm_asm! should prevent compilation"
+            false
+        fi
+        grep -q "Inline assembly is not supported" target/out/llvm_fallback.unsupported.json
+    fi
+
+    if [[ "$TARGET_TRIPLE" = "x86_64-unknown-linux-gnu" ]]; then
+        echo "[AOT] trap_handler"
+        $MY_RUSTC example/trap_handler.rs --crate-type bin --target "$TARGET_TRIPLE"
+        if $RUN_WRAPPER ./target/out/trap_handler 2> target/out/trap_handler.stderr; then
+            echo "trap_handler should have trapped"
+            false
+        fi
+        grep -q "trap: Called intrinsic::abort. (user1)" target/out/trap_handler.stderr
+        grep -q "at example/trap_handler.rs:6:9" target/out/trap_handler.stderr
+    fi
+
     pushd rand
     rm -r ./target || true
     ../build/cargo.sh test --workspace
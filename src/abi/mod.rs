@@ -1,6 +1,5 @@
 //! Handling of everything related to the calling convention. Also fills `fx.local_map`.
 
-#[cfg(debug_assertions)]
 mod comments;
 mod pass_mode;
 mod returning;
@@ -93,8 +92,9 @@ pub(crate) fn get_function_ref(&mut self, inst: Instance<'tcx>) -> FuncRef {
             .module
             .declare_func_in_func(func_id, &mut self.bcx.func);
 
-        #[cfg(debug_assertions)]
-        self.add_comment(func_ref, format!("{:?}", inst));
+        if self.clif_comments.enabled() {
+            self.add_comment(func_ref, format!("{:?}", inst));
+        }
 
         func_ref
     }
@@ -121,8 +121,7 @@ pub(crate) fn lib_call(
             .module
             .declare_func_in_func(func_id, &mut self.bcx.func);
         let call_inst = self.bcx.ins().call(func_ref, args);
-        #[cfg(debug_assertions)]
-        {
+        if self.clif_comments.enabled() {
             self.add_comment(call_inst, format!("easy_call {}", name));
         }
         let results = self.bcx.inst_results(call_inst);
@@ -183,8 +182,9 @@ fn make_local_place<'tcx>(
         CPlace::new_stack_slot(fx, layout)
     };
 
-    #[cfg(debug_assertions)]
-    self::comments::add_local_place_comments(fx, place, local);
+    if fx.clif_comments.enabled() {
+        self::comments::add_local_place_comments(fx, place, local);
+    }
 
     place
 }
@@ -200,8 +200,9 @@ pub(crate) fn codegen_fn_prelude<'tcx>(
 
     let ssa_analyzed = crate::analyze::analyze(fx);
 
-    #[cfg(debug_assertions)]
-    self::comments::add_args_header_comment(fx);
+    if fx.clif_comments.enabled() {
+        self::comments::add_args_header_comment(fx);
+    }
 
     let mut block_params_iter = fx
         .bcx
@@ -271,8 +272,9 @@ enum ArgKind<'tcx> {
     fx.fn_abi = Some(fn_abi);
     assert!(block_params_iter.next().is_none(), "arg_value left behind");
 
-    #[cfg(debug_assertions)]
-    self::comments::add_locals_header_comment(fx);
+    if fx.clif_comments.enabled() {
+        self::comments::add_locals_header_comment(fx);
+    }
 
     for (local, arg_kind, ty) in func_params {
         let layout = fx.layout_of(ty);
@@ -299,8 +301,9 @@ enum ArgKind<'tcx> {
                         CPlace::for_ptr(addr, val.layout())
                     };
 
-                    #[cfg(debug_assertions)]
-                    self::comments::add_local_place_comments(fx, place, local);
+                    if fx.clif_comments.enabled() {
+                        self::comments::add_local_place_comments(fx, place, local);
+                    }
 
                     assert_eq!(fx.local_map.push(place), local);
                     continue;
@@ -454,8 +457,7 @@ pub(crate) fn codegen_terminator_call<'tcx>(
             def: InstanceDef::Virtual(_, idx),
             ..
         }) => {
-            #[cfg(debug_assertions)]
-            {
+            if fx.clif_comments.enabled() {
                 let nop_inst = fx.bcx.ins().nop();
                 fx.add_comment(
                     nop_inst,
@@ -476,8 +478,7 @@ pub(crate) fn codegen_terminator_call<'tcx>(
 
         // Indirect call
         None => {
-            #[cfg(debug_assertions)]
-            {
+            if fx.clif_comments.enabled() {
                 let nop_inst = fx.bcx.ins().nop();
                 fx.add_comment(nop_inst, "indirect call");
             }
@@ -542,25 +543,25 @@ pub(crate) fn codegen_terminator_call<'tcx>(
     // FIXME find a cleaner way to support varargs
     if fn_sig.c_variadic {
         if fn_sig.abi != Abi::C {
-            fx.tcx.sess.span_fatal(
+            crate::trap::unsupported(
+                fx,
                 span,
-                &format!("Variadic call for non-C abi {:?}", fn_sig.abi),
+                format!("Variadic call for non-C abi {:?}", fn_sig.abi),
             );
         }
         let sig_ref = fx.bcx.func.dfg.call_signature(call_inst).unwrap();
         let abi_params = call_args
             .into_iter()
-            .map(|arg| {
-                let ty = fx.bcx.func.dfg.value_type(arg);
-                if !ty.is_int() {
-                    // FIXME set %al to upperbound on float args once floats are supported
-                    fx.tcx
-                        .sess
-                        .span_fatal(span, &format!("Non int ty {:?} for variadic call", ty));
-                }
-                AbiParam::new(ty)
-            })
+            .map(|arg| AbiParam::new(fx.bcx.func.dfg.value_type(arg)))
             .collect::<Vec<AbiParam>>();
+        if let Some(ty) = abi_params
+            .iter()
+            .map(|param| param.value_type)
+            .find(|ty| !ty.is_int())
+        {
+            // FIXME set %al to upperbound on float args once floats are supported
+            crate::trap::unsupported(fx, span, format!("Non int ty {:?} for variadic call", ty));
+        }
         fx.bcx.func.dfg.signatures[sig_ref].params = abi_params;
     }
 
@@ -302,16 +302,17 @@ pub(super) fn cvalue_for_param<'tcx>(
         })
         .collect::<SmallVec<[_; 2]>>();
 
-    #[cfg(debug_assertions)]
-    crate::abi::comments::add_arg_comment(
-        fx,
-        "arg",
-        local,
-        local_field,
-        &block_params,
-        arg_abi.mode,
-        arg_abi.layout,
-    );
+    if fx.clif_comments.enabled() {
+        crate::abi::comments::add_arg_comment(
+            fx,
+            "arg",
+            local,
+            local_field,
+            &block_params,
+            arg_abi.mode,
+            arg_abi.layout,
+        );
+    }
 
     match arg_abi.mode {
         PassMode::Ignore => None,
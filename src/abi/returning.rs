@@ -101,19 +101,17 @@ pub(super) fn codegen_return_param<'tcx>(
         } => unreachable!("unsized return value"),
     };
 
-    #[cfg(not(debug_assertions))]
-    let _ = ret_param;
-
-    #[cfg(debug_assertions)]
-    crate::abi::comments::add_arg_comment(
-        fx,
-        "ret",
-        Some(RETURN_PLACE),
-        None,
-        &ret_param,
-        fx.fn_abi.as_ref().unwrap().ret.mode,
-        fx.fn_abi.as_ref().unwrap().ret.layout,
-    );
+    if fx.clif_comments.enabled() {
+        crate::abi::comments::add_arg_comment(
+            fx,
+            "ret",
+            Some(RETURN_PLACE),
+            None,
+            &ret_param,
+            fx.fn_abi.as_ref().unwrap().ret.mode,
+            fx.fn_abi.as_ref().unwrap().ret.layout,
+        );
+    }
 
     ret_place
 }
@@ -3,15 +3,60 @@
 use rustc_index::vec::IndexVec;
 use rustc_middle::ty::adjustment::PointerCast;
 use rustc_middle::ty::layout::FnAbiExt;
+use rustc_session::config::OutputFilenames;
 use rustc_target::abi::call::FnAbi;
 
+use crate::debuginfo::FunctionDebugContext;
 use crate::prelude::*;
 
+/// A function that has been lowered to clif ir, but not yet compiled by Cranelift.
+///
+/// Unlike the [`FunctionCx`] it was created from, this doesn't reference the `TyCtxt`, so it can
+/// be sent to a codegen worker thread.
+pub(crate) struct CodegenedFunction {
+    symbol_name: String,
+    func_id: FuncId,
+    func: Function,
+    clif_comments: crate::pretty_clif::CommentWriter,
+    func_debug_cx: Option<FunctionDebugContext>,
+    /// Indexed by the `SourceLoc`s in `func`.
+    source_info_set: indexmap::IndexSet<SourceInfo>,
+    trap_messages: Option<crate::trap_metadata::TrapMessages>,
+    /// See [`crate::disasm::source_lines`].
+    asm_source_lines: Option<Vec<String>>,
+}
+
+impl CodegenedFunction {
+    /// The clif ir of the function. Drivers may modify it before it is compiled.
+    pub(crate) fn func_mut(&mut self) -> &mut Function {
+        &mut self.func
+    }
+}
+
+/// The text outputs of a codegen unit, which [`compile_fn`] appends every function to.
+#[derive(Default)]
+pub(crate) struct CguTextOutputs {
+    /// The clif ir for `--emit=llvm-ir`, see [`crate::pretty_clif::should_emit_clif`].
+    pub(crate) clif: Option<String>,
+    /// The assembly for `--emit=asm`, see [`crate::disasm`].
+    pub(crate) asm: Option<String>,
+}
+
+/// A function compiled by [`codegen_and_compile_fn`].
+pub(crate) struct CompiledFunction {
+    pub(crate) func_id: FuncId,
+    /// The size of the machine code in bytes.
+    pub(crate) size: u32,
+    /// The start offset of every range of machine code together with the span it was generated
+    /// for.
+    pub(crate) source_spans: Vec<(u32, Span)>,
+}
+
 pub(crate) fn codegen_fn<'tcx>(
     cx: &mut crate::CodegenCx<'tcx, impl Module>,
     instance: Instance<'tcx>,
     linkage: Linkage,
-) {
+) -> CodegenedFunction {
     let tcx = cx.tcx;
 
     let _inst_guard =
@@ -63,6 +108,11 @@ pub(crate) fn codegen_fn<'tcx>(
 
         clif_comments,
         source_info_set: indexmap::IndexSet::new(),
+        trap_messages: if cx.trap_metadata {
+            Some(FxHashMap::default())
+        } else {
+            None
+        },
         next_ssa_var: 0,
 
         inline_asm_index: 0,
@@ -79,6 +129,11 @@ pub(crate) fn codegen_fn<'tcx>(
             .append_block_params_for_function_params(fx.block_map[START_BLOCK]);
         fx.bcx.switch_to_block(fx.block_map[START_BLOCK]);
         crate::trap::trap_unreachable(&mut fx, "function has uninhabited argument");
+    } else if crate::driver::should_inject_trap(tcx, instance) {
+        fx.bcx
+            .append_block_params_for_function_params(fx.block_map[START_BLOCK]);
+        fx.bcx.switch_to_block(fx.block_map[START_BLOCK]);
+        crate::trap::trap_abort(&mut fx, "trap injected using `CG_CLIF_INJECT_TRAP`");
     } else {
         tcx.sess.time("codegen clif ir", || {
             tcx.sess.time("codegen prelude", || {
@@ -92,22 +147,34 @@ pub(crate) fn codegen_fn<'tcx>(
     let instance = fx.instance;
     let mut clif_comments = fx.clif_comments;
     let source_info_set = fx.source_info_set;
-    let local_map = fx.local_map;
+    let trap_messages = fx.trap_messages;
     let cold_blocks = fx.cold_blocks;
+    let local_map = fx.local_map;
+
+    crate::reduce::maybe_inject_verifier_error(tcx, instance, &mut func);
 
     // Store function in context
     let context = &mut cx.cached_context;
     context.func = func;
 
-    crate::pretty_clif::write_clif_file(tcx, "unopt", None, instance, &context, &clif_comments);
+    crate::pretty_clif::write_clif_file(
+        tcx.output_filenames(LOCAL_CRATE),
+        &name,
+        "unopt",
+        cx.module.isa(),
+        &context,
+        &mut clif_comments,
+    );
 
     // Verify function
-    verify_func(tcx, &clif_comments, &context.func);
+    verify_func(tcx, cx.module.isa(), &clif_comments, &context.func);
 
     // Perform rust specific optimizations
     tcx.sess.time("optimize clif ir", || {
         crate::optimize::optimize_function(
             tcx,
+            cx.opt_level,
+            cx.module.isa(),
             instance,
             context,
             &cold_blocks,
@@ -115,75 +182,235 @@ pub(crate) fn codegen_fn<'tcx>(
         );
     });
 
+    let func = std::mem::replace(&mut context.func, Function::new());
+    context.clear();
+
+    let func_debug_cx = if let Some(debug_context) = &mut cx.debug_context {
+        Some(debug_context.define_function(
+            tcx,
+            &mut cx.type_debug_context,
+            instance,
+            &name,
+            &source_info_set,
+            local_map,
+        ))
+    } else {
+        None
+    };
+
+    CodegenedFunction {
+        symbol_name: name,
+        func_id,
+        func,
+        clif_comments,
+        func_debug_cx,
+        asm_source_lines: crate::disasm::source_lines(tcx, &source_info_set),
+        source_info_set,
+        trap_messages,
+    }
+}
+
+/// Compile a function previously lowered by [`codegen_fn`] and define it in `module`.
+///
+/// This doesn't need a `TyCtxt` and as such can run on a codegen worker thread. `clif_output` is
+/// only `Some` when clif ir should be written for debugging. The function is appended to the
+/// enabled `text_outputs`. The compilation result is left in `cached_context` until the next
+/// function is compiled.
+pub(crate) fn compile_fn(
+    module: &mut impl Module,
+    cached_context: &mut Context,
+    debug_context: Option<&mut DebugContext>,
+    unwind_context: &mut UnwindContext,
+    clif_output: Option<&OutputFilenames>,
+    text_outputs: &mut CguTextOutputs,
+    codegened_func: CodegenedFunction,
+) {
+    let CodegenedFunction {
+        symbol_name,
+        func_id,
+        func,
+        mut clif_comments,
+        func_debug_cx,
+        source_info_set: _,
+        trap_messages,
+        asm_source_lines,
+    } = codegened_func;
+
+    let _inst_guard = crate::PrintOnPanic(|| symbol_name.clone());
+
+    let context = cached_context;
+    context.clear();
+    context.func = func;
+
     // If the return block is not reachable, then the SSA builder may have inserted an `iconst.i128`
     // instruction, which doesn't have an encoding.
     context.compute_cfg();
     context.compute_domtree();
-    context.eliminate_unreachable_code(cx.module.isa()).unwrap();
-    context.dce(cx.module.isa()).unwrap();
+    context.eliminate_unreachable_code(module.isa()).unwrap();
+    context.dce(module.isa()).unwrap();
     // Some Cranelift optimizations expect the domtree to not yet be computed and as such don't
     // invalidate it when it would change.
     context.domtree.clear();
 
-    context.want_disasm = crate::pretty_clif::should_write_ir(tcx);
+    if let Some(clif) = &mut text_outputs.clif {
+        crate::pretty_clif::write_clif_function(clif, module.isa(), &context.func, &clif_comments);
+    }
+
+    let clif_output = clif_output.filter(|_| clif_comments.dump_ir());
+    context.want_disasm = clif_output.is_some() || text_outputs.asm.is_some();
 
     // Define function
-    let module = &mut cx.module;
-    tcx.sess.time("define function", || {
+    let mut trap_sink = crate::trap_metadata::TrapSink::default();
+    if let Some(reproducer_file) = clif_comments.reproducer_file() {
+        crate::reduce::define_function_or_reduce(
+            module,
+            func_id,
+            context,
+            &mut trap_sink,
+            &clif_comments,
+            &symbol_name,
+            reproducer_file,
+        );
+    } else {
         module
-            .define_function(
-                func_id,
-                context,
-                &mut cranelift_codegen::binemit::NullTrapSink {},
-            )
-            .unwrap()
-    });
+            .define_function(func_id, context, &mut trap_sink)
+            .unwrap();
+    }
+    if let Some(trap_messages) = &trap_messages {
+        crate::trap_metadata::define_trap_records(
+            module,
+            func_id,
+            &symbol_name,
+            trap_sink,
+            trap_messages,
+        );
+    }
 
-    // Write optimized function to file for debugging
-    crate::pretty_clif::write_clif_file(
-        tcx,
-        "opt",
-        Some(cx.module.isa()),
-        instance,
-        &context,
-        &clif_comments,
-    );
+    if let Some(asm) = &mut text_outputs.asm {
+        crate::disasm::write_function_asm(
+            asm,
+            module,
+            &symbol_name,
+            context,
+            asm_source_lines.as_deref(),
+        );
+    }
 
-    if let Some(mach_compile_result) = &context.mach_compile_result {
-        if let Some(disasm) = &mach_compile_result.disasm {
-            crate::pretty_clif::write_ir_file(
-                tcx,
-                &format!("{}.vcode", tcx.symbol_name(instance).name),
-                |file| file.write_all(disasm.as_bytes()),
-            )
+    // Write optimized function to file for debugging
+    if let Some(output_filenames) = clif_output {
+        crate::pretty_clif::write_clif_file(
+            output_filenames,
+            &symbol_name,
+            "opt",
+            module.isa(),
+            &context,
+            &mut clif_comments,
+        );
+        crate::pretty_clif::write_clif_stage_diff(output_filenames, &symbol_name, &clif_comments);
+
+        if let Some(mach_compile_result) = &context.mach_compile_result {
+            if let Some(disasm) = &mach_compile_result.disasm {
+                crate::pretty_clif::write_ir_file(
+                    output_filenames,
+                    &format!("{}.vcode", symbol_name),
+                    |file| file.write_all(disasm.as_bytes()),
+                )
+            }
         }
     }
 
     // Define debuginfo for function
-    let isa = cx.module.isa();
-    let debug_context = &mut cx.debug_context;
-    let unwind_context = &mut cx.unwind_context;
-    tcx.sess.time("generate debug info", || {
-        if let Some(debug_context) = debug_context {
-            debug_context.define_function(
-                instance,
-                func_id,
-                &name,
-                isa,
-                context,
-                &source_info_set,
-                local_map,
-            );
-        }
-        unwind_context.add_function(func_id, &context, isa);
+    let isa = module.isa();
+    if let (Some(debug_context), Some(func_debug_cx)) = (debug_context, func_debug_cx) {
+        func_debug_cx.finalize(debug_context, func_id, isa, context);
+    }
+    unwind_context.add_function(func_id, &context, isa);
+}
+
+/// Lower `instance` to clif ir and immediately compile it into `cx.module`.
+///
+/// This is used by the JIT, which needs the function to be defined right away.
+pub(crate) fn codegen_and_compile_fn<'tcx>(
+    cx: &mut crate::CodegenCx<'tcx, impl Module>,
+    instance: Instance<'tcx>,
+    linkage: Linkage,
+) -> CompiledFunction {
+    let codegened_func = codegen_fn(cx, instance, linkage);
+    compile_codegened_fn(cx, instance, codegened_func)
+}
+
+/// Compile a function lowered by [`codegen_fn`] for `instance` into `cx.module` on the current
+/// thread.
+pub(crate) fn compile_codegened_fn<'tcx>(
+    cx: &mut crate::CodegenCx<'tcx, impl Module>,
+    instance: Instance<'tcx>,
+    mut codegened_func: CodegenedFunction,
+) -> CompiledFunction {
+    let tcx = cx.tcx;
+    let func_id = codegened_func.func_id;
+    let source_info_set = std::mem::take(&mut codegened_func.source_info_set);
+    let clif_output = if crate::pretty_clif::should_write_ir(tcx) {
+        Some(&**tcx.output_filenames(LOCAL_CRATE))
+    } else {
+        None
+    };
+    tcx.sess.time("compile function", || {
+        compile_fn(
+            &mut cx.module,
+            &mut cx.cached_context,
+            cx.debug_context.as_mut(),
+            &mut cx.unwind_context,
+            clif_output,
+            &mut CguTextOutputs::default(),
+            codegened_func,
+        )
     });
 
-    // Clear context to make it usable for the next function
-    context.clear();
+    let function_span = tcx.instance_mir(instance.def).span;
+    let span_for = |loc: SourceLoc| {
+        if loc.is_default() {
+            function_span
+        } else {
+            source_info_set[loc.bits() as usize].span
+        }
+    };
+
+    let context = &cx.cached_context;
+    let (size, source_spans) = if let Some(mach_compile_result) = &context.mach_compile_result {
+        let source_spans = mach_compile_result
+            .buffer
+            .get_srclocs_sorted()
+            .iter()
+            .map(|srcloc| (srcloc.start, span_for(srcloc.loc)))
+            .collect();
+        (mach_compile_result.buffer.total_size(), source_spans)
+    } else {
+        let func = &context.func;
+        let encinfo = cx.module.isa().encoding_info();
+        let mut blocks = func.layout.blocks().collect::<Vec<_>>();
+        blocks.sort_by_key(|block| func.offsets[*block]); // Ensure inst offsets always increase
+
+        let mut size = 0;
+        let mut source_spans = Vec::new();
+        for block in blocks {
+            for (offset, inst, inst_size) in func.inst_offsets(block, &encinfo) {
+                source_spans.push((offset, span_for(func.srclocs[inst])));
+                size = offset + inst_size;
+            }
+        }
+        (size, source_spans)
+    };
+
+    CompiledFunction {
+        func_id,
+        size,
+        source_spans,
+    }
 }
 
 pub(crate) fn verify_func(
     tcx: TyCtxt<'_>,
+    isa: &dyn isa::TargetIsa,
     writer: &crate::pretty_clif::CommentWriter,
     func: &Function,
 ) {
@@ -193,14 +420,39 @@ pub(crate) fn verify_func(
             Ok(_) => {}
             Err(err) => {
                 tcx.sess.err(&format!("{:?}", err));
+                let reduced = writer.reproducer_file().map(|reproducer_file| {
+                    let res = crate::reduce::reduce_verifier_error(
+                        isa,
+                        func,
+                        writer,
+                        &err,
+                        reproducer_file,
+                    );
+                    (reproducer_file, res)
+                });
                 let pretty_error = cranelift_codegen::print_errors::pretty_verifier_error(
                     &func,
                     None,
                     Some(Box::new(writer)),
                     err,
                 );
-                tcx.sess
-                    .fatal(&format!("cranelift verify error:\n{}", pretty_error));
+                let mut diag = tcx
+                    .sess
+                    .struct_fatal(&format!("cranelift verify error:\n{}", pretty_error));
+                match reduced {
+                    Some((reproducer_file, Ok(()))) => diag.note(&format!(
+                        "a reduced test case has been written to `{}`",
+                        reproducer_file.display()
+                    )),
+                    Some((reproducer_file, Err(err))) => diag.note(&format!(
+                        "error writing reduced test case `{}`: {}",
+                        reproducer_file.display(),
+                        err
+                    )),
+                    None => diag.note("set `CG_CLIF_REDUCE=1` to write a reduced test case"),
+                };
+                diag.emit();
+                rustc_errors::FatalError.raise();
             }
         }
     });
@@ -228,8 +480,7 @@ fn codegen_fn_content(fx: &mut FunctionCx<'_, '_, impl Module>) {
             codegen_stmt(fx, block, stmt);
         }
 
-        #[cfg(debug_assertions)]
-        {
+        if fx.clif_comments.enabled() {
             let mut terminator_head = "\n".to_string();
             bb_data
                 .terminator()
@@ -912,13 +1163,16 @@ fn is_fat_ptr<'tcx>(
                 "int $$0x29" => {
                     crate::trap::trap_unimplemented(fx, "Windows abort");
                 }
-                _ => fx
-                    .tcx
-                    .sess
-                    .span_fatal(stmt.source_info.span, "Inline assembly is not supported"),
+                _ => crate::trap::unsupported(
+                    fx,
+                    stmt.source_info.span,
+                    "Inline assembly is not supported",
+                ),
             }
         }
-        StatementKind::Coverage { .. } => fx.tcx.sess.fatal("-Zcoverage is unimplemented"),
+        StatementKind::Coverage { .. } => {
+            crate::trap::unsupported(fx, stmt.source_info.span, "-Zcoverage is unimplemented")
+        }
     }
 }
 
@@ -6,6 +6,8 @@
 extern crate rustc_session;
 extern crate rustc_target;
 
+use std::ffi::OsString;
+
 use rustc_data_structures::profiling::{get_resident_set_size, print_time_passes_entry};
 use rustc_interface::interface;
 use rustc_session::config::ErrorOutputType;
@@ -38,6 +40,38 @@ fn config(&mut self, config: &mut interface::Config) {
     }
 }
 
+/// libtest runs every test of a JIT compiled test harness in a new process by running rustc with
+/// the name of the test in `__RUST_TEST_INVOKE`. In that case JIT compile the test harness again
+/// using the original arguments passed through `JIT_TEST_RUSTC_ARGS_VAR`. Any arguments rustc was
+/// spawned with are ignored.
+#[cfg(feature = "jit")]
+fn jit_test_process_args(args: Vec<OsString>) -> Vec<OsString> {
+    if std::env::var_os("__RUST_TEST_INVOKE").is_none() {
+        return args;
+    }
+    let rustc_args = match std::env::var_os(rustc_codegen_cranelift::JIT_TEST_RUSTC_ARGS_VAR) {
+        Some(rustc_args) => rustc_args,
+        None => return args,
+    };
+    let rustc_args = rustc_codegen_cranelift::decode_jit_test_rustc_args(&rustc_args)
+        .unwrap_or_else(|| {
+            early_error(
+                ErrorOutputType::default(),
+                &format!(
+                    "`{}` is malformed",
+                    rustc_codegen_cranelift::JIT_TEST_RUSTC_ARGS_VAR
+                ),
+            )
+        });
+
+    args.into_iter()
+        .take(1)
+        .chain(rustc_args)
+        // Warnings would end up in the captured output of the test.
+        .chain(std::iter::once(OsString::from("-Awarnings")))
+        .collect()
+}
+
 fn main() {
     let start_time = std::time::Instant::now();
     let start_rss = get_resident_set_size();
@@ -45,7 +79,11 @@ fn main() {
     let mut callbacks = CraneliftPassesCallbacks::default();
     rustc_driver::install_ice_hook();
     let exit_code = rustc_driver::catch_with_exit_code(|| {
-        let args = std::env::args_os()
+        let args = std::env::args_os().collect::<Vec<_>>();
+        #[cfg(feature = "jit")]
+        let args = jit_test_process_args(args);
+        let args = args
+            .into_iter()
             .enumerate()
             .map(|(i, arg)| {
                 arg.into_string().unwrap_or_else(|arg| {
@@ -58,7 +96,7 @@ fn main() {
             .collect::<Vec<_>>();
         let mut run_compiler = rustc_driver::RunCompiler::new(&args, &mut callbacks);
         run_compiler.set_make_codegen_backend(Some(Box::new(move |_| {
-            Box::new(rustc_codegen_cranelift::CraneliftCodegenBackend { config: None })
+            Box::new(rustc_codegen_cranelift::CraneliftCodegenBackend::default())
         })));
         run_compiler.run()
     });
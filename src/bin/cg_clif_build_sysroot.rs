@@ -89,7 +89,7 @@ fn main() {
         let mut run_compiler = rustc_driver::RunCompiler::new(&args, &mut callbacks);
         if use_clif {
             run_compiler.set_make_codegen_backend(Some(Box::new(move |_| {
-                Box::new(rustc_codegen_cranelift::CraneliftCodegenBackend { config: None })
+                Box::new(rustc_codegen_cranelift::CraneliftCodegenBackend::default())
             })));
         }
         run_compiler.run()
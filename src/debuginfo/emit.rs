@@ -9,18 +9,9 @@
 
 use super::DebugContext;
 
-impl DebugContext<'_> {
+impl DebugContext {
     pub(crate) fn emit<P: WriteDebugInfo>(&mut self, product: &mut P) {
-        let unit_range_list_id = self.dwarf.unit.ranges.add(self.unit_range_list.clone());
-        let root = self.dwarf.unit.root();
-        let root = self.dwarf.unit.get_mut(root);
-        root.set(
-            gimli::DW_AT_ranges,
-            AttributeValue::RangeListRef(unit_range_list_id),
-        );
-
-        let mut sections = Sections::new(WriterRelocate::new(self.endian));
-        self.dwarf.write(&mut sections).unwrap();
+        let mut sections = self.write_sections();
 
         let mut section_map = FxHashMap::default();
         let _: Result<()> = sections.for_each_mut(|id, section| {
@@ -40,6 +31,21 @@ pub(crate) fn emit<P: WriteDebugInfo>(&mut self, product: &mut P) {
             Ok(())
         });
     }
+
+    /// Write all DWARF sections. Relocations are collected, but not applied.
+    pub(super) fn write_sections(&mut self) -> Sections<WriterRelocate> {
+        let unit_range_list_id = self.dwarf.unit.ranges.add(self.unit_range_list.clone());
+        let root = self.dwarf.unit.root();
+        let root = self.dwarf.unit.get_mut(root);
+        root.set(
+            gimli::DW_AT_ranges,
+            AttributeValue::RangeListRef(unit_range_list_id),
+        );
+
+        let mut sections = Sections::new(WriterRelocate::new(self.endian));
+        self.dwarf.write(&mut sections).unwrap();
+        sections
+    }
 }
 
 #[derive(Clone)]
@@ -73,13 +79,19 @@ pub(super) fn new(endian: RunTimeEndian) -> Self {
     }
 
     /// Perform the collected relocations to be usable for JIT usage.
+    ///
+    /// References to other sections are resolved as offsets, like in a single object file.
     #[cfg(feature = "jit")]
     pub(super) fn relocate_for_jit(mut self, jit_module: &cranelift_jit::JITModule) -> Vec<u8> {
         use std::convert::TryInto;
 
         for reloc in self.relocs.drain(..) {
             match reloc.name {
-                super::DebugRelocName::Section(_) => unreachable!(),
+                super::DebugRelocName::Section(_) => {
+                    self.writer
+                        .write_udata_at(reloc.offset as usize, reloc.addend as u64, reloc.size)
+                        .unwrap();
+                }
                 super::DebugRelocName::Symbol(sym) => {
                     let addr = jit_module.get_finalized_function(
                         cranelift_module::FuncId::from_u32(sym.try_into().unwrap()),
@@ -0,0 +1,149 @@
+//! Registration of the debuginfo of jitted functions with gdb and lldb using the
+//! [GDB JIT interface](https://sourceware.org/gdb/current/onlinedocs/gdb/JIT-Interface.html).
+//!
+//! For every batch of finalized functions an in-memory object file containing the DWARF sections
+//! is created. Jitted functions are scattered over memory, so instead of placing the functions in
+//! a section, all addresses in the object file are absolute and the `.text` section is empty.
+
+use std::convert::TryFrom;
+
+use cranelift_jit::JITModule;
+use gimli::write::{Address, Range};
+use object::write::{StandardSegment, Symbol, SymbolSection};
+use object::{SectionKind, SymbolFlags, SymbolKind, SymbolScope};
+
+use crate::prelude::*;
+
+use super::emit::WriterRelocate;
+use super::DebugContext;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+const JIT_REGISTER_FN: u32 = 1;
+
+/// The debugger reads the list of registered object files from this descriptor.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: std::ptr::null_mut(),
+    first_entry: std::ptr::null_mut(),
+};
+
+/// The debugger sets a breakpoint on this function to get notified about new object files.
+#[no_mangle]
+#[inline(never)]
+extern "C" fn __jit_debug_register_code() {
+    // Make sure calls to this function are not optimized away.
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+impl DebugContext {
+    /// Register the debuginfo of all functions in this debug context with an attached debugger.
+    /// All functions must have been finalized before.
+    ///
+    /// The registration is never undone, not even when the jitted functions are freed using
+    /// `JitHandle::free`. This must only be called on the rustc thread, as the descriptor isn't
+    /// protected by a lock.
+    pub(crate) fn register_jit(mut self, tcx: TyCtxt<'_>, jit_module: &JITModule) {
+        let functions = self
+            .unit_range_list
+            .0
+            .iter()
+            .map(|range| match *range {
+                Range::StartLength {
+                    begin: Address::Symbol { symbol, addend: 0 },
+                    length,
+                } => (FuncId::from_u32(u32::try_from(symbol).unwrap()), length),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        if functions.is_empty() {
+            return;
+        }
+
+        let mut sections = self.write_sections();
+
+        let symfile = crate::backend::with_object(tcx.sess, "jit", |object| {
+            let text_section = object.add_section(
+                object.segment_name(StandardSegment::Text).to_vec(),
+                b".text".to_vec(),
+                SectionKind::Text,
+            );
+            for (func_id, length) in functions {
+                let name = jit_module
+                    .declarations()
+                    .get_function_decl(func_id)
+                    .name
+                    .clone();
+                object.add_symbol(Symbol {
+                    name: name.into_bytes(),
+                    value: jit_module.get_finalized_function(func_id) as u64,
+                    size: length,
+                    kind: SymbolKind::Text,
+                    scope: SymbolScope::Compilation,
+                    weak: false,
+                    section: SymbolSection::Section(text_section),
+                    flags: SymbolFlags::None,
+                });
+            }
+
+            let _: gimli::write::Result<()> = sections.for_each_mut(|id, section| {
+                if section.writer.slice().is_empty() {
+                    return Ok(());
+                }
+
+                let section = std::mem::replace(section, WriterRelocate::new(self.endian));
+                let name = if object.format() == object::BinaryFormat::MachO {
+                    id.name().replace('.', "__") // machO expects __debug_info instead of .debug_info
+                } else {
+                    id.name().to_string()
+                };
+                let section_id = object.add_section(
+                    object.segment_name(StandardSegment::Debug).to_vec(),
+                    name.into_bytes(),
+                    SectionKind::Debug,
+                );
+                object
+                    .section_mut(section_id)
+                    .set_data(section.relocate_for_jit(jit_module), 1);
+                Ok(())
+            });
+        });
+
+        // The debugger may read the object file at any time until the process exits.
+        let symfile = Box::leak(symfile.into_boxed_slice());
+        let entry = Box::leak(Box::new(JitCodeEntry {
+            next_entry: std::ptr::null_mut(),
+            prev_entry: std::ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        }));
+
+        unsafe {
+            entry.next_entry = __jit_debug_descriptor.first_entry;
+            if let Some(first_entry) = entry.next_entry.as_mut() {
+                first_entry.prev_entry = entry;
+            }
+            __jit_debug_descriptor.first_entry = entry;
+            __jit_debug_descriptor.relevant_entry = entry;
+            __jit_debug_descriptor.action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        }
+    }
+}
@@ -5,6 +5,7 @@
 
 use crate::prelude::*;
 
+use rustc_data_structures::sync::Lrc;
 use rustc_span::{
     FileName, Pos, SourceFile, SourceFileAndLine, SourceFileHash, SourceFileHashAlgorithm,
 };
@@ -17,6 +18,8 @@
     UnitEntryId,
 };
 
+use super::{DebugContext, FunctionDebugContext};
+
 // OPTIMIZATION: It is cheaper to do this in one pass than using `.parent()` and `.file_name()`.
 fn split_path_dir_and_file(path: &Path) -> (&Path, &OsStr) {
     let mut iter = path.components();
@@ -100,9 +103,15 @@ fn line_program_add_file(
     }
 }
 
-impl<'tcx> DebugContext<'tcx> {
-    pub(super) fn emit_location(&mut self, entry_id: UnitEntryId, span: Span) {
-        let loc = self.tcx.sess.source_map().lookup_char_pos(span.lo());
+impl DebugContext {
+    /// Set the `DW_AT_decl_*` attributes of `entry_id` and return the resolved source location.
+    pub(super) fn emit_location(
+        &mut self,
+        tcx: TyCtxt<'_>,
+        entry_id: UnitEntryId,
+        span: Span,
+    ) -> (FileId, u64, u64) {
+        let loc = tcx.sess.source_map().lookup_char_pos(span.lo());
 
         let file_id = line_program_add_file(
             &mut self.dwarf.unit.line_program,
@@ -125,33 +134,27 @@ pub(super) fn emit_location(&mut self, entry_id: UnitEntryId, span: Span) {
             gimli::DW_AT_decl_column,
             AttributeValue::Udata(loc.col.to_usize() as u64),
         );
+
+        (
+            file_id,
+            loc.line as u64,
+            loc.col.to_usize() as u64 + 1,
+        )
     }
 
-    pub(super) fn create_debug_lines(
+    /// Resolve the span of every entry of `source_info_set` to a file, line and column. The result
+    /// is indexed by the `SourceLoc` that `FunctionCx::set_debug_loc` assigned to the entry.
+    pub(super) fn resolve_source_locs(
         &mut self,
-        isa: &dyn cranelift_codegen::isa::TargetIsa,
-        symbol: usize,
-        entry_id: UnitEntryId,
-        context: &Context,
+        tcx: TyCtxt<'_>,
         function_span: Span,
         source_info_set: &indexmap::IndexSet<SourceInfo>,
-    ) -> CodeOffset {
-        let tcx = self.tcx;
+    ) -> Vec<(FileId, u64, u64)> {
         let line_program = &mut self.dwarf.unit.line_program;
-        let func = &context.func;
-
         let line_strings = &mut self.dwarf.line_strings;
-        let mut last_span = None;
-        let mut last_file = None;
-        let mut create_row_for_span = |line_program: &mut LineProgram, span: Span| {
-            if let Some(last_span) = last_span {
-                if span == last_span {
-                    line_program.generate_row();
-                    return;
-                }
-            }
-            last_span = Some(span);
 
+        let mut last_file: Option<(Lrc<SourceFile>, FileId)> = None;
+        let mut resolve_span = |span: Span| {
             // Based on https://github.com/rust-lang/rust/blob/e369d87b015a84653343032833d65d0545fd3f26/src/librustc_codegen_ssa/mir/mod.rs#L116-L131
             // In order to have a good line stepping behavior in debugger, we overwrite debug
             // locations of macro expansions with that of the outermost expansion site
@@ -180,22 +183,60 @@ pub(super) fn create_debug_lines(
 
             // line_program_add_file is very slow.
             // Optimize for the common case of the current file not being changed.
-            let current_file_changed = if let Some(last_file) = &last_file {
+            let file_id = match &last_file {
                 // If the allocations are not equal, then the files may still be equal, but that
                 // is not a problem, as this is just an optimization.
-                !rustc_data_structures::sync::Lrc::ptr_eq(last_file, &file)
-            } else {
-                true
+                Some((last_file, file_id)) if Lrc::ptr_eq(last_file, &file) => *file_id,
+                _ => {
+                    let file_id = line_program_add_file(line_program, line_strings, &file);
+                    last_file = Some((file, file_id));
+                    file_id
+                }
             };
-            if current_file_changed {
-                let file_id = line_program_add_file(line_program, line_strings, &file);
+
+            (file_id, line, col)
+        };
+
+        source_info_set
+            .iter()
+            .map(|source_info| resolve_span(source_info.span))
+            .collect()
+    }
+}
+
+impl FunctionDebugContext {
+    pub(super) fn create_debug_lines(
+        &self,
+        debug_context: &mut DebugContext,
+        isa: &dyn cranelift_codegen::isa::TargetIsa,
+        symbol: usize,
+        context: &Context,
+    ) -> CodeOffset {
+        let line_program = &mut debug_context.dwarf.unit.line_program;
+        let func = &context.func;
+
+        let mut last_source_loc = None;
+        let mut create_row_for_source_loc =
+            |line_program: &mut LineProgram, source_loc: (FileId, u64, u64)| {
+                if last_source_loc == Some(source_loc) {
+                    line_program.generate_row();
+                    return;
+                }
+                last_source_loc = Some(source_loc);
+
+                let (file_id, line, col) = source_loc;
                 line_program.row().file = file_id;
-                last_file = Some(file);
-            }
+                line_program.row().line = line;
+                line_program.row().column = col;
+                line_program.generate_row();
+            };
 
-            line_program.row().line = line;
-            line_program.row().column = col;
-            line_program.generate_row();
+        let source_loc_for = |loc: SourceLoc| {
+            if !loc.is_default() {
+                self.source_loc_set[loc.bits() as usize]
+            } else {
+                self.function_source_loc
+            }
         };
 
         line_program.begin_sequence(Some(Address::Symbol { symbol, addend: 0 }));
@@ -205,12 +246,7 @@ pub(super) fn create_debug_lines(
         if let Some(ref mcr) = &context.mach_compile_result {
             for &MachSrcLoc { start, end, loc } in mcr.buffer.get_srclocs_sorted() {
                 line_program.row().address_offset = u64::from(start);
-                if !loc.is_default() {
-                    let source_info = *source_info_set.get_index(loc.bits() as usize).unwrap();
-                    create_row_for_span(line_program, source_info.span);
-                } else {
-                    create_row_for_span(line_program, function_span);
-                }
+                create_row_for_source_loc(line_program, source_loc_for(loc));
                 func_end = end;
             }
 
@@ -224,15 +260,8 @@ pub(super) fn create_debug_lines(
 
             for block in blocks {
                 for (offset, inst, size) in func.inst_offsets(block, &encinfo) {
-                    let srcloc = func.srclocs[inst];
                     line_program.row().address_offset = u64::from(offset);
-                    if !srcloc.is_default() {
-                        let source_info =
-                            *source_info_set.get_index(srcloc.bits() as usize).unwrap();
-                        create_row_for_span(line_program, source_info.span);
-                    } else {
-                        create_row_for_span(line_program, function_span);
-                    }
+                    create_row_for_source_loc(line_program, source_loc_for(func.srclocs[inst]));
                     func_end = offset + size;
                 }
             }
@@ -241,7 +270,7 @@ pub(super) fn create_debug_lines(
 
         assert_ne!(func_end, 0);
 
-        let entry = self.dwarf.unit.get_mut(entry_id);
+        let entry = debug_context.dwarf.unit.get_mut(self.entry_id);
         entry.set(
             gimli::DW_AT_low_pc,
             AttributeValue::Address(Address::Symbol { symbol, addend: 0 }),
@@ -251,8 +280,6 @@ pub(super) fn create_debug_lines(
             AttributeValue::Udata(u64::from(func_end)),
         );
 
-        self.emit_location(entry_id, function_span);
-
         func_end
     }
 }
@@ -1,6 +1,8 @@
 //! Handling of everything related to debuginfo.
 
 mod emit;
+#[cfg(feature = "jit")]
+mod gdb_jit;
 mod line_info;
 mod unwind;
 
@@ -14,13 +16,13 @@
 use cranelift_codegen::ValueLocRange;
 
 use gimli::write::{
-    Address, AttributeValue, DwarfUnit, Expression, LineProgram, LineString, Location,
+    Address, AttributeValue, DwarfUnit, Expression, FileId, LineProgram, LineString, Location,
     LocationList, Range, RangeList, UnitEntryId,
 };
 use gimli::{Encoding, Format, LineEncoding, RunTimeEndian, X86_64};
 
 pub(crate) use emit::{DebugReloc, DebugRelocName};
-pub(crate) use unwind::UnwindContext;
+pub(crate) use unwind::{UnwindContext, UnwindRegistry};
 
 fn target_endian(tcx: TyCtxt<'_>) -> RunTimeEndian {
     use rustc_target::abi::Endian;
@@ -31,19 +33,40 @@ fn target_endian(tcx: TyCtxt<'_>) -> RunTimeEndian {
     }
 }
 
-pub(crate) struct DebugContext<'tcx> {
-    tcx: TyCtxt<'tcx>,
-
+/// The DWARF unit of a single object file.
+///
+/// This doesn't hold on to the `TyCtxt`, so that it can be moved to a codegen worker thread
+/// together with the module once all functions of a codegen unit have been lowered to clif ir.
+pub(crate) struct DebugContext {
     endian: RunTimeEndian,
 
     dwarf: DwarfUnit,
     unit_range_list: RangeList,
+}
+
+/// Debuginfo of a single function that has been lowered to clif ir, but not yet compiled.
+///
+/// All source locations have already been resolved into line program entries, as that requires
+/// the `TyCtxt`. The rows of the line program can only be generated once the machine code offsets
+/// are known, which happens in [`FunctionDebugContext::finalize`].
+pub(crate) struct FunctionDebugContext {
+    entry_id: UnitEntryId,
+    function_source_loc: (FileId, u64, u64),
+    source_loc_set: Vec<(FileId, u64, u64)>,
+    /// The variable entries of all locals and where they are stored. Their locations can only be
+    /// computed once the function has been compiled.
+    locals: Vec<(UnitEntryId, CPlaceInner)>,
+}
 
+/// The DWARF type entries of a [`DebugContext`]. These are keyed by `Ty`, so unlike the
+/// `DebugContext` they stay on the rustc thread.
+#[derive(Default)]
+pub(crate) struct TypeDebugContext<'tcx> {
     types: FxHashMap<Ty<'tcx>, UnitEntryId>,
 }
 
-impl<'tcx> DebugContext<'tcx> {
-    pub(crate) fn new(tcx: TyCtxt<'tcx>, isa: &dyn TargetIsa) -> Self {
+impl DebugContext {
+    pub(crate) fn new(tcx: TyCtxt<'_>, isa: &dyn TargetIsa) -> Self {
         let encoding = Encoding {
             format: Format::Dwarf32,
             // TODO: this should be configurable
@@ -107,19 +130,20 @@ pub(crate) fn new(tcx: TyCtxt<'tcx>, isa: &dyn TargetIsa) -> Self {
         }
 
         DebugContext {
-            tcx,
-
             endian: target_endian(tcx),
 
             dwarf,
             unit_range_list: RangeList(Vec::new()),
-
-            types: FxHashMap::default(),
         }
     }
 
-    fn dwarf_ty(&mut self, ty: Ty<'tcx>) -> UnitEntryId {
-        if let Some(type_id) = self.types.get(ty) {
+    fn dwarf_ty<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        type_dbg: &mut TypeDebugContext<'tcx>,
+        ty: Ty<'tcx>,
+    ) -> UnitEntryId {
+        if let Some(type_id) = type_dbg.types.get(ty) {
             return *type_id;
         }
 
@@ -133,7 +157,7 @@ fn dwarf_ty(&mut self, ty: Ty<'tcx>) -> UnitEntryId {
         };
 
         let name = format!("{}", ty);
-        let layout = self.tcx.layout_of(ParamEnv::reveal_all().and(ty)).unwrap();
+        let layout = tcx.layout_of(ParamEnv::reveal_all().and(ty)).unwrap();
 
         let type_id = match ty.kind() {
             ty::Bool => primitive(&mut self.dwarf, gimli::DW_ATE_boolean),
@@ -149,9 +173,9 @@ fn dwarf_ty(&mut self, ty: Ty<'tcx>) -> UnitEntryId {
                 let type_id = new_entry(&mut self.dwarf, gimli::DW_TAG_pointer_type);
 
                 // Ensure that type is inserted before recursing to avoid duplicates
-                self.types.insert(ty, type_id);
+                type_dbg.types.insert(ty, type_id);
 
-                let pointee = self.dwarf_ty(pointee_ty);
+                let pointee = self.dwarf_ty(tcx, type_dbg, pointee_ty);
 
                 let type_entry = self.dwarf.unit.get_mut(type_id);
 
@@ -164,7 +188,7 @@ fn dwarf_ty(&mut self, ty: Ty<'tcx>) -> UnitEntryId {
                 let type_id = new_entry(&mut self.dwarf, gimli::DW_TAG_structure_type);
 
                 // Ensure that type is inserted before recursing to avoid duplicates
-                self.types.insert(ty, type_id);
+                type_dbg.types.insert(ty, type_id);
 
                 let variant = adt_def.non_enum_variant();
 
@@ -173,14 +197,14 @@ fn dwarf_ty(&mut self, ty: Ty<'tcx>) -> UnitEntryId {
                     let field_layout = layout
                         .field(
                             &layout::LayoutCx {
-                                tcx: self.tcx,
+                                tcx,
                                 param_env: ParamEnv::reveal_all(),
                             },
                             field_idx,
                         )
                         .unwrap();
 
-                    let field_type = self.dwarf_ty(field_layout.ty);
+                    let field_type = self.dwarf_ty(tcx, type_dbg, field_layout.ty);
 
                     let field_id = self.dwarf.unit.add(type_id, gimli::DW_TAG_member);
                     let field_entry = self.dwarf.unit.get_mut(field_id);
@@ -209,13 +233,20 @@ fn dwarf_ty(&mut self, ty: Ty<'tcx>) -> UnitEntryId {
             AttributeValue::Udata(layout.size.bytes()),
         );
 
-        self.types.insert(ty, type_id);
+        type_dbg.types.insert(ty, type_id);
 
         type_id
     }
 
-    fn define_local(&mut self, scope: UnitEntryId, name: String, ty: Ty<'tcx>) -> UnitEntryId {
-        let dw_ty = self.dwarf_ty(ty);
+    fn define_local<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        type_dbg: &mut TypeDebugContext<'tcx>,
+        scope: UnitEntryId,
+        name: String,
+        ty: Ty<'tcx>,
+    ) -> UnitEntryId {
+        let dw_ty = self.dwarf_ty(tcx, type_dbg, ty);
 
         let var_id = self.dwarf.unit.add(scope, gimli::DW_TAG_variable);
         let var_entry = self.dwarf.unit.get_mut(var_id);
@@ -226,18 +257,16 @@ fn define_local(&mut self, scope: UnitEntryId, name: String, ty: Ty<'tcx>) -> Un
         var_id
     }
 
-    pub(crate) fn define_function(
+    pub(crate) fn define_function<'tcx>(
         &mut self,
+        tcx: TyCtxt<'tcx>,
+        type_dbg: &mut TypeDebugContext<'tcx>,
         instance: Instance<'tcx>,
-        func_id: FuncId,
         name: &str,
-        isa: &dyn TargetIsa,
-        context: &Context,
         source_info_set: &indexmap::IndexSet<SourceInfo>,
         local_map: IndexVec<mir::Local, CPlace<'tcx>>,
-    ) {
-        let symbol = func_id.as_u32() as usize;
-        let mir = self.tcx.instance_mir(instance.def);
+    ) -> FunctionDebugContext {
+        let mir = tcx.instance_mir(instance.def);
 
         // FIXME: add to appropriate scope instead of root
         let scope = self.dwarf.unit.root();
@@ -252,10 +281,48 @@ pub(crate) fn define_function(
             AttributeValue::StringRef(name_id),
         );
 
-        let end =
-            self.create_debug_lines(isa, symbol, entry_id, context, mir.span, source_info_set);
+        let function_source_loc = self.emit_location(tcx, entry_id, mir.span);
+        let source_loc_set = self.resolve_source_locs(tcx, mir.span, source_info_set);
+
+        let mut locals = Vec::new();
+
+        // FIXME make it more reliable and implement scopes before re-enabling this.
+        if false {
+            for (local, _local_decl) in mir.local_decls.iter_enumerated() {
+                let ty = tcx.subst_and_normalize_erasing_regions(
+                    instance.substs,
+                    ty::ParamEnv::reveal_all(),
+                    mir.local_decls[local].ty,
+                );
+                let var_id = self.define_local(tcx, type_dbg, entry_id, format!("{:?}", local), ty);
+                locals.push((var_id, *local_map[local].inner()));
+            }
+        }
+
+        // FIXME create locals for all entries in mir.var_debug_info
+
+        FunctionDebugContext {
+            entry_id,
+            function_source_loc,
+            source_loc_set,
+            locals,
+        }
+    }
+}
+
+impl FunctionDebugContext {
+    pub(crate) fn finalize(
+        self,
+        debug_context: &mut DebugContext,
+        func_id: FuncId,
+        isa: &dyn TargetIsa,
+        context: &Context,
+    ) {
+        let symbol = func_id.as_u32() as usize;
+
+        let end = self.create_debug_lines(debug_context, isa, symbol, context);
 
-        self.unit_range_list.0.push(Range::StartLength {
+        debug_context.unit_range_list.0.push(Range::StartLength {
             begin: Address::Symbol { symbol, addend: 0 },
             length: u64::from(end),
         });
@@ -264,7 +331,7 @@ pub(crate) fn define_function(
             return; // Not yet implemented for the AArch64 backend.
         }
 
-        let func_entry = self.dwarf.unit.get_mut(entry_id);
+        let func_entry = debug_context.dwarf.unit.get_mut(self.entry_id);
         // Gdb requires both DW_AT_low_pc and DW_AT_high_pc. Otherwise the DW_TAG_subprogram is skipped.
         func_entry.set(
             gimli::DW_AT_low_pc,
@@ -273,55 +340,38 @@ pub(crate) fn define_function(
         // Using Udata for DW_AT_high_pc requires at least DWARF4
         func_entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(u64::from(end)));
 
-        // FIXME make it more reliable and implement scopes before re-enabling this.
-        if false {
+        if !self.locals.is_empty() {
             let value_labels_ranges = context.build_value_labels_ranges(isa).unwrap();
 
-            for (local, _local_decl) in mir.local_decls.iter_enumerated() {
-                let ty = self.tcx.subst_and_normalize_erasing_regions(
-                    instance.substs,
-                    ty::ParamEnv::reveal_all(),
-                    mir.local_decls[local].ty,
-                );
-                let var_id = self.define_local(entry_id, format!("{:?}", local), ty);
-
+            for (var_id, place) in &self.locals {
                 let location = place_location(
-                    self,
+                    debug_context,
                     isa,
                     symbol,
                     context,
-                    &local_map,
+                    place,
                     &value_labels_ranges,
-                    Place {
-                        local,
-                        projection: ty::List::empty(),
-                    },
                 );
 
-                let var_entry = self.dwarf.unit.get_mut(var_id);
+                let var_entry = debug_context.dwarf.unit.get_mut(*var_id);
                 var_entry.set(gimli::DW_AT_location, location);
             }
         }
-
-        // FIXME create locals for all entries in mir.var_debug_info
     }
 }
 
-fn place_location<'tcx>(
-    debug_context: &mut DebugContext<'tcx>,
+fn place_location(
+    debug_context: &mut DebugContext,
     isa: &dyn TargetIsa,
     symbol: usize,
     context: &Context,
-    local_map: &IndexVec<mir::Local, CPlace<'tcx>>,
+    place: &CPlaceInner,
     #[allow(rustc::default_hash_types)] value_labels_ranges: &std::collections::HashMap<
         ValueLabel,
         Vec<ValueLocRange>,
     >,
-    place: Place<'tcx>,
 ) -> AttributeValue {
-    assert!(place.projection.is_empty()); // FIXME implement them
-
-    match local_map[place.local].inner() {
+    match place {
         CPlaceInner::Var(_local, var) => {
             let value_label = cranelift_codegen::ir::ValueLabel::new(var.index());
             if let Some(value_loc_ranges) = value_labels_ranges.get(&value_label) {
@@ -0,0 +1,207 @@
+//! Writes the machine code of functions compiled by Cranelift as human-readable assembly for
+//! `--emit=asm`.
+//!
+//! The assembly is the disassembly of the machine instructions Cranelift generated, with all
+//! references to other functions and data objects replaced by their symbol names. Every basic block
+//! gets a `label<n>:` label, which branches refer to. When compiling with debuginfo, basic blocks
+//! are annotated with the source line they were generated for:
+//!
+//! ```text
+//! _ZN7example3foo17h6ad0b9ad93c4e3a1E:
+//! label0:
+//!     # example/example.rs:3: let a = bar(1);
+//!     pushq   %rbp
+//!     movq    %rsp, %rbp
+//!     movl    $1, %edi
+//!     call    _ZN7example3bar17h0de5e9be5a4bc36bE
+//! ```
+
+use std::fmt::Write;
+
+use rustc_session::config::{DebugInfo, OutputType};
+
+use crate::prelude::*;
+
+pub(crate) fn should_write_asm(tcx: TyCtxt<'_>) -> bool {
+    tcx.sess
+        .opts
+        .output_types
+        .contains_key(&OutputType::Assembly)
+}
+
+/// The source line of every entry of `source_info_set`, if the assembly is annotated with source
+/// lines.
+pub(crate) fn source_lines(
+    tcx: TyCtxt<'_>,
+    source_info_set: &indexmap::IndexSet<SourceInfo>,
+) -> Option<Vec<String>> {
+    if !should_write_asm(tcx) || tcx.sess.opts.debuginfo == DebugInfo::None {
+        return None;
+    }
+
+    let source_map = tcx.sess.source_map();
+    Some(
+        source_info_set
+            .iter()
+            .map(|source_info| {
+                let loc = source_map.lookup_char_pos(source_info.span.lo());
+                let text = loc
+                    .file
+                    .get_line(loc.line - 1)
+                    .map(|line| line.trim().to_string())
+                    .unwrap_or_default();
+                format!("{}:{}: {}", loc.file.name, loc.line, text)
+            })
+            .collect(),
+    )
+}
+
+/// Append the assembly of the function which has just been compiled in `context` to `asm`.
+pub(crate) fn write_function_asm(
+    asm: &mut String,
+    module: &impl Module,
+    symbol_name: &str,
+    context: &Context,
+    source_lines: Option<&[String]>,
+) {
+    writeln!(asm, "{}:", symbol_name).unwrap();
+
+    let disasm = match context
+        .mach_compile_result
+        .as_ref()
+        .and_then(|mach_compile_result| mach_compile_result.disasm.as_ref())
+    {
+        Some(disasm) => disasm,
+        None => {
+            // The legacy backend can't disassemble functions. The clif ir contains the encoding of
+            // every instruction after compilation, so write that instead.
+            for line in context.func.display(module.isa()).to_string().lines() {
+                writeln!(asm, "\t# {}", line).unwrap();
+            }
+            writeln!(asm).unwrap();
+            return;
+        }
+    };
+
+    let mut last_source_line = None;
+    for line in disasm.lines().map(str::trim) {
+        if let Some(block) = line
+            .strip_prefix("Block ")
+            .and_then(|block| block.strip_suffix(':'))
+        {
+            writeln!(asm, "label{}:", block).unwrap();
+        } else if let Some(inst) = line.strip_prefix("Inst ") {
+            let inst = inst.splitn(2, ':').nth(1).unwrap_or(inst).trim();
+            writeln!(asm, "\t{}", symbolize(module, inst)).unwrap();
+        } else if let Some(clif_block) = line
+            .strip_prefix("(original IR block: ")
+            .and_then(|block| block.strip_suffix(')'))
+        {
+            let source_line = source_lines
+                .and_then(|source_lines| block_source_line(context, clif_block, source_lines));
+            if source_line.is_some() && source_line != last_source_line {
+                writeln!(asm, "\t# {}", source_line.unwrap()).unwrap();
+                last_source_line = source_line;
+            }
+        } else if line.is_empty()
+            || line.starts_with('(')
+            || line.starts_with("Entry block")
+            || line.starts_with("VCode_")
+            || line == "}}"
+        {
+            // Details about the block layout and the register allocation.
+        } else {
+            writeln!(asm, "\t# {}", line).unwrap();
+        }
+    }
+    writeln!(asm).unwrap();
+}
+
+/// The source line of the first instruction with a source location in the clif block
+/// `clif_block`.
+fn block_source_line<'a>(
+    context: &Context,
+    clif_block: &str,
+    source_lines: &'a [String],
+) -> Option<&'a str> {
+    let func = &context.func;
+    let block = Block::with_number(clif_block.strip_prefix("block")?.parse().ok()?)?;
+    if !func.layout.is_block_inserted(block) {
+        return None;
+    }
+    let srcloc = func
+        .layout
+        .block_insts(block)
+        .map(|inst| func.srclocs[inst])
+        .find(|srcloc| !srcloc.is_default())?;
+    source_lines
+        .get(srcloc.bits() as usize)
+        .map(|source_line| &**source_line)
+}
+
+/// Replace all references to functions and data objects of `module` in `inst` with their symbol
+/// names. They are printed as `u0:<func id>` and `u1:<data id>`, or using the `Debug`
+/// implementation of `ExternalName` by some instructions.
+fn symbolize(module: &impl Module, inst: &str) -> String {
+    let mut inst = inst.to_string();
+    for namespace in 0..2 {
+        let debug_prefix = format!("User {{ namespace: {}, index: ", namespace);
+        inst = replace_external_names(&inst, &debug_prefix, " }", |index| {
+            external_name(module, namespace, index)
+        });
+        let display_prefix = format!("u{}:", namespace);
+        inst = replace_external_names(&inst, &display_prefix, "", |index| {
+            external_name(module, namespace, index)
+        });
+    }
+    inst
+}
+
+fn replace_external_names(
+    inst: &str,
+    prefix: &str,
+    suffix: &str,
+    name: impl Fn(u32) -> Option<String>,
+) -> String {
+    let mut res = String::new();
+    let mut rest = inst;
+    while let Some(pos) = rest.find(prefix) {
+        let after_prefix = &rest[pos + prefix.len()..];
+        let digits = after_prefix
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after_prefix.len());
+        let symbol = if after_prefix[digits..].starts_with(suffix) {
+            after_prefix[..digits].parse().ok().and_then(&name)
+        } else {
+            None
+        };
+        match symbol {
+            Some(symbol) => {
+                res.push_str(&rest[..pos]);
+                res.push_str(&symbol);
+                rest = &after_prefix[digits + suffix.len()..];
+            }
+            None => {
+                res.push_str(&rest[..pos + prefix.len()]);
+                rest = after_prefix;
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+fn external_name(module: &impl Module, namespace: u32, index: u32) -> Option<String> {
+    let declarations = module.declarations();
+    let name = match namespace {
+        0 => &declarations.get_function_decl(FuncId::from_u32(index)).name,
+        1 => &declarations.get_data_decl(DataId::from_u32(index)).name,
+        _ => return None,
+    };
+    // Anonymous data objects don't have a symbol name.
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.clone())
+    }
+}
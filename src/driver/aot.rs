@@ -1,22 +1,31 @@
 //! The AOT driver uses [`cranelift_object`] to write object files suitable for linking into a
 //! standalone executable.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use rustc_codegen_ssa::back::linker::LinkerInfo;
 use rustc_codegen_ssa::{CodegenResults, CompiledModule, CrateInfo, ModuleKind};
+use rustc_data_structures::fx::FxHashSet;
+use rustc_data_structures::profiling::SelfProfilerRef;
 use rustc_data_structures::stable_hasher::{HashStable, StableHasher};
 use rustc_middle::dep_graph::{WorkProduct, WorkProductId};
 use rustc_middle::middle::cstore::EncodedMetadata;
-use rustc_middle::mir::mono::{CodegenUnit, MonoItem};
+use rustc_middle::mir::mono::{CodegenUnit, Linkage as RLinkage, MonoItem, Visibility};
 use rustc_session::cgu_reuse_tracker::CguReuse;
-use rustc_session::config::{DebugInfo, OutputType};
+use rustc_session::config::{DebugInfo, OutputFilenames, OutputType};
 
-use cranelift_object::{ObjectModule, ObjectProduct};
+use cranelift_object::ObjectModule;
 
 use crate::prelude::*;
 
-use crate::backend::AddConstructor;
+use crate::base::{CguTextOutputs, CodegenedFunction};
+use crate::trap::UnsupportedSite;
+
+use super::bisect::Bisect;
+use super::fn_cache::FnCache;
+use super::global_asm::GlobalAsm;
+use super::worker_pool::WorkerPool;
 
 fn new_module(tcx: TyCtxt<'_>, name: String) -> ObjectModule {
     let module = crate::backend::make_module(tcx.sess, name);
@@ -26,21 +35,32 @@ fn new_module(tcx: TyCtxt<'_>, name: String) -> ObjectModule {
 
 struct ModuleCodegenResult(CompiledModule, Option<(WorkProductId, WorkProduct)>);
 
-impl<HCX> HashStable<HCX> for ModuleCodegenResult {
-    fn hash_stable(&self, _: &mut HCX, _: &mut StableHasher) {
-        // do nothing
-    }
-}
-
 fn emit_module(
     tcx: TyCtxt<'_>,
     name: String,
     kind: ModuleKind,
     module: ObjectModule,
-    debug: Option<DebugContext<'_>>,
-    unwind_context: UnwindContext<'_>,
-    map_product: impl FnOnce(ObjectProduct) -> ObjectProduct,
+    debug: Option<DebugContext>,
+    unwind_context: UnwindContext,
 ) -> ModuleCodegenResult {
+    let tmp_file = tcx
+        .output_filenames(LOCAL_CRATE)
+        .temp_path(OutputType::Object, Some(&name));
+    if let Err(err) = write_module(&tmp_file, module, debug, unwind_context) {
+        tcx.sess.fatal(&err);
+    }
+
+    make_compiled_module(tcx, name, kind, tmp_file)
+}
+
+/// Write the object file for `module` to `tmp_file`. This doesn't need the `TyCtxt`, so it can run
+/// on a codegen worker thread.
+fn write_module(
+    tmp_file: &Path,
+    module: ObjectModule,
+    debug: Option<DebugContext>,
+    unwind_context: UnwindContext,
+) -> Result<(), String> {
     let mut product = module.finish();
 
     if let Some(mut debug) = debug {
@@ -49,17 +69,16 @@ fn emit_module(
 
     unwind_context.emit(&mut product);
 
-    let product = map_product(product);
-
-    let tmp_file = tcx
-        .output_filenames(LOCAL_CRATE)
-        .temp_path(OutputType::Object, Some(&name));
     let obj = product.object.write().unwrap();
-    if let Err(err) = std::fs::write(&tmp_file, obj) {
-        tcx.sess
-            .fatal(&format!("error writing object file: {}", err));
-    }
+    std::fs::write(tmp_file, obj).map_err(|err| format!("error writing object file: {}", err))
+}
 
+fn make_compiled_module(
+    tcx: TyCtxt<'_>,
+    name: String,
+    kind: ModuleKind,
+    tmp_file: PathBuf,
+) -> ModuleCodegenResult {
     let work_product = if std::env::var("CG_CLIF_INCR_CACHE_DISABLED").is_ok() {
         None
     } else {
@@ -117,34 +136,156 @@ fn reuse_workproduct_for_cgu(
     }
 }
 
-fn module_codegen(tcx: TyCtxt<'_>, cgu_name: rustc_span::Symbol) -> ModuleCodegenResult {
-    let cgu = tcx.codegen_unit(cgu_name);
-    let mono_items = cgu.items_in_deterministic_order(tcx);
+/// A codegen unit whose functions have been lowered to clif ir on the rustc thread. Compiling them
+/// and writing the object file happens on a codegen worker thread in [`OngoingModuleCodegen::run`].
+struct OngoingModuleCodegen {
+    name: String,
+    tmp_file: PathBuf,
+    module: ObjectModule,
+    functions: Vec<CodegenedFunction>,
+    debug: Option<DebugContext>,
+    unwind_context: UnwindContext,
+    global_asm: Option<GlobalAsm>,
+    cached_functions: Option<CachedFunctions>,
+    /// Everything cg_clif doesn't support which the codegen unit uses, if recorded. Such a codegen
+    /// unit is compiled using the LLVM backend instead.
+    unsupported: Vec<UnsupportedSite>,
+    clif_output: Option<Arc<OutputFilenames>>,
+    /// See [`cgu_text_file`].
+    clif_file: Option<PathBuf>,
+    asm_file: Option<PathBuf>,
+    prof: SelfProfilerRef,
+}
 
-    let mut module = new_module(tcx, cgu_name.as_str().to_string());
+/// The functions of a codegen unit which are stored in the [`FnCache`]. Their object files are
+/// linked into the object file of the codegen unit.
+struct CachedFunctions {
+    /// The object files of functions which have been cached already.
+    objects: Vec<PathBuf>,
+    /// Functions which still have to be compiled and written to the cache.
+    new: Vec<NewCachedFunction>,
+    linker: PathBuf,
+}
 
-    // Initialize the global atomic mutex using a constructor for proc-macros.
-    // FIXME implement atomic instructions in Cranelift.
-    let mut init_atomics_mutex_from_constructor = None;
-    if tcx
-        .sess
-        .crate_types()
-        .contains(&rustc_session::config::CrateType::ProcMacro)
-    {
-        if mono_items.iter().any(|(mono_item, _)| match mono_item {
-            rustc_middle::mir::mono::MonoItem::Static(def_id) => tcx
-                .symbol_name(Instance::mono(tcx, *def_id))
-                .name
-                .contains("__rustc_proc_macro_decls_"),
-            _ => false,
-        }) {
-            init_atomics_mutex_from_constructor =
-                Some(crate::atomic_shim::init_global_lock_constructor(
+struct NewCachedFunction {
+    module: ObjectModule,
+    function: CodegenedFunction,
+    debug: Option<DebugContext>,
+    unwind_context: UnwindContext,
+    object: PathBuf,
+}
+
+impl<HCX> HashStable<HCX> for OngoingModuleCodegen {
+    fn hash_stable(&self, _: &mut HCX, _: &mut StableHasher) {
+        // do nothing
+    }
+}
+
+impl OngoingModuleCodegen {
+    fn run(self) -> Result<(String, PathBuf), String> {
+        let OngoingModuleCodegen {
+            name,
+            tmp_file,
+            mut module,
+            functions,
+            mut debug,
+            mut unwind_context,
+            global_asm,
+            cached_functions,
+            unsupported: _,
+            clif_output,
+            clif_file,
+            asm_file,
+            prof,
+        } = self;
+
+        let mut cached_context = Context::new();
+        let mut text_outputs = CguTextOutputs {
+            clif: clif_file
+                .as_ref()
+                .map(|_| crate::pretty_clif::clif_header(module.isa())),
+            asm: asm_file.as_ref().map(|_| String::new()),
+        };
+
+        {
+            let _timer = prof.generic_activity("compile functions");
+            for codegened_func in functions {
+                crate::base::compile_fn(
                     &mut module,
-                    &format!("{}_init_atomics_mutex", cgu_name.as_str()),
-                ));
+                    &mut cached_context,
+                    debug.as_mut(),
+                    &mut unwind_context,
+                    clif_output.as_deref(),
+                    &mut text_outputs,
+                    codegened_func,
+                );
+            }
+        }
+
+        for (file, text) in vec![(clif_file, text_outputs.clif), (asm_file, text_outputs.asm)] {
+            if let (Some(file), Some(text)) = (file, text) {
+                std::fs::write(&file, text)
+                    .map_err(|err| format!("error writing `{}`: {}", file.display(), err))?;
+            }
+        }
+
+        {
+            let _timer = prof.generic_activity("write object file");
+            write_module(&tmp_file, module, debug, unwind_context)?;
+        }
+
+        if let Some(global_asm) = global_asm {
+            let _timer = prof.generic_activity("assemble global asm");
+            global_asm.assemble(&tmp_file)?;
         }
+
+        if let Some(CachedFunctions {
+            mut objects,
+            new,
+            linker,
+        }) = cached_functions
+        {
+            let _timer = prof.generic_activity("compile and link cached functions");
+            for NewCachedFunction {
+                mut module,
+                function,
+                mut debug,
+                mut unwind_context,
+                object,
+            } in new
+            {
+                crate::base::compile_fn(
+                    &mut module,
+                    &mut cached_context,
+                    debug.as_mut(),
+                    &mut unwind_context,
+                    clif_output.as_deref(),
+                    &mut CguTextOutputs::default(),
+                    function,
+                );
+                write_module(&object, module, debug, unwind_context)?;
+                objects.push(object);
+            }
+            super::global_asm::link_objects_into(&linker, &tmp_file, &objects)?;
+        }
+
+        Ok((name, tmp_file))
     }
+}
+
+fn module_codegen(
+    tcx: TyCtxt<'_>,
+    (cgu_name, config, bisect): (rustc_span::Symbol, crate::BackendConfig, Option<&Bisect>),
+) -> OngoingModuleCodegen {
+    let cgu = tcx.codegen_unit(cgu_name);
+    let mut mono_items = cgu.items_in_deterministic_order(tcx);
+    if let Some(bisect) = bisect {
+        mono_items.retain(|&(mono_item, (linkage, _))| {
+            !bisect.is_compiled_by_llvm(tcx, mono_item, linkage)
+        });
+    }
+
+    let module = new_module(tcx, cgu_name.as_str().to_string());
 
     let mut cx = crate::CodegenCx::new(
         tcx,
@@ -152,58 +293,240 @@ fn module_codegen(tcx: TyCtxt<'_>, cgu_name: rustc_span::Symbol) -> ModuleCodege
         tcx.sess.opts.debuginfo != DebugInfo::None,
         true,
     );
+    if config.llvm_fallback || config.unsupported_report {
+        cx.unsupported = Some(Vec::new());
+    }
+    cx.trap_metadata = crate::trap_metadata::is_supported(tcx);
+    let mut functions = Vec::with_capacity(mono_items.len());
+    // Cached functions are not codegened, so they would be missing from the report, the clif ir
+    // and the assembly. When bisecting, the cache would contain functions of a different range.
+    let fn_cache = if config.unsupported_report || writes_text_outputs(tcx) || bisect.is_some() {
+        None
+    } else {
+        FnCache::new(tcx, &config)
+    };
+    let cached_functions = fn_cache.map(|fn_cache| {
+        codegen_cached_functions(
+            &mut cx,
+            &fn_cache,
+            &cgu_name.as_str(),
+            &mut mono_items,
+            &mut functions,
+        )
+    });
     super::predefine_mono_items(&mut cx, &mono_items);
     for (mono_item, (linkage, visibility)) in mono_items {
         let linkage = crate::linkage::get_clif_linkage(mono_item, linkage, visibility);
         match mono_item {
             MonoItem::Fn(inst) => {
                 cx.tcx.sess.time("codegen fn", || {
-                    crate::base::codegen_fn(&mut cx, inst, linkage)
+                    functions.push(crate::base::codegen_fn(&mut cx, inst, linkage));
                 });
             }
             MonoItem::Static(def_id) => {
                 crate::constant::codegen_static(&mut cx.constants_cx, def_id)
             }
             MonoItem::GlobalAsm(hir_id) => {
-                let item = cx.tcx.hir().expect_item(hir_id);
-                if let rustc_hir::ItemKind::GlobalAsm(rustc_hir::GlobalAsm { asm }) = item.kind {
-                    cx.global_asm.push_str(&*asm.as_str());
-                    cx.global_asm.push_str("\n\n");
-                } else {
-                    bug!("Expected GlobalAsm found {:?}", item);
-                }
+                super::global_asm::codegen_global_asm_item(tcx, &mut cx.global_asm, hir_id);
             }
         }
     }
+    let unsupported = cx.unsupported.take().unwrap_or_default();
     let (mut module, global_asm, debug, mut unwind_context) =
         tcx.sess.time("finalize CodegenCx", || cx.finalize());
-    crate::main_shim::maybe_create_entry_wrapper(tcx, &mut module, &mut unwind_context, false);
+    // When bisecting, the entry wrapper is part of the object files of the LLVM backend.
+    if bisect.is_none() {
+        crate::main_shim::maybe_create_entry_wrapper(
+            tcx,
+            &mut module,
+            &mut unwind_context,
+            crate::trap_metadata::is_supported(tcx),
+        );
+    }
 
-    let codegen_result = emit_module(
-        tcx,
-        cgu.name().as_str().to_string(),
-        ModuleKind::Regular,
+    let name = cgu.name().as_str().to_string();
+    let tmp_file = tcx
+        .output_filenames(LOCAL_CRATE)
+        .temp_path(OutputType::Object, Some(&name));
+    let clif_file = match bisect {
+        Some(bisect) => Some(bisect.clif_file(&name)),
+        None => cgu_text_file(tcx, OutputType::LlvmAssembly, &name),
+    };
+    let asm_file = cgu_text_file(tcx, OutputType::Assembly, &name);
+
+    OngoingModuleCodegen {
+        name,
+        tmp_file,
         module,
+        functions,
         debug,
         unwind_context,
-        |mut product| {
-            if let Some(func_id) = init_atomics_mutex_from_constructor {
-                product.add_constructor(func_id);
+        global_asm: GlobalAsm::new(tcx, global_asm),
+        cached_functions,
+        unsupported,
+        clif_output: if crate::pretty_clif::should_write_ir(tcx) {
+            Some(tcx.output_filenames(LOCAL_CRATE).clone())
+        } else {
+            None
+        },
+        clif_file,
+        asm_file,
+        prof: tcx.prof.clone(),
+    }
+}
+
+/// Codegen every function of a codegen unit which isn't in the function cache yet into its own
+/// module and remove all functions from `mono_items`. Functions which can't be cached are codegened
+/// into the module of the codegen unit instead.
+fn codegen_cached_functions<'tcx>(
+    cx: &mut crate::CodegenCx<'tcx, ObjectModule>,
+    fn_cache: &FnCache,
+    cgu_name: &str,
+    mono_items: &mut Vec<(MonoItem<'tcx>, (RLinkage, Visibility))>,
+    functions: &mut Vec<CodegenedFunction>,
+) -> CachedFunctions {
+    let tcx = cx.tcx;
+
+    // Statics stay in the module of the codegen unit. Cached functions can't reference those which
+    // are local to it, which only happens when requested using `#[linkage]`.
+    let local_statics = mono_items
+        .iter()
+        .filter_map(|&(mono_item, _)| match mono_item {
+            MonoItem::Static(def_id)
+                if crate::linkage::get_static_linkage(tcx, def_id) == Linkage::Local =>
+            {
+                Some(
+                    tcx.symbol_name(Instance::mono(tcx, def_id))
+                        .name
+                        .to_string(),
+                )
             }
+            _ => None,
+        })
+        .collect::<FxHashSet<_>>();
 
-            product
-        },
-    );
+    let mut cached_functions = CachedFunctions {
+        objects: Vec::new(),
+        new: Vec::new(),
+        linker: crate::toolchain::get_toolchain_binary(tcx.sess, "ld"),
+    };
+    let mut keys = Vec::new();
+    for &(mono_item, (linkage, visibility)) in &*mono_items {
+        let inst = match mono_item {
+            MonoItem::Fn(inst) => inst,
+            MonoItem::Static(_) | MonoItem::GlobalAsm(_) => continue,
+        };
+        let linkage = super::fn_cache::fn_cache_linkage(
+            tcx,
+            mono_item,
+            crate::linkage::get_clif_linkage(mono_item, linkage, visibility),
+        );
+
+        let (key, object) = fn_cache.object_file(tcx, inst, linkage);
+        if object.exists() {
+            cached_functions.objects.push(object);
+            keys.push(key);
+            continue;
+        }
+
+        let mut fn_cx = crate::CodegenCx::new(
+            tcx,
+            new_module(tcx, tcx.symbol_name(inst).name.to_string()),
+            tcx.sess.opts.debuginfo != DebugInfo::None,
+            true,
+        );
+        if cx.unsupported.is_some() {
+            fn_cx.unsupported = Some(Vec::new());
+        }
+        fn_cx.trap_metadata = cx.trap_metadata;
+        let function = tcx.sess.time("codegen fn", || {
+            crate::base::codegen_fn(&mut fn_cx, inst, linkage)
+        });
 
-    codegen_global_asm(tcx, &cgu.name().as_str(), &global_asm);
+        // The codegen unit will be compiled using the LLVM backend, so don't cache the traps
+        // replacing the unsupported constructs.
+        match (&mut cx.unsupported, fn_cx.unsupported.take()) {
+            (Some(unsupported), Some(fn_unsupported)) if !fn_unsupported.is_empty() => {
+                unsupported.extend(fn_unsupported);
+                continue;
+            }
+            _ => {}
+        }
 
-    codegen_result
+        let (module, global_asm, debug, unwind_context) = fn_cx.finalize();
+
+        // `asm!` is assembled together with the rest of the codegen unit.
+        // FIXME cache functions using `asm!` too
+        if !global_asm.is_empty()
+            || module
+                .declarations()
+                .get_data_objects()
+                .any(|(_data_id, decl)| {
+                    decl.linkage == Linkage::Import && local_statics.contains(&decl.name)
+                })
+        {
+            tcx.sess.time("codegen fn", || {
+                functions.push(crate::base::codegen_fn(cx, inst, linkage));
+            });
+            continue;
+        }
+
+        keys.push(key);
+        cached_functions.new.push(NewCachedFunction {
+            module,
+            function,
+            debug,
+            unwind_context,
+            object,
+        });
+    }
+
+    mono_items.retain(|(mono_item, _)| !matches!(mono_item, MonoItem::Fn(_)));
+    fn_cache.write_manifest(tcx.sess, cgu_name, &keys);
+
+    cached_functions
+}
+
+/// How the object file of a codegen unit is produced.
+enum CguModule {
+    Reused(CompiledModule),
+    /// Compiled by a codegen worker.
+    Cranelift,
+    /// Compiled using the LLVM backend, as cg_clif doesn't support everything it uses.
+    Llvm,
+}
+
+fn note_llvm_fallback(
+    tcx: TyCtxt<'_>,
+    cgu_name: rustc_span::Symbol,
+    unsupported: &[UnsupportedSite],
+) {
+    const MAX_SITES: usize = 3;
+
+    let mut diag = tcx.sess.struct_note_without_error(&format!(
+        "compiling codegen unit `{}` using the LLVM backend",
+        cgu_name
+    ));
+    for site in unsupported.iter().take(MAX_SITES) {
+        diag.span_note(
+            site.span,
+            &format!("{} in `{}`", site.reason, site.instance),
+        );
+    }
+    if unsupported.len() > MAX_SITES {
+        diag.note(&format!(
+            "and {} more unsupported constructs",
+            unsupported.len() - MAX_SITES
+        ));
+    }
+    diag.emit();
 }
 
 pub(super) fn run_aot(
     tcx: TyCtxt<'_>,
     metadata: EncodedMetadata,
     need_metadata_module: bool,
+    config: crate::BackendConfig,
 ) -> Box<(CodegenResults, FxHashMap<WorkProductId, WorkProduct>)> {
     let mut work_products = FxHashMap::default();
 
@@ -221,8 +544,17 @@ pub(super) fn run_aot(
         }
     }
 
+    let bisect = Bisect::new(tcx, cgus);
+
+    // Lowering to clif ir needs the `TyCtxt` and as such happens on the rustc thread. Compiling the
+    // clif ir and writing the object files is done in parallel by the worker pool.
+    let mut worker_pool = WorkerPool::new(tcx.sess);
+    let mut llvm_cgus = Vec::new();
+    let mut unsupported = Vec::new();
+
     let modules = super::time(tcx, "codegen mono items", || {
-        cgus.iter()
+        let modules = cgus
+            .iter()
             .map(|cgu| {
                 let cgu_reuse = determine_cgu_reuse(tcx, cgu);
                 tcx.sess
@@ -231,33 +563,101 @@ pub(super) fn run_aot(
 
                 match cgu_reuse {
                     _ if std::env::var("CG_CLIF_INCR_CACHE_DISABLED").is_ok() => {}
+                    // Reused codegen units would be missing from the report and the assembly. When
+                    // bisecting, they may contain functions of a different range.
+                    _ if config.unsupported_report
+                        || writes_text_outputs(tcx)
+                        || bisect.is_some() => {}
                     CguReuse::No => {}
                     CguReuse::PreLto => {
-                        return reuse_workproduct_for_cgu(tcx, &*cgu, &mut work_products);
+                        return CguModule::Reused(reuse_workproduct_for_cgu(
+                            tcx,
+                            &*cgu,
+                            &mut work_products,
+                        ));
                     }
                     CguReuse::PostLto => unreachable!(),
                 }
 
                 let dep_node = cgu.codegen_dep_node(tcx);
-                let (ModuleCodegenResult(module, work_product), _) = tcx.dep_graph.with_task(
+                let (mut ongoing_module_codegen, _) = tcx.dep_graph.with_task(
                     dep_node,
                     tcx,
-                    cgu.name(),
+                    (cgu.name(), config, bisect.as_ref()),
                     module_codegen,
                     rustc_middle::dep_graph::hash_result,
                 );
 
-                if let Some((id, product)) = work_product {
-                    work_products.insert(id, product);
+                if config.llvm_fallback
+                    && bisect.is_none()
+                    && !ongoing_module_codegen.unsupported.is_empty()
+                {
+                    note_llvm_fallback(tcx, cgu.name(), &ongoing_module_codegen.unsupported);
+                    llvm_cgus.push(cgu.name());
+                    return CguModule::Llvm;
                 }
+                unsupported.append(&mut ongoing_module_codegen.unsupported);
+
+                worker_pool.spawn(move || ongoing_module_codegen.run());
 
-                module
+                CguModule::Cranelift
             })
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>();
+
+        let mut codegened_modules = worker_pool.join().into_iter();
+
+        #[cfg(feature = "llvm_fallback")]
+        let llvm_modules =
+            super::llvm_fallback::codegen_cgus(tcx, &metadata, &llvm_cgus, &mut work_products);
+        #[cfg(not(feature = "llvm_fallback"))]
+        let llvm_modules: Vec<CompiledModule> = Vec::new();
+        let mut llvm_modules = llvm_modules.into_iter();
+
+        let mut modules = modules
+            .into_iter()
+            .map(|module| match module {
+                CguModule::Reused(module) => module,
+                CguModule::Cranelift => {
+                    let (name, tmp_file) = codegened_modules
+                        .next()
+                        .unwrap()
+                        .unwrap_or_else(|err| tcx.sess.fatal(&err));
+                    let ModuleCodegenResult(module, work_product) =
+                        make_compiled_module(tcx, name, ModuleKind::Regular, tmp_file);
+
+                    if let Some((id, product)) = work_product {
+                        work_products.insert(id, product);
+                    }
+
+                    module
+                }
+                CguModule::Llvm => llvm_modules.next().unwrap(),
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(bisect) = &bisect {
+            bisect.codegen_llvm_modules(tcx, &metadata, cgus, &mut modules, &mut work_products);
+        }
+
+        modules
     });
 
+    if config.unsupported_report {
+        super::unsupported_report::write_report(tcx, &unsupported);
+    }
+
     tcx.sess.abort_if_errors();
 
+    for &output_type in &[OutputType::LlvmAssembly, OutputType::Assembly] {
+        if tcx.sess.opts.output_types.contains_key(&output_type) {
+            copy_text_output_to_output(tcx, cgus, output_type);
+        }
+    }
+
+    if let Some(fn_cache) = FnCache::new(tcx, &config) {
+        fn_cache.remove_unused(cgus);
+    }
+
     let mut allocator_module = new_module(tcx, "allocator_shim".to_string());
     let mut allocator_unwind_context = UnwindContext::new(tcx, allocator_module.isa(), true);
     let created_alloc_shim =
@@ -271,7 +671,6 @@ pub(super) fn run_aot(
             allocator_module,
             None,
             allocator_unwind_context,
-            |product| product,
         );
         if let Some((id, product)) = work_product {
             work_products.insert(id, product);
@@ -334,105 +733,62 @@ pub(super) fn run_aot(
     ))
 }
 
-fn codegen_global_asm(tcx: TyCtxt<'_>, cgu_name: &str, global_asm: &str) {
-    use std::io::Write;
-    use std::process::{Command, Stdio};
+/// Whether the clif ir or the assembly of all codegen units is written. Both are only available
+/// for functions compiled in the current session.
+fn writes_text_outputs(tcx: TyCtxt<'_>) -> bool {
+    crate::pretty_clif::should_emit_clif(tcx) || crate::disasm::should_write_asm(tcx)
+}
 
-    if global_asm.is_empty() {
-        return;
+/// The file the clif ir for `--emit=llvm-ir` or the assembly for `--emit=asm` of a codegen unit is
+/// written to, if requested.
+fn cgu_text_file(tcx: TyCtxt<'_>, output_type: OutputType, cgu_name: &str) -> Option<PathBuf> {
+    if !tcx.sess.opts.output_types.contains_key(&output_type) {
+        return None;
     }
 
-    if cfg!(not(feature = "inline_asm"))
-        || tcx.sess.target.is_like_osx
-        || tcx.sess.target.is_like_windows
-    {
-        if global_asm.contains("__rust_probestack") {
-            return;
-        }
-
-        // FIXME fix linker error on macOS
-        if cfg!(not(feature = "inline_asm")) {
-            tcx.sess.fatal(
-                "asm! and global_asm! support is disabled while compiling rustc_codegen_cranelift",
-            );
-        } else {
-            tcx.sess
-                .fatal("asm! and global_asm! are not yet supported on macOS and Windows");
-        }
-    }
-
-    let assembler = crate::toolchain::get_toolchain_binary(tcx.sess, "as");
-    let linker = crate::toolchain::get_toolchain_binary(tcx.sess, "ld");
-
-    // Remove all LLVM style comments
-    let global_asm = global_asm
-        .lines()
-        .map(|line| {
-            if let Some(index) = line.find("//") {
-                &line[0..index]
-            } else {
-                line
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    let output_object_file = tcx
-        .output_filenames(LOCAL_CRATE)
-        .temp_path(OutputType::Object, Some(cgu_name));
-
-    // Assemble `global_asm`
-    let global_asm_object_file = add_file_stem_postfix(output_object_file.clone(), ".asm");
-    let mut child = Command::new(assembler)
-        .arg("-o")
-        .arg(&global_asm_object_file)
-        .stdin(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn `as`.");
-    child
-        .stdin
-        .take()
-        .unwrap()
-        .write_all(global_asm.as_bytes())
-        .unwrap();
-    let status = child.wait().expect("Failed to wait for `as`.");
-    if !status.success() {
-        tcx.sess
-            .fatal(&format!("Failed to assemble `{}`", global_asm));
-    }
+    Some(
+        tcx.output_filenames(LOCAL_CRATE)
+            .temp_path_ext(text_output_extension(output_type), Some(cgu_name)),
+    )
+}
 
-    // Link the global asm and main object file together
-    let main_object_file = add_file_stem_postfix(output_object_file.clone(), ".main");
-    std::fs::rename(&output_object_file, &main_object_file).unwrap();
-    let status = Command::new(linker)
-        .arg("-r") // Create a new object file
-        .arg("-o")
-        .arg(output_object_file)
-        .arg(&main_object_file)
-        .arg(&global_asm_object_file)
-        .status()
-        .unwrap();
-    if !status.success() {
-        tcx.sess.fatal(&format!(
-            "Failed to link `{}` and `{}` together",
-            main_object_file.display(),
-            global_asm_object_file.display(),
-        ));
+fn text_output_extension(output_type: OutputType) -> &'static str {
+    if output_type == OutputType::LlvmAssembly {
+        "clif"
+    } else {
+        output_type.extension()
     }
-
-    std::fs::remove_file(global_asm_object_file).unwrap();
-    std::fs::remove_file(main_object_file).unwrap();
 }
 
-fn add_file_stem_postfix(mut path: PathBuf, postfix: &str) -> PathBuf {
-    let mut new_filename = path.file_stem().unwrap().to_owned();
-    new_filename.push(postfix);
-    if let Some(extension) = path.extension() {
-        new_filename.push(".");
-        new_filename.push(extension);
+/// Like for the LLVM backend, the clif ir or assembly is only written to the requested output path
+/// when there is a single codegen unit. Otherwise the file of every codegen unit is left next to
+/// it.
+fn copy_text_output_to_output(tcx: TyCtxt<'_>, cgus: &[CodegenUnit<'_>], output_type: OutputType) {
+    let output_filenames = tcx.output_filenames(LOCAL_CRATE);
+    let explicit_path = matches!(output_filenames.outputs.get(&output_type), Some(Some(_)))
+        || output_filenames.single_output_file.is_some();
+    match cgus {
+        [] => {}
+        [cgu] => {
+            let cgu_file = cgu_text_file(tcx, output_type, &cgu.name().as_str()).unwrap();
+            let mut path = output_filenames.path(output_type);
+            if !explicit_path {
+                path.set_extension(text_output_extension(output_type));
+            }
+            if let Err(err) = std::fs::rename(&cgu_file, &path) {
+                tcx.sess
+                    .err(&format!("error writing `{}`: {}", path.display(), err));
+            }
+        }
+        _ => {
+            if explicit_path {
+                tcx.sess.warn(&format!(
+                    "ignoring emit path because multiple .{} files were produced",
+                    text_output_extension(output_type)
+                ));
+            }
+        }
     }
-    path.set_file_name(new_filename);
-    path
 }
 
 // Adapted from https://github.com/rust-lang/rust/blob/303d8aff6092709edd4dbd35b1c88e9aa40bf6d8/src/librustc_codegen_ssa/base.rs#L922-L953
@@ -0,0 +1,224 @@
+//! Finds the function cg_clif miscompiles by compiling some functions using cg_clif and all other
+//! functions using the LLVM backend, enabled using `CG_CLIF_BISECT_CRATE`. `scripts/bisect.sh`
+//! binary searches for the function which makes a test fail this way.
+//!
+//! Every function which can be called from other codegen units gets an index, which is written to
+//! `functions.txt` in `CG_CLIF_BISECT_DIR` together with its symbol name and instance. Only the
+//! functions in `CG_CLIF_BISECT_RANGE` are compiled by cg_clif. Their clif ir is written to
+//! `<cgu>.clif` in the same directory. LLVM compiles every codegen unit as a whole, after which the
+//! functions compiled by cg_clif are turned into weak symbols in its object files using `objcopy`,
+//! so the linker picks the cg_clif versions instead.
+//!
+//! Functions local to a codegen unit are compiled by both backends and used by the functions
+//! compiled by the same backend. Calls LLVM inlined always use the LLVM version of the callee.
+
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::Command;
+
+use rustc_codegen_ssa::CompiledModule;
+use rustc_middle::dep_graph::{WorkProduct, WorkProductId};
+use rustc_middle::middle::cstore::EncodedMetadata;
+use rustc_middle::mir::mono::{CodegenUnit, Linkage as RLinkage, MonoItem};
+use rustc_middle::ty::print::with_no_trimmed_paths;
+
+use crate::prelude::*;
+
+/// Whether functions of the current crate are bisected.
+pub(crate) fn is_bisected_crate(tcx: TyCtxt<'_>) -> bool {
+    std::env::var("CG_CLIF_BISECT_CRATE").map_or(false, |crate_name| {
+        crate_name == &*tcx.crate_name(LOCAL_CRATE).as_str()
+    })
+}
+
+/// Whether the body of `instance` is replaced with a trap, because it matches
+/// `CG_CLIF_INJECT_TRAP`. This is used to test `scripts/bisect.sh`.
+pub(crate) fn should_inject_trap<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> bool {
+    std::env::var("CG_CLIF_INJECT_TRAP").map_or(false, |filter| {
+        crate::pretty_clif::fn_matches_filter(tcx, instance, &filter)
+    })
+}
+
+#[derive(Debug)]
+pub(super) struct Bisect {
+    dir: PathBuf,
+    /// The index of every function which can be bisected by symbol name.
+    functions: FxHashMap<String, usize>,
+    /// The indices of the functions compiled by cg_clif.
+    range: Range<usize>,
+}
+
+impl Bisect {
+    /// Index all functions of `cgus` and write them to `functions.txt`, if bisecting the current
+    /// crate.
+    pub(super) fn new(tcx: TyCtxt<'_>, cgus: &[CodegenUnit<'_>]) -> Option<Self> {
+        if !is_bisected_crate(tcx) {
+            return None;
+        }
+        if !cfg!(feature = "llvm_fallback") {
+            tcx.sess
+                .fatal("bisecting requires cg_clif to be built with the `llvm_fallback` feature");
+        }
+        // Otherwise rustc makes functions which are only used in their own codegen unit local to
+        // it, so they can't be called from the object file of the other backend.
+        if !tcx.sess.link_dead_code() {
+            tcx.sess.fatal("bisecting requires `-Clink-dead-code`");
+        }
+        let dir = PathBuf::from(
+            std::env::var_os("CG_CLIF_BISECT_DIR")
+                .unwrap_or_else(|| tcx.sess.fatal("`CG_CLIF_BISECT_DIR` is not set")),
+        );
+
+        let mut functions = cgus
+            .iter()
+            .flat_map(|cgu| cgu.items())
+            .filter_map(|(&mono_item, &(linkage, _))| match mono_item {
+                MonoItem::Fn(inst) if linkage != RLinkage::Internal => {
+                    Some((tcx.symbol_name(inst).name.to_string(), inst))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        functions.dedup_by(|(a, _), (b, _)| a == b);
+
+        let list = functions
+            .iter()
+            .enumerate()
+            .map(|(index, (symbol_name, inst))| {
+                let inst = with_no_trimmed_paths(|| inst.to_string());
+                format!("{}\t{}\t{}\n", index, symbol_name, inst)
+            })
+            .collect::<String>();
+        let list_file = dir.join("functions.txt");
+        if let Err(err) =
+            std::fs::create_dir_all(&dir).and_then(|()| std::fs::write(&list_file, list))
+        {
+            tcx.sess
+                .fatal(&format!("error writing `{}`: {}", list_file.display(), err));
+        }
+
+        let range = match std::env::var("CG_CLIF_BISECT_RANGE") {
+            Ok(range) => parse_range(&range).unwrap_or_else(|| {
+                tcx.sess.fatal(&format!(
+                    "`CG_CLIF_BISECT_RANGE` must have the form `<start>..<end>`, not `{}`",
+                    range
+                ))
+            }),
+            Err(_) => 0..functions.len(),
+        };
+
+        Some(Bisect {
+            dir,
+            functions: functions
+                .into_iter()
+                .enumerate()
+                .map(|(index, (symbol_name, _))| (symbol_name, index))
+                .collect(),
+            range,
+        })
+    }
+
+    /// Whether `mono_item` is only compiled by LLVM.
+    pub(super) fn is_compiled_by_llvm<'tcx>(
+        &self,
+        tcx: TyCtxt<'tcx>,
+        mono_item: MonoItem<'tcx>,
+        linkage: RLinkage,
+    ) -> bool {
+        match mono_item {
+            MonoItem::Fn(_) if linkage == RLinkage::Internal => false,
+            MonoItem::Fn(inst) => !self
+                .functions
+                .get(tcx.symbol_name(inst).name)
+                .map_or(false, |index| self.range.contains(index)),
+            MonoItem::Static(_) | MonoItem::GlobalAsm(_) => true,
+        }
+    }
+
+    /// The file the clif ir of the functions of `cgu_name` compiled by cg_clif is written to.
+    pub(super) fn clif_file(&self, cgu_name: &str) -> PathBuf {
+        self.dir.join(format!("{}.clif", cgu_name))
+    }
+
+    /// Compile all of `cgus` using LLVM and add them to `modules`, which contains the modules
+    /// compiled by cg_clif.
+    #[cfg_attr(not(feature = "llvm_fallback"), allow(unused_variables))]
+    pub(super) fn codegen_llvm_modules(
+        &self,
+        tcx: TyCtxt<'_>,
+        metadata: &EncodedMetadata,
+        cgus: &[CodegenUnit<'_>],
+        modules: &mut Vec<CompiledModule>,
+        work_products: &mut FxHashMap<WorkProductId, WorkProduct>,
+    ) {
+        // LLVM writes its object files to the same paths as cg_clif.
+        for object in modules
+            .iter_mut()
+            .filter_map(|module| module.object.as_mut())
+        {
+            let clif_object = object.with_extension("clif.o");
+            if let Err(err) = std::fs::rename(&*object, &clif_object) {
+                tcx.sess
+                    .fatal(&format!("error renaming `{}`: {}", object.display(), err));
+            }
+            *object = clif_object;
+        }
+
+        let cgu_names = cgus.iter().map(|cgu| cgu.name()).collect::<Vec<_>>();
+        #[cfg(feature = "llvm_fallback")]
+        let llvm_modules =
+            super::llvm_fallback::codegen_cgus(tcx, metadata, &cgu_names, work_products);
+        #[cfg(not(feature = "llvm_fallback"))]
+        let llvm_modules: Vec<CompiledModule> = Vec::new();
+
+        let symbols_file = self.dir.join("cranelift_functions.txt");
+        let symbols = self
+            .functions
+            .iter()
+            .filter(|&(_, &index)| self.range.contains(&index))
+            .map(|(symbol_name, _)| format!("{}\n", symbol_name))
+            .collect::<String>();
+        if let Err(err) = std::fs::write(&symbols_file, symbols) {
+            tcx.sess.fatal(&format!(
+                "error writing `{}`: {}",
+                symbols_file.display(),
+                err
+            ));
+        }
+
+        let objcopy = crate::toolchain::get_toolchain_binary(tcx.sess, "objcopy");
+        for object in llvm_modules
+            .iter()
+            .filter_map(|module| module.object.as_ref())
+        {
+            match Command::new(&objcopy)
+                .arg("--weaken-symbols")
+                .arg(&symbols_file)
+                .arg(object)
+                .status()
+            {
+                Ok(status) if status.success() => {}
+                Ok(status) => tcx.sess.fatal(&format!(
+                    "`{}` failed with {} for `{}`",
+                    objcopy.display(),
+                    status,
+                    object.display()
+                )),
+                Err(err) => {
+                    tcx.sess
+                        .fatal(&format!("failed to run `{}`: {}", objcopy.display(), err))
+                }
+            }
+        }
+
+        modules.extend(llvm_modules);
+    }
+}
+
+fn parse_range(range: &str) -> Option<Range<usize>> {
+    let mut parts = range.splitn(2, "..");
+    let start = parts.next()?.parse().ok()?;
+    let end = parts.next()?.parse().ok()?;
+    Some(start..end)
+}
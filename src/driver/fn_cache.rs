@@ -0,0 +1,478 @@
+//! A cache for the object files of individual functions in AOT mode, enabled using
+//! `CG_CLIF_FN_CACHE=1` together with incremental compilation.
+//!
+//! Without it a codegen unit is either reused as a whole or all of its functions are compiled
+//! again. With it every function is compiled into its own object file, which is stored in the
+//! incremental session directory. When a codegen unit has to be compiled again, only functions
+//! whose [`hash_instance`] changed are compiled. The object files of all functions are linked into
+//! the object file of the codegen unit using `ld -r`.
+//!
+//! As functions are referenced across object files, functions which are local to their codegen unit
+//! get hidden or weak linkage instead, see [`fn_cache_linkage`].
+//!
+//! The function hashing is shared with the JIT code cache.
+
+use std::ffi::{CStr, OsStr};
+use std::hash::Hash;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use rustc_data_structures::fingerprint::Fingerprint;
+use rustc_data_structures::fx::FxHashSet;
+use rustc_data_structures::stable_hasher::{HashStable, StableHasher};
+use rustc_middle::ich::StableHashingContext;
+use rustc_middle::mir::interpret::{AllocId, Allocation, ConstValue, GlobalAlloc};
+use rustc_middle::mir::mono::{CodegenUnit, InstantiationMode, MonoItem};
+use rustc_middle::mir::tcx::PlaceTy;
+use rustc_middle::mir::visit::{PlaceContext, TyContext, Visitor};
+use rustc_middle::ty::adjustment::PointerCast;
+use rustc_session::Session;
+use rustc_span::DUMMY_SP;
+
+use cranelift_codegen::isa::TargetIsa;
+
+use crate::prelude::*;
+
+const FN_OBJECT_PREFIX: &str = "cgclif-fn-";
+const MANIFEST_PREFIX: &str = "cgclif-fns-";
+
+pub(super) struct FnCache {
+    dir: PathBuf,
+    triple: target_lexicon::Triple,
+    /// See [`codegen_config`].
+    config: String,
+}
+
+impl FnCache {
+    /// Open the function cache in the incremental session directory, if enabled and supported.
+    pub(super) fn new(tcx: TyCtxt<'_>, config: &crate::BackendConfig) -> Option<Self> {
+        if std::env::var("CG_CLIF_FN_CACHE").as_deref() != Ok("1")
+            || std::env::var("CG_CLIF_INCR_CACHE_DISABLED").is_ok()
+            || !tcx.dep_graph.is_fully_enabled()
+            // FIXME support linking the function objects together on macOS and Windows
+            || tcx.sess.target.is_like_osx
+            || tcx.sess.target.is_like_windows
+        {
+            return None;
+        }
+
+        let isa = crate::build_isa(tcx.sess);
+        Some(FnCache {
+            dir: tcx.sess.incr_comp_session_dir().to_path_buf(),
+            triple: isa.triple().clone(),
+            config: codegen_config(tcx, &*isa, config),
+        })
+    }
+
+    /// The cache key of `instance` defined with `linkage` and the path of its object file. The
+    /// object file only exists if `instance` has been cached already.
+    pub(super) fn object_file<'tcx>(
+        &self,
+        tcx: TyCtxt<'tcx>,
+        instance: Instance<'tcx>,
+        linkage: Linkage,
+    ) -> (String, PathBuf) {
+        let mut hasher = StableHasher::new();
+        self.config.hash(&mut hasher);
+        hash_instance(tcx, &self.triple, instance).hash(&mut hasher);
+        format!("{:?}", linkage).hash(&mut hasher);
+        let key = hasher.finish::<Fingerprint>().to_hex();
+
+        let path = self.dir.join(format!("{}{}.o", FN_OBJECT_PREFIX, key));
+        (key, path)
+    }
+
+    /// Record the cache keys of all functions of a codegen unit. This keeps their object files
+    /// alive in [`remove_unused`](Self::remove_unused) while the codegen unit is reused as a whole.
+    pub(super) fn write_manifest(&self, sess: &Session, cgu_name: &str, keys: &[String]) {
+        let path = self.manifest_path(cgu_name);
+        if let Err(err) = std::fs::write(&path, keys.join("\n")) {
+            sess.warn(&format!(
+                "Failed to write function cache manifest `{}`: {}",
+                path.display(),
+                err
+            ));
+        }
+    }
+
+    fn manifest_path(&self, cgu_name: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}{}.txt", MANIFEST_PREFIX, cgu_name))
+    }
+
+    /// Remove the object files of all functions and the manifests of all codegen units which are
+    /// not part of the current session. The incremental session directory is copied for every
+    /// session, so they would be kept forever otherwise.
+    pub(super) fn remove_unused(&self, cgus: &[CodegenUnit<'_>]) {
+        let mut used_files = FxHashSet::default();
+        for cgu in cgus {
+            let manifest_path = self.manifest_path(&cgu.name().as_str());
+            if let Ok(manifest) = std::fs::read_to_string(&manifest_path) {
+                for key in manifest.lines() {
+                    used_files.insert(self.dir.join(format!("{}{}.o", FN_OBJECT_PREFIX, key)));
+                }
+                used_files.insert(manifest_path);
+            }
+        }
+
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if (file_name.starts_with(FN_OBJECT_PREFIX) || file_name.starts_with(MANIFEST_PREFIX))
+                && !used_files.contains(&entry.path())
+            {
+                // Failing to remove an unused file only wastes some disk space.
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// The linkage of a function stored in the function cache. Functions which are local to their
+/// codegen unit are referenced from other object files, so they have to be visible to the linker.
+/// Local copies and functions which may be instantiated by other crates too get weak linkage, as
+/// they may be defined by multiple object files.
+pub(super) fn fn_cache_linkage(
+    tcx: TyCtxt<'_>,
+    mono_item: MonoItem<'_>,
+    linkage: Linkage,
+) -> Linkage {
+    if linkage != Linkage::Local {
+        return linkage;
+    }
+
+    match mono_item.instantiation_mode(tcx) {
+        InstantiationMode::GloballyShared {
+            may_conflict: false,
+        } => Linkage::Hidden,
+        InstantiationMode::GloballyShared { may_conflict: true } | InstantiationMode::LocalCopy => {
+            Linkage::Preemptible
+        }
+    }
+}
+
+/// Identifies everything outside of the crate which influences the generated code: the target isa,
+/// the opt level, the debuginfo level, the backend config, the `-Cllvm-args` options, whether trap
+/// sites are recorded and the cg_clif build.
+pub(super) fn codegen_config(
+    tcx: TyCtxt<'_>,
+    isa: &dyn TargetIsa,
+    config: &crate::BackendConfig,
+) -> String {
+    format!(
+        "{}\n{}\n{:?}\n{:?}\n{:?}\n{:?}\n{}\n{}",
+        isa.triple(),
+        isa.flags(),
+        tcx.sess.opts.optimize,
+        tcx.sess.opts.debuginfo,
+        config,
+        tcx.sess.opts.cg.llvm_args,
+        crate::trap_metadata::is_supported(tcx),
+        cg_clif_build_id(),
+    )
+}
+
+/// Hash the symbol name, the signature and the MIR of `instance` together with everything the
+/// generated code depends on which is not part of the MIR, see [`DependencyHasher`].
+pub(super) fn hash_instance<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    triple: &target_lexicon::Triple,
+    instance: Instance<'tcx>,
+) -> Fingerprint {
+    let mut hcx = tcx.create_stable_hashing_context();
+    let mut hasher = StableHasher::new();
+
+    tcx.symbol_name(instance).name.hash(&mut hasher);
+    get_function_sig(tcx, triple, instance)
+        .to_string()
+        .hash(&mut hasher);
+
+    let mir = tcx.instance_mir(instance.def);
+    mir.hash_stable(&mut hcx, &mut hasher);
+
+    let mut dependency_hasher = DependencyHasher {
+        tcx,
+        triple,
+        instance,
+        mir,
+        hcx,
+        hasher,
+        hashed_layouts: FxHashSet::default(),
+        hashed_allocs: FxHashSet::default(),
+    };
+    dependency_hasher.visit_body(mir);
+    dependency_hasher.hasher.finish()
+}
+
+/// Hashes everything the code generated for the MIR of a function depends on which is not part of
+/// the MIR itself:
+///
+/// * the layout of all types used by the MIR, including the types of all intermediate places, as
+///   they determine the offsets of fields accessed through references
+/// * the identity and signature of all referenced functions
+/// * the contents of all vtables created by unsizing
+/// * the drop glue of all dropped types
+/// * the values of all constants and the symbol names, mutability and layout of all referenced
+///   statics
+struct DependencyHasher<'a, 'tcx> {
+    tcx: TyCtxt<'tcx>,
+    triple: &'a target_lexicon::Triple,
+    instance: Instance<'tcx>,
+    mir: &'tcx Body<'tcx>,
+    hcx: StableHashingContext<'tcx>,
+    hasher: StableHasher,
+    hashed_layouts: FxHashSet<Ty<'tcx>>,
+    hashed_allocs: FxHashSet<AllocId>,
+}
+
+impl<'tcx> DependencyHasher<'_, 'tcx> {
+    fn monomorphize<T>(&self, value: T) -> T
+    where
+        T: TypeFoldable<'tcx> + Copy,
+    {
+        self.instance.subst_mir_and_normalize_erasing_regions(
+            self.tcx,
+            ParamEnv::reveal_all(),
+            value,
+        )
+    }
+
+    fn hash_layout(&mut self, ty: Ty<'tcx>) {
+        let ty = self.monomorphize(ty);
+        if !self.hashed_layouts.insert(ty) {
+            return;
+        }
+        let layout = self
+            .tcx
+            .layout_of(ParamEnv::reveal_all().and(ty))
+            .map(|layout| layout.layout);
+        format!("{:?}", layout).hash(&mut self.hasher);
+    }
+
+    /// Hash the identity, the symbol name and the signature of a called or referenced function.
+    fn hash_fn(&mut self, instance: Instance<'tcx>) {
+        let instance = instance.polymorphize(self.tcx);
+        instance.def.hash_stable(&mut self.hcx, &mut self.hasher);
+        instance.substs.hash_stable(&mut self.hcx, &mut self.hasher);
+        match instance.def {
+            InstanceDef::Intrinsic(_) => {}
+            InstanceDef::Virtual(_, _) => {
+                get_function_sig(self.tcx, self.triple, instance)
+                    .to_string()
+                    .hash(&mut self.hasher);
+            }
+            _ => {
+                self.tcx.symbol_name(instance).name.hash(&mut self.hasher);
+                get_function_sig(self.tcx, self.triple, instance)
+                    .to_string()
+                    .hash(&mut self.hasher);
+            }
+        }
+    }
+
+    /// Hash everything which determines how a static is referenced: its symbol name, whether it is
+    /// thread local, its linkage, whether it is mutable and the layout of its type.
+    fn hash_static(&mut self, def_id: DefId) {
+        let instance = Instance::mono(self.tcx, def_id);
+        self.tcx.symbol_name(instance).name.hash(&mut self.hasher);
+        self.tcx
+            .is_thread_local_static(def_id)
+            .hash(&mut self.hasher);
+        format!("{:?}", self.tcx.codegen_fn_attrs(def_id).linkage).hash(&mut self.hasher);
+        self.tcx.is_mutable_static(def_id).hash(&mut self.hasher);
+        let ty = instance.ty(self.tcx, ParamEnv::reveal_all());
+        ty.is_freeze(self.tcx.at(DUMMY_SP), ParamEnv::reveal_all())
+            .hash(&mut self.hasher);
+        self.hash_layout(ty);
+    }
+
+    fn hash_alloc(&mut self, alloc_id: AllocId) {
+        if !self.hashed_allocs.insert(alloc_id) {
+            return;
+        }
+        match self.tcx.get_global_alloc(alloc_id) {
+            Some(GlobalAlloc::Memory(alloc)) => self.hash_allocation(alloc),
+            Some(GlobalAlloc::Function(instance)) => self.hash_fn(instance),
+            Some(GlobalAlloc::Static(def_id)) => self.hash_static(def_id),
+            None => {}
+        }
+    }
+
+    fn hash_allocation(&mut self, alloc: &'tcx Allocation) {
+        alloc.hash_stable(&mut self.hcx, &mut self.hasher);
+        for &(_offset, (_tag, reloc)) in alloc.relocations().iter() {
+            self.hash_alloc(reloc);
+        }
+    }
+
+    fn hash_const_value(&mut self, value: ConstValue<'tcx>) {
+        value.hash_stable(&mut self.hcx, &mut self.hasher);
+        match value {
+            ConstValue::Scalar(mir::interpret::Scalar::Ptr(ptr)) => self.hash_alloc(ptr.alloc_id),
+            ConstValue::Scalar(mir::interpret::Scalar::Int(_)) => {}
+            ConstValue::Slice { data: alloc, .. } | ConstValue::ByRef { alloc, .. } => {
+                self.hash_allocation(alloc);
+            }
+        }
+    }
+
+    /// Hash the vtables created when unsizing a pointer of type `source` to `target`.
+    fn hash_unsize(&mut self, source: Ty<'tcx>, target: Ty<'tcx>) {
+        if let (Some(source), Some(target)) =
+            (source.builtin_deref(true), target.builtin_deref(true))
+        {
+            self.hash_vtable(source.ty, target.ty);
+            return;
+        }
+
+        // `CoerceUnsized` structs like `Rc<T>` or `Pin<&T>` unsize one of their type parameters.
+        if let (ty::Adt(_, source_substs), ty::Adt(_, target_substs)) =
+            (source.kind(), target.kind())
+        {
+            for (source, target) in source_substs.types().zip(target_substs.types()) {
+                if source == target {
+                    continue;
+                }
+                if source.builtin_deref(true).is_some() {
+                    self.hash_unsize(source, target);
+                } else {
+                    self.hash_vtable(source, target);
+                }
+            }
+        }
+    }
+
+    /// Hash the vtable for unsizing the pointee type `source` to `target`, if any.
+    fn hash_vtable(&mut self, source: Ty<'tcx>, target: Ty<'tcx>) {
+        let (source, target) = self.tcx.struct_lockstep_tails_erasing_lifetimes(
+            source,
+            target,
+            ParamEnv::reveal_all(),
+        );
+        let data = match target.kind() {
+            // Upcasting `dyn Trait` to `dyn Trait` reuses the existing vtable.
+            ty::Dynamic(data, _) if !source.is_trait() => data,
+            _ => return,
+        };
+
+        self.hash_layout(source);
+        self.hash_fn(Instance::resolve_drop_in_place(self.tcx, source));
+        if let Some(principal) = data.principal() {
+            let trait_ref = principal.with_self_ty(self.tcx, source);
+            for &method in self.tcx.vtable_methods(trait_ref) {
+                method.is_some().hash(&mut self.hasher);
+                if let Some((def_id, substs)) = method {
+                    let instance = Instance::resolve_for_vtable(
+                        self.tcx,
+                        ParamEnv::reveal_all(),
+                        def_id,
+                        substs,
+                    )
+                    .unwrap();
+                    self.hash_fn(instance);
+                }
+            }
+        }
+    }
+}
+
+impl<'tcx> Visitor<'tcx> for DependencyHasher<'_, 'tcx> {
+    fn visit_ty(&mut self, ty: Ty<'tcx>, _: TyContext) {
+        self.hash_layout(ty);
+    }
+
+    fn visit_place(&mut self, place: &Place<'tcx>, context: PlaceContext, location: Location) {
+        // The offsets of fields behind a reference depend on the layout of the referenced type.
+        let mut place_ty = PlaceTy::from_ty(self.mir.local_decls[place.local].ty);
+        for elem in place.projection {
+            place_ty = place_ty.projection_ty(self.tcx, elem);
+            self.hash_layout(place_ty.ty);
+        }
+        self.super_place(place, context, location);
+    }
+
+    fn visit_constant(&mut self, constant: &Constant<'tcx>, location: Location) {
+        let const_ = self.monomorphize(constant.literal);
+        self.hash_layout(const_.ty);
+        match const_.val {
+            ty::ConstKind::Value(value) => self.hash_const_value(value),
+            ty::ConstKind::Unevaluated(def, substs, promoted) => {
+                if let Ok(value) =
+                    self.tcx
+                        .const_eval_resolve(ParamEnv::reveal_all(), def, substs, promoted, None)
+                {
+                    self.hash_const_value(value);
+                }
+            }
+            _ => {}
+        }
+
+        // Called and referenced functions
+        if let ty::FnDef(def_id, substs) = *const_.ty.kind() {
+            if let Ok(Some(instance)) =
+                Instance::resolve(self.tcx, ParamEnv::reveal_all(), def_id, substs)
+            {
+                self.hash_fn(instance);
+            }
+        }
+
+        self.super_constant(constant, location);
+    }
+
+    fn visit_rvalue(&mut self, rvalue: &Rvalue<'tcx>, location: Location) {
+        match *rvalue {
+            Rvalue::Cast(CastKind::Pointer(PointerCast::Unsize), ref operand, target_ty) => {
+                let source_ty = self.monomorphize(operand.ty(self.mir, self.tcx));
+                let target_ty = self.monomorphize(target_ty);
+                self.hash_unsize(source_ty, target_ty);
+            }
+            Rvalue::ThreadLocalRef(def_id) => self.hash_static(def_id),
+            _ => {}
+        }
+        self.super_rvalue(rvalue, location);
+    }
+
+    fn visit_terminator(&mut self, terminator: &Terminator<'tcx>, location: Location) {
+        match terminator.kind {
+            TerminatorKind::Drop { place, .. } | TerminatorKind::DropAndReplace { place, .. } => {
+                let ty = self.monomorphize(place.ty(self.mir, self.tcx).ty);
+                self.hash_fn(Instance::resolve_drop_in_place(self.tcx, ty));
+            }
+            _ => {}
+        }
+        self.super_terminator(terminator, location);
+    }
+}
+
+/// Identify the cg_clif build using the path and modification time of the file containing cg_clif,
+/// as a rebuilt cg_clif may generate different code for the same input.
+fn cg_clif_build_id() -> String {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let dl_path = if unsafe { libc::dladdr(cg_clif_build_id as *const libc::c_void, &mut info) }
+        != 0
+        && !info.dli_fname.is_null()
+    {
+        Some(PathBuf::from(OsStr::from_bytes(
+            unsafe { CStr::from_ptr(info.dli_fname) }.to_bytes(),
+        )))
+    } else {
+        None
+    };
+
+    // The path returned for the main executable may be relative to the original working
+    // directory.
+    let path = dl_path
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::current_exe().ok());
+    let modified = path.as_ref().and_then(|path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    });
+
+    format!("{} {:?} {:?}", env!("CARGO_PKG_VERSION"), path, modified)
+}
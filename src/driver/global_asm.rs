@@ -0,0 +1,178 @@
+//! The AOT driver links the `global_asm!` and `asm!` of a codegen unit into its object file, while
+//! the JIT driver loads it into memory. Both use an external assembler.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use rustc_hir::ItemKind;
+
+use crate::prelude::*;
+
+pub(super) fn codegen_global_asm_item(
+    tcx: TyCtxt<'_>,
+    global_asm: &mut String,
+    hir_id: rustc_hir::HirId,
+) {
+    let item = tcx.hir().expect_item(hir_id);
+    if let ItemKind::GlobalAsm(rustc_hir::GlobalAsm { asm }) = item.kind {
+        global_asm.push_str(&*asm.as_str());
+        global_asm.push_str("\n\n");
+    } else {
+        bug!("Expected GlobalAsm found {:?}", item);
+    }
+}
+
+/// The `global_asm!` and `asm!` of a codegen unit, together with the tools necessary to assemble
+/// it.
+pub(super) struct GlobalAsm {
+    asm: String,
+    assembler: PathBuf,
+    linker: PathBuf,
+}
+
+impl GlobalAsm {
+    pub(super) fn new(tcx: TyCtxt<'_>, global_asm: String) -> Option<Self> {
+        if global_asm.is_empty() {
+            return None;
+        }
+
+        if cfg!(not(feature = "inline_asm"))
+            || tcx.sess.target.is_like_osx
+            || tcx.sess.target.is_like_windows
+        {
+            if global_asm.contains("__rust_probestack") {
+                return None;
+            }
+
+            // FIXME fix linker error on macOS
+            if cfg!(not(feature = "inline_asm")) {
+                tcx.sess.fatal(
+                    "asm! and global_asm! support is disabled while compiling rustc_codegen_cranelift",
+                );
+            } else {
+                tcx.sess
+                    .fatal("asm! and global_asm! are not yet supported on macOS and Windows");
+            }
+        }
+
+        // Remove all LLVM style comments
+        let asm = global_asm
+            .lines()
+            .map(|line| {
+                if let Some(index) = line.find("//") {
+                    &line[0..index]
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Some(GlobalAsm {
+            asm,
+            assembler: crate::toolchain::get_toolchain_binary(tcx.sess, "as"),
+            linker: crate::toolchain::get_toolchain_binary(tcx.sess, "ld"),
+        })
+    }
+
+    /// Assemble the global asm and link it into `output_object_file`.
+    pub(super) fn assemble(&self, output_object_file: &Path) -> Result<(), String> {
+        // Assemble `global_asm`
+        let global_asm_object_file = add_file_stem_postfix(output_object_file.to_owned(), ".asm");
+        self.run_assembler(&global_asm_object_file)?;
+
+        // Link the global asm and main object file together
+        link_objects_into(
+            &self.linker,
+            output_object_file,
+            &[global_asm_object_file.clone()],
+        )?;
+
+        std::fs::remove_file(global_asm_object_file).unwrap();
+
+        Ok(())
+    }
+
+    /// Assemble the global asm into a relocatable object file and return its contents.
+    #[cfg(feature = "jit")]
+    pub(super) fn assemble_to_vec(&self) -> Result<Vec<u8>, String> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let object_file = std::env::temp_dir().join(format!(
+            "cg_clif_jit_asm_{}_{}.o",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+        self.run_assembler(&object_file)?;
+        let object = std::fs::read(&object_file)
+            .map_err(|err| format!("Failed to read `{}`: {}", object_file.display(), err))?;
+        std::fs::remove_file(object_file).unwrap();
+
+        Ok(object)
+    }
+
+    fn run_assembler(&self, object_file: &Path) -> Result<(), String> {
+        use std::io::Write;
+
+        let mut child = Command::new(&self.assembler)
+            .arg("-o")
+            .arg(object_file)
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("Failed to spawn `as`.");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(self.asm.as_bytes())
+            .unwrap();
+        let status = child.wait().expect("Failed to wait for `as`.");
+        if !status.success() {
+            return Err(format!("Failed to assemble `{}`", self.asm));
+        }
+
+        Ok(())
+    }
+}
+
+/// Link `objects` into the relocatable object file `output_object_file` using `ld -r`.
+pub(super) fn link_objects_into(
+    linker: &Path,
+    output_object_file: &Path,
+    objects: &[PathBuf],
+) -> Result<(), String> {
+    let main_object_file = add_file_stem_postfix(output_object_file.to_owned(), ".main");
+    std::fs::rename(output_object_file, &main_object_file).unwrap();
+    let status = Command::new(linker)
+        .arg("-r") // Create a new object file
+        .arg("-o")
+        .arg(output_object_file)
+        .arg(&main_object_file)
+        .args(objects)
+        .status()
+        .unwrap();
+    if !status.success() {
+        return Err(format!(
+            "Failed to link `{}` and {} other object files together",
+            main_object_file.display(),
+            objects.len(),
+        ));
+    }
+
+    std::fs::remove_file(main_object_file).unwrap();
+
+    Ok(())
+}
+
+fn add_file_stem_postfix(mut path: PathBuf, postfix: &str) -> PathBuf {
+    let mut new_filename = path.file_stem().unwrap().to_owned();
+    new_filename.push(postfix);
+    if let Some(extension) = path.extension() {
+        new_filename.push(".");
+        new_filename.push(extension);
+    }
+    path.set_file_name(new_filename);
+    path
+}
@@ -1,80 +1,716 @@
 //! The JIT driver uses [`cranelift_simplejit`] to JIT execute programs without writing any object
 //! files.
 
-use std::cell::RefCell;
-use std::ffi::CString;
+use std::collections::hash_map::Entry;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::lazy::SyncOnceCell;
 use std::os::raw::{c_char, c_int};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU32;
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
 
 use rustc_codegen_ssa::CrateInfo;
-use rustc_middle::mir::mono::MonoItem;
+use rustc_data_structures::fx::FxHashSet;
+use rustc_middle::middle::cstore::NativeLib;
+use rustc_middle::mir::mono::{Linkage as RLinkage, MonoItem, Visibility};
+use rustc_session::config::{CrateType, DebugInfo, OptLevel};
+use rustc_session::utils::NativeLibKind;
 
 use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::FuncOrDataId;
 
+use crate::debuginfo::UnwindRegistry;
 use crate::prelude::*;
-use crate::{CodegenCx, CodegenMode};
+use crate::{BackendConfig, CodegenCx, CodegenMode};
 
-thread_local! {
-    pub static CURRENT_MODULE: RefCell<Option<JITModule>> = RefCell::new(None);
+use super::global_asm::GlobalAsm;
+use super::jit_cache::JitCache;
+use super::jit_perf::PerfJitListener;
+use super::jit_watch::{CrateSnapshot, SourceWatcher};
+use super::object_loader::{InitFunction, LoadedObject, TlsSymbol};
+
+struct JitState {
+    jit_module: JITModule,
+    imported_symbols: ImportedSymbols,
+    static_libs: StaticLibs,
+    /// The arguments of the jitted program. The lazy JIT passes them to the initializers of static
+    /// library members it links while the program is running already.
+    program_args: Vec<CString>,
+    /// The symbols defined by all `global_asm!` and `asm!` loaded so far.
+    asm_symbols: FxHashMap<String, *const u8>,
+    /// The symbol name of the function behind every lazy JIT trampoline, keyed by the instance
+    /// passed to `__clif_jit_fn` by the trampoline. In watch mode the instance may belong to a
+    /// session which has finished already, so it can't be used to compile the function.
+    lazy_fn_names: FxHashMap<*const Instance<'static>, String>,
+    /// The functions compiled by the lazy JIT so far, keyed by symbol name.
+    lazy_compiled_fns: FxHashMap<String, *const u8>,
+    /// See [`BackendConfig::jit_tier_up_threshold`](crate::BackendConfig::jit_tier_up_threshold).
+    tier_up_threshold: Option<u32>,
+    /// The functions which have been recompiled with optimizations by the tiered lazy JIT.
+    tiered_up_fns: FxHashSet<*const Instance<'static>>,
+    /// The JIT modules into which the tiered lazy JIT compiles hot functions, see
+    /// [`JitState::compile_optimized_fn`]. New hot functions are compiled into the last one.
+    optimized_modules: Vec<JITModule>,
+    /// Whether statics have been added to `jit_module` since the last optimized module was
+    /// created. The symbols known to a JIT module can't be extended, so hot functions referencing
+    /// these statics need a new optimized module.
+    optimized_module_outdated: bool,
+    perf_listener: Option<PerfJitListener>,
+    cache: Option<JitCache>,
 }
 
-pub(super) fn run_jit(tcx: TyCtxt<'_>, codegen_mode: CodegenMode) -> ! {
-    if !tcx.sess.opts.output_types.should_codegen() {
-        tcx.sess.fatal("JIT mode doesn't work with `cargo check`.");
+/// The symbols of all loaded dylibs and static libraries.
+struct ImportedSymbols {
+    symbols: FxHashMap<String, *const u8>,
+    /// The thread locals of all loaded static libraries. Thread locals of dylibs are handled by the
+    /// dynamic linker.
+    tls_symbols: FxHashMap<String, TlsSymbol>,
+}
+
+/// The members of all loaded rlibs and static libraries. All members are placed in memory up front,
+/// as the addresses of their symbols have to be passed to the [`JITBuilder`]. Like with a static
+/// linker a member is only linked, that is relocated and initialized, once the jitted code or a
+/// linked member references one of its symbols.
+struct StaticLibs {
+    members: Vec<LoadedObject>,
+    linked: Vec<bool>,
+    /// The member defining every symbol in [`ImportedSymbols`] which isn't defined by a dylib.
+    definitions: FxHashMap<String, usize>,
+}
+
+impl StaticLibs {
+    /// Link all members defining one of `referenced_symbols` and all members referenced by these
+    /// in turn. Returns the initializers of the newly linked members.
+    fn link(
+        &mut self,
+        tcx: TyCtxt<'_>,
+        referenced_symbols: Vec<String>,
+        jit_module: &JITModule,
+        imported_symbols: &ImportedSymbols,
+        asm_symbols: &FxHashMap<String, *const u8>,
+    ) -> Vec<InitFunction> {
+        let mut newly_linked = Vec::new();
+        let mut worklist = referenced_symbols;
+        while let Some(name) = worklist.pop() {
+            let index = match self.definitions.get(&name) {
+                Some(&index) if !self.linked[index] => index,
+                _ => continue,
+            };
+            self.linked[index] = true;
+            newly_linked.push(index);
+            worklist.extend(self.members[index].undefined_symbols().iter().cloned());
+        }
+
+        newly_linked.sort_unstable();
+        let mut init_functions = Vec::new();
+        for index in newly_linked {
+            let member = &mut self.members[index];
+            relocate_loaded_object(tcx, member, jit_module, imported_symbols, asm_symbols);
+            init_functions.extend(member.init_functions());
+        }
+        // The sort is stable, so initializers with the same priority keep the link order.
+        init_functions.sort_by_key(|&(priority, _)| priority);
+        init_functions
+            .into_iter()
+            .map(|(_priority, init_function)| init_function)
+            .collect()
     }
+}
 
-    #[cfg(unix)]
-    unsafe {
-        // When not using our custom driver rustc will open us without the RTLD_GLOBAL flag, so
-        // __cg_clif_global_atomic_mutex will not be exported. We fix this by opening ourself again
-        // as global.
-        // FIXME remove once atomic_shim is gone
+/// In lazy JIT mode the jitted program runs on a separate thread, while the rustc thread acts as
+/// compile server for it, as only the rustc thread may access the `TyCtxt`. `__clif_jit_fn` sends
+/// its requests through this channel.
+static LAZY_JIT_MESSAGE_SENDER: SyncOnceCell<Mutex<mpsc::Sender<UnsafeMessage>>> =
+    SyncOnceCell::new();
 
-        let mut dl_info: libc::Dl_info = std::mem::zeroed();
-        assert_ne!(
-            libc::dladdr(run_jit as *const libc::c_void, &mut dl_info),
-            0
-        );
-        assert_ne!(
-            libc::dlopen(dl_info.dli_fname, libc::RTLD_NOW | libc::RTLD_GLOBAL),
-            std::ptr::null_mut(),
-        );
+/// A message to the lazy JIT compile server. It contains raw pointers which are only valid on the
+/// rustc thread, hence the name.
+enum UnsafeMessage {
+    /// Compile the function for which `instance_ptr` was passed to `__clif_jit_fn` and send back
+    /// the address of the compiled function.
+    JitFn {
+        instance_ptr: *const Instance<'static>,
+        tx: mpsc::Sender<*const u8>,
+    },
+    /// Recompile the function for which `instance_ptr` was passed to `__clif_jit_tier_up` with
+    /// optimizations. The jitted program doesn't wait for this.
+    TierUp {
+        instance_ptr: *const Instance<'static>,
+    },
+    /// Hand the compile server over to a new session started in watch mode. See
+    /// [`reload_jit`].
+    Reload { tx: mpsc::Sender<Handover> },
+}
+
+unsafe impl Send for UnsafeMessage {}
+
+/// How often the compile server checks the source files for changes in watch mode.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Everything the lazy JIT compile server needs which doesn't depend on the session. In watch mode
+/// it is handed over from session to session.
+struct CompileServer {
+    jit_state: JitState,
+    rx: mpsc::Receiver<UnsafeMessage>,
+    watch: Option<Watch>,
+}
+
+// The compile server is only ever used by a single session at a time.
+unsafe impl Send for CompileServer {}
+
+struct Watch {
+    watcher: SourceWatcher,
+    /// The state of the crate as currently loaded into the running program.
+    snapshot: CrateSnapshot,
+}
+
+/// Sent to a reload session in response to [`UnsafeMessage::Reload`]. The reload session either
+/// sends the compile server back, in which case the current session keeps serving requests, or
+/// drops `back_tx` to take over.
+struct Handover {
+    server: CompileServer,
+    back_tx: mpsc::Sender<CompileServer>,
+}
+
+/// A crate which has been JIT compiled as library. Its exported functions and statics can be
+/// looked up by their symbol name.
+///
+/// All code is compiled eagerly, so this doesn't depend on the compiler session anymore.
+pub struct JitHandle {
+    jit_module: JITModule,
+    _unwind_registry: Option<UnwindRegistry>,
+}
+
+// The jitted code doesn't depend on the thread it was compiled on.
+unsafe impl Send for JitHandle {}
+
+impl JitHandle {
+    /// Get the address of an exported function or static, like a `#[no_mangle]` function, by its
+    /// symbol name. It is up to the caller to cast it to the right type.
+    pub fn get_symbol(&self, name: &str) -> Option<*const u8> {
+        let is_exported = match self.jit_module.get_name(name)? {
+            FuncOrDataId::Func(func_id) => {
+                self.jit_module
+                    .declarations()
+                    .get_function_decl(func_id)
+                    .linkage
+                    == Linkage::Export
+            }
+            FuncOrDataId::Data(data_id) => {
+                self.jit_module
+                    .declarations()
+                    .get_data_decl(data_id)
+                    .linkage
+                    == Linkage::Export
+            }
+        };
+        if is_exported {
+            lookup_jit_symbol(&self.jit_module, name)
+        } else {
+            None
+        }
+    }
+
+    /// Free all jitted functions and statics. Loaded static libraries and the debuginfo registered
+    /// with debuggers are not freed.
+    ///
+    /// # Safety
+    ///
+    /// None of the jitted functions may be running or be called afterwards and none of the
+    /// pointers returned by [`get_symbol`](Self::get_symbol) may be used afterwards.
+    pub unsafe fn free(self) {
+        drop(self._unwind_registry);
+        self.jit_module.free_memory();
+    }
+}
+
+/// A crate which has been JIT compiled, but not yet run.
+struct JittedCrate {
+    jit_state: JitState,
+    /// The initializers of all linked static library members, which have to be run before any
+    /// jitted code.
+    init_functions: Vec<InitFunction>,
+    unwind_registry: Option<UnwindRegistry>,
+}
+
+pub(super) fn run_jit(tcx: TyCtxt<'_>, config: BackendConfig) -> ! {
+    if tcx.sess.opts.test {
+        check_test_harness_gets_args(tcx);
+    }
+
+    let JittedCrate {
+        mut jit_state,
+        init_functions,
+        unwind_registry: _unwind_registry,
+    } = jit_crate(tcx, config, CrateType::Executable);
+
+    let finalized_main = lookup_jit_symbol(&jit_state.jit_module, "main").unwrap_or_else(|| {
+        tcx.sess
+            .fatal("No `main` function found to run in JIT mode")
+    });
+
+    // Test processes spawned by libtest shouldn't add anything to the captured test output.
+    if std::env::var_os(JIT_TEST_RUSTC_ARGS_VAR).is_none() {
+        println!("Rustc codegen cranelift will JIT run the executable, because -Cllvm-args=mode=jit was passed");
     }
 
-    let imported_symbols = load_imported_symbols_for_jit(tcx);
+    let f: extern "C" fn(c_int, *const *const c_char) -> c_int =
+        unsafe { ::std::mem::transmute(finalized_main) };
+
+    let args = jit_program_args(tcx);
+    jit_state.program_args = args.clone();
+    // Test processes already got the arguments of the original rustc invocation.
+    if tcx.sess.opts.test && std::env::var_os(JIT_TEST_RUSTC_ARGS_VAR).is_none() {
+        let rustc_args = encode_jit_test_rustc_args(std::env::args_os().skip(1));
+        std::env::set_var(JIT_TEST_RUSTC_ARGS_VAR, rustc_args);
+    }
+
+    let run_main = move || -> ! {
+        let mut argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+
+        // Push a null pointer as a terminating argument. This is required by POSIX and
+        // useful as some dynamic linkers use it as a marker to jump over.
+        argv.push(std::ptr::null());
 
-    let mut jit_builder = JITBuilder::with_isa(
-        crate::build_isa(tcx.sess),
-        cranelift_module::default_libcall_names(),
+        run_init_functions(&init_functions, args.len() as c_int, argv.as_ptr());
+
+        let ret = f(args.len() as c_int, argv.as_ptr());
+
+        std::process::exit(ret);
+    };
+
+    match config.codegen_mode {
+        CodegenMode::Aot => unreachable!(),
+        CodegenMode::Jit => run_main(),
+        CodegenMode::JitLazy => {
+            let watch = if config.jit_watch {
+                let mono_items = collect_mono_items(tcx)
+                    .into_iter()
+                    .map(|(mono_item, _)| mono_item)
+                    .collect::<Vec<_>>();
+                Some(Watch {
+                    watcher: SourceWatcher::new(tcx.sess, None),
+                    snapshot: CrateSnapshot::new(tcx, &mono_items),
+                })
+            } else {
+                None
+            };
+
+            let (tx, rx) = mpsc::channel();
+            if LAZY_JIT_MESSAGE_SENDER.set(Mutex::new(tx)).is_err() {
+                bug!("lazy JIT compile server started twice");
+            }
+
+            std::thread::Builder::new()
+                .name("main".to_string())
+                // Match the default stack size of the main thread on Linux.
+                .stack_size(8 * 1024 * 1024)
+                .spawn(run_main)
+                .unwrap();
+
+            serve(
+                tcx,
+                CompileServer {
+                    jit_state,
+                    rx,
+                    watch,
+                },
+                |_jit_state, instance_ptr| {
+                    // lift is used to ensure the correct lifetime for instance.
+                    tcx.lift(unsafe { *instance_ptr }).unwrap()
+                },
+            );
+
+            // A reload session has taken over as compile server. Returning would make rustc exit,
+            // so wait for the jitted program to exit the process instead.
+            loop {
+                std::thread::park();
+            }
+        }
+    }
+}
+
+/// Serve the requests of the jitted program until a reload session has taken over as compile
+/// server. Without watch mode this never returns.
+///
+/// `resolve_instance` gets the instance to compile for a pointer passed to `__clif_jit_fn`.
+fn serve<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    mut server: CompileServer,
+    resolve_instance: impl Fn(&JitState, *const Instance<'static>) -> Instance<'tcx>,
+) {
+    loop {
+        let message = match &mut server.watch {
+            Some(watch) => {
+                let message = server.rx.recv_timeout(WATCH_POLL_INTERVAL).ok();
+                watch.watcher.poll(tcx.sess);
+                match message {
+                    Some(message) => message,
+                    None => continue,
+                }
+            }
+            None => server.rx.recv().unwrap(),
+        };
+
+        match message {
+            UnsafeMessage::JitFn { instance_ptr, tx } => {
+                let func_ptr = server
+                    .jit_state
+                    .jit_fn(tcx, instance_ptr, &resolve_instance);
+                // The requesting thread may have exited in the mean time.
+                let _ = tx.send(func_ptr);
+            }
+            UnsafeMessage::TierUp { instance_ptr } => {
+                let instance = resolve_instance(&server.jit_state, instance_ptr);
+                server.jit_state.tier_up(tcx, instance_ptr, instance);
+            }
+            UnsafeMessage::Reload { tx } => {
+                let (back_tx, back_rx) = mpsc::channel();
+                if let Err(mpsc::SendError(handover)) = tx.send(Handover { server, back_tx }) {
+                    // The reload session has failed in the mean time.
+                    server = handover.server;
+                    continue;
+                }
+                match back_rx.recv() {
+                    Ok(rejected_server) => {
+                        server = rejected_server;
+                        if let Some(watch) = &mut server.watch {
+                            watch.watcher.reload_rejected();
+                        }
+                    }
+                    Err(mpsc::RecvError) => return,
+                }
+            }
+        }
+    }
+}
+
+/// Whether the current session has been started by the compile server of the lazy JIT in watch
+/// mode to reload the jitted crate. Once the compile server runs, no other session calls into the
+/// codegen backend.
+pub(super) fn is_reload_session() -> bool {
+    LAZY_JIT_MESSAGE_SENDER.get().is_some()
+}
+
+/// Take over as compile server and apply the changes of the current session to the running
+/// program. Returns once yet another reload session has taken over or when the changes can't be
+/// applied.
+pub(super) fn reload_jit(tcx: TyCtxt<'_>) {
+    let mono_items = collect_mono_items(tcx);
+    let snapshot = CrateSnapshot::new(
+        tcx,
+        &mono_items
+            .iter()
+            .map(|&(mono_item, _)| mono_item)
+            .collect::<Vec<_>>(),
     );
-    jit_builder.hotswap(matches!(codegen_mode, CodegenMode::JitLazy));
-    jit_builder.symbols(imported_symbols);
-    let mut jit_module = JITModule::new(jit_builder);
-    assert_eq!(pointer_ty(tcx), jit_module.target_config().pointer_type());
 
-    let sig = Signature {
-        params: vec![
-            AbiParam::new(jit_module.target_config().pointer_type()),
-            AbiParam::new(jit_module.target_config().pointer_type()),
-        ],
-        returns: vec![AbiParam::new(
-            jit_module.target_config().pointer_type(), /*isize*/
-        )],
-        call_conv: CallConv::triple_default(&crate::target_triple(tcx.sess)),
+    let (tx, rx) = mpsc::channel();
+    LAZY_JIT_MESSAGE_SENDER
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .send(UnsafeMessage::Reload { tx })
+        .expect("lazy JIT compile server exited");
+    let Handover {
+        mut server,
+        back_tx,
+    } = rx.recv().expect("lazy JIT compile server exited");
+    let old_watch = server
+        .watch
+        .take()
+        .expect("reload session started without watch mode");
+
+    let incompatible_changes = old_watch.snapshot.incompatible_changes(&snapshot);
+    if !incompatible_changes.is_empty() {
+        let mut err = tcx
+            .sess
+            .struct_err("the changes can't be applied to the running program");
+        for change in incompatible_changes {
+            err.note(&change);
+        }
+        err.help("restart the program to apply these changes");
+        err.emit();
+
+        server.watch = Some(old_watch);
+        back_tx
+            .send(server)
+            .expect("lazy JIT compile server exited");
+        return;
+    }
+    drop(back_tx);
+
+    let (new_fns, changed_fns) =
+        server
+            .jit_state
+            .apply_reload(tcx, &old_watch.snapshot, &snapshot, &mono_items);
+    tcx.sess.note_without_error(&format!(
+        "Reloaded the jitted program: {} new and {} recompiled functions",
+        new_fns, changed_fns
+    ));
+
+    server.watch = Some(Watch {
+        watcher: SourceWatcher::new(tcx.sess, Some(&old_watch.watcher)),
+        snapshot,
+    });
+
+    let instances = mono_items
+        .iter()
+        .filter_map(|&(mono_item, _)| match mono_item {
+            MonoItem::Fn(instance) => Some((tcx.symbol_name(instance).name, instance)),
+            MonoItem::Static(_) | MonoItem::GlobalAsm(_) => None,
+        })
+        .collect::<FxHashMap<_, _>>();
+    serve(tcx, server, |jit_state, instance_ptr| {
+        let name = &*jit_state.lazy_fn_names[&instance_ptr];
+        *instances.get(name).unwrap_or_else(|| {
+            tcx.sess.fatal(&format!(
+                "`{}` has been removed, but the running program still calls it",
+                name
+            ))
+        })
+    });
+}
+
+/// The rustc arguments to JIT compile the current crate again, see
+/// [`decode_jit_test_rustc_args`].
+///
+/// With `-Cpanic=abort` libtest runs every test in a new process by spawning `argv[0]` with the
+/// name of the test in `__RUST_TEST_INVOKE`. For test harnesses `argv[0]` is set to the rustc
+/// executable, which uses this variable to JIT compile and run the harness once again. The
+/// jitted code of the parent process isn't reused, so every test pays for the analysis and
+/// codegen of the whole crate.
+pub const JIT_TEST_RUSTC_ARGS_VAR: &str = "CG_CLIF_JIT_TEST_RUSTC_ARGS";
+
+/// Encode arguments for [`JIT_TEST_RUSTC_ARGS_VAR`]. Arguments may contain any byte except nul,
+/// so every argument is hex encoded and the arguments are separated by spaces.
+fn encode_jit_test_rustc_args(args: impl Iterator<Item = OsString>) -> OsString {
+    use std::fmt::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut encoded = String::new();
+    for (i, arg) in args.enumerate() {
+        if i != 0 {
+            encoded.push(' ');
+        }
+        for byte in arg.as_bytes() {
+            write!(encoded, "{:02x}", byte).unwrap();
+        }
+    }
+    encoded.into()
+}
+
+/// Decode the arguments in [`JIT_TEST_RUSTC_ARGS_VAR`]. Returns `None` if they are malformed.
+pub fn decode_jit_test_rustc_args(encoded: &OsStr) -> Option<Vec<OsString>> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let encoded = encoded.to_str()?;
+    if encoded.is_empty() {
+        return Some(vec![]);
+    }
+    encoded
+        .split(' ')
+        .map(|arg| {
+            if arg.len() % 2 != 0 || !arg.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return None;
+            }
+            (0..arg.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(arg.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+                .map(OsString::from_vec)
+        })
+        .collect()
+}
+
+/// Get the arguments to pass to the jitted program, including `argv[0]`, which is the rustc
+/// executable for test harnesses. See [`JIT_TEST_RUSTC_ARGS_VAR`].
+///
+/// `CG_CLIF_JIT_ENCODED_ARGS` contains the arguments separated by `\x1f`, like
+/// `CARGO_ENCODED_RUSTFLAGS`. This allows arguments to contain spaces. The older
+/// `CG_CLIF_JIT_ARGS` is split on spaces.
+fn jit_program_args(tcx: TyCtxt<'_>) -> Vec<CString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let program_name = if tcx.sess.opts.test {
+        let rustc_path = std::env::current_exe().unwrap_or_else(|err| {
+            tcx.sess
+                .fatal(&format!("Failed to get rustc path: {}", err))
+        });
+        CString::new(rustc_path.as_os_str().as_bytes()).unwrap()
+    } else {
+        CString::new(&*tcx.crate_name(LOCAL_CRATE).as_str()).unwrap()
     };
-    let main_func_id = jit_module
-        .declare_function("main", Linkage::Import, &sig)
-        .unwrap();
 
-    let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
-    let mono_items = cgus
+    let args = if let Some(args) = std::env::var_os("CG_CLIF_JIT_ENCODED_ARGS") {
+        if args.is_empty() {
+            vec![]
+        } else {
+            args.as_bytes()
+                .split(|&byte| byte == 0x1f)
+                .map(|arg| arg.to_vec())
+                .collect()
+        }
+    } else if let Some(args) = std::env::var_os("CG_CLIF_JIT_ARGS") {
+        args.as_bytes()
+            .split(|&byte| byte == b' ')
+            .map(|arg| arg.to_vec())
+            .collect()
+    } else {
+        vec![]
+    };
+
+    std::iter::once(program_name)
+        .chain(args.into_iter().map(|arg| {
+            CString::new(arg).unwrap_or_else(|_| {
+                tcx.sess
+                    .fatal("Arguments for the jitted program may not contain nul bytes")
+            })
+        }))
+        .collect()
+}
+
+/// libtest reads its arguments using `std::env::args()`. On linux-gnu a dynamically linked libstd
+/// gets those from its `.init_array` entry, which was run by the dynamic linker for rustc. As such
+/// the test harness would see the arguments of rustc. A statically loaded libstd gets the
+/// arguments of the jitted program instead.
+fn check_test_harness_gets_args(tcx: TyCtxt<'_>) {
+    use rustc_middle::middle::dependency_format::Linkage;
+
+    if tcx.sess.target.os != "linux" || tcx.sess.target.env != "gnu" {
+        return;
+    }
+
+    let formats = tcx.dependency_formats(LOCAL_CRATE);
+    let data = &formats
         .iter()
-        .map(|cgu| cgu.items_in_deterministic_order(tcx).into_iter())
-        .flatten()
-        .collect::<FxHashMap<_, (_, _)>>()
-        .into_iter()
-        .collect::<Vec<(_, (_, _))>>();
+        .find(|(crate_type, _data)| *crate_type == CrateType::Executable)
+        .unwrap()
+        .1;
+    let std_is_dynamic = tcx.crates().iter().any(|&cnum| {
+        tcx.crate_name(cnum) == rustc_span::symbol::sym::std
+            && data[cnum.as_usize() - 1] == Linkage::Dynamic
+    });
+    if std_is_dynamic {
+        let mut err = tcx
+            .sess
+            .struct_err("Can't run the test harness with a dynamically linked libstd in JIT mode");
+        err.note("libstd would see the arguments of rustc instead of those for the test harness");
+        err.help("remove -Cprefer-dynamic to load libstd statically");
+        err.emit();
+        tcx.sess.abort_if_errors();
+    }
+}
+
+/// JIT compile a library crate without running anything but the initializers of the static
+/// libraries it depends on.
+pub(super) fn jit_library(tcx: TyCtxt<'_>, config: BackendConfig) -> JitHandle {
+    if let CodegenMode::JitLazy = config.codegen_mode {
+        tcx.sess.fatal(
+            "The lazy JIT can't be used to JIT compile a library, as it needs the compiler \
+             session to stay alive.",
+        );
+    }
+
+    // A crate type which links all dependencies is necessary to know which dependencies to load
+    // and to get an allocator shim.
+    let crate_type = tcx
+        .sess
+        .crate_types()
+        .iter()
+        .copied()
+        .find(|crate_type| {
+            matches!(
+                crate_type,
+                CrateType::Executable | CrateType::Dylib | CrateType::Cdylib
+            )
+        })
+        .unwrap_or_else(|| {
+            tcx.sess.fatal(
+                "Libraries can only be JIT compiled with crate type `dylib`, `cdylib` or `bin`",
+            )
+        });
+
+    let jitted_crate = jit_crate(tcx, config, crate_type);
 
-    let mut cx = crate::CodegenCx::new(tcx, jit_module, false, false);
+    let argv = [std::ptr::null()];
+    run_init_functions(&jitted_crate.init_functions, 0, argv.as_ptr());
+
+    JitHandle {
+        jit_module: jitted_crate.jit_state.jit_module,
+        _unwind_registry: jitted_crate.unwind_registry,
+    }
+}
+
+/// Run the initializers of the loaded static libraries. The dynamic linker only runs the
+/// initializers of dylibs. For example libstd uses one to get the program arguments.
+fn run_init_functions(init_functions: &[InitFunction], argc: c_int, argv: *const *const c_char) {
+    extern "C" {
+        static environ: *const *const c_char;
+    }
+
+    for init_function in init_functions {
+        init_function(argc, argv, unsafe { environ });
+    }
+}
+
+/// JIT compile the local crate. Depending on the codegen mode either all functions are compiled
+/// right away or only trampolines for lazy compilation are created. `crate_type` determines which
+/// dependencies are loaded.
+fn jit_crate(tcx: TyCtxt<'_>, config: BackendConfig, crate_type: CrateType) -> JittedCrate {
+    let BackendConfig {
+        codegen_mode,
+        jit_tier_up_threshold: tier_up_threshold,
+        ..
+    } = config;
+
+    if !tcx.sess.opts.output_types.should_codegen() {
+        tcx.sess.fatal("JIT mode doesn't work with `cargo check`.");
+    }
+
+    let (imported_symbols, mut static_libs) = load_imported_symbols_for_jit(tcx, crate_type);
+
+    // The tiered lazy JIT compiles hot functions again using an optimizing isa, see
+    // `JitState::compile_optimized_fn`.
+    let isa = if tier_up_threshold.is_some() {
+        crate::build_isa_with_opt_level(tcx.sess, OptLevel::No)
+    } else {
+        crate::build_isa(tcx.sess)
+    };
+    let mut jit_builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+    jit_builder.hotswap(matches!(codegen_mode, CodegenMode::JitLazy));
+    jit_builder.symbols(
+        imported_symbols
+            .symbols
+            .iter()
+            .map(|(name, &addr)| (name.clone(), addr)),
+    );
+    let jit_module = JITModule::new(jit_builder);
+    assert_eq!(pointer_ty(tcx), jit_module.target_config().pointer_type());
+
+    let mono_items = collect_mono_items(tcx);
+
+    let mut perf_listener = PerfJitListener::new(tcx);
+    let mut compiled_fns = Vec::new();
+    let mut lazy_fn_names = FxHashMap::default();
+    let mut cached_objects = Vec::new();
+
+    // The tiered lazy JIT doesn't use the code cache, as hot functions are always recompiled.
+    let mut cache = match tier_up_threshold {
+        None => JitCache::new(tcx, &config),
+        Some(_) => None,
+    };
+
+    let mut cx = crate::CodegenCx::new(
+        tcx,
+        jit_module,
+        tcx.sess.opts.debuginfo != DebugInfo::None,
+        false,
+    );
 
     super::time(tcx, "codegen mono items", || {
         super::predefine_mono_items(&mut cx, &mono_items);
@@ -84,121 +720,932 @@ pub(super) fn run_jit(tcx: TyCtxt<'_>, codegen_mode: CodegenMode) -> ! {
                 MonoItem::Fn(inst) => match codegen_mode {
                     CodegenMode::Aot => unreachable!(),
                     CodegenMode::Jit => {
-                        cx.tcx.sess.time("codegen fn", || {
-                            crate::base::codegen_fn(&mut cx, inst, linkage)
+                        if let Some(object) = cache
+                            .as_mut()
+                            .and_then(|cache| cache.load_or_compile(tcx, inst))
+                        {
+                            let name = tcx.symbol_name(inst).name;
+                            let sig = get_function_sig(tcx, cx.module.isa().triple(), inst);
+                            let func_id = cx.module.declare_function(name, linkage, &sig).unwrap();
+                            cached_objects.push(load_cached_fn(
+                                tcx,
+                                &mut cx.module,
+                                func_id,
+                                name,
+                                object,
+                            ));
+                            continue;
+                        }
+
+                        let compiled_fn = cx.tcx.sess.time("codegen fn", || {
+                            crate::base::codegen_and_compile_fn(&mut cx, inst, linkage)
                         });
+                        if perf_listener.is_some() {
+                            compiled_fns.push((inst.to_string(), compiled_fn));
+                        }
+                    }
+                    CodegenMode::JitLazy => {
+                        let instance_ptr = codegen_shim(&mut cx, inst);
+                        lazy_fn_names.insert(instance_ptr, tcx.symbol_name(inst).name.to_string());
                     }
-                    CodegenMode::JitLazy => codegen_shim(&mut cx, inst),
                 },
                 MonoItem::Static(def_id) => {
                     crate::constant::codegen_static(&mut cx.constants_cx, def_id);
                 }
                 MonoItem::GlobalAsm(hir_id) => {
-                    let item = cx.tcx.hir().expect_item(hir_id);
-                    tcx.sess
-                        .span_fatal(item.span, "Global asm is not supported in JIT mode");
+                    super::global_asm::codegen_global_asm_item(tcx, &mut cx.global_asm, hir_id);
                 }
             }
         }
     });
 
-    let (mut jit_module, global_asm, _debug, mut unwind_context) =
+    let (mut jit_module, global_asm, debug, mut unwind_context) =
         tcx.sess.time("finalize CodegenCx", || cx.finalize());
-    jit_module.finalize_definitions();
-
-    if !global_asm.is_empty() {
-        tcx.sess.fatal("Inline asm is not supported in JIT mode");
-    }
 
-    crate::main_shim::maybe_create_entry_wrapper(tcx, &mut jit_module, &mut unwind_context, true);
+    crate::main_shim::maybe_create_entry_wrapper(tcx, &mut jit_module, &mut unwind_context, false);
     crate::allocator::codegen(tcx, &mut jit_module, &mut unwind_context);
 
     tcx.sess.abort_if_errors();
 
-    jit_module.finalize_definitions();
+    // Static libraries may reference symbols of the jitted crate, like the allocator shim, so they
+    // can only be linked once all of it has been defined.
+    let referenced_symbols = cached_objects
+        .iter()
+        .flat_map(|cached_object| cached_object.undefined_symbols().iter().cloned())
+        .collect();
+    let mut asm_symbols = FxHashMap::default();
+    let init_functions = finalize_definitions(
+        tcx,
+        &mut jit_module,
+        global_asm,
+        &imported_symbols,
+        &mut asm_symbols,
+        &mut static_libs,
+        referenced_symbols,
+    );
+    for cached_object in &mut cached_objects {
+        relocate_loaded_object(
+            tcx,
+            cached_object,
+            &jit_module,
+            &imported_symbols,
+            &asm_symbols,
+        );
+    }
 
-    let _unwind_register_guard = unsafe { unwind_context.register_jit(&jit_module) };
+    if let Some(debug) = debug {
+        debug.register_jit(tcx, &jit_module);
+    }
+    if let Some(perf_listener) = &mut perf_listener {
+        perf_listener.register_functions(tcx, &jit_module, &compiled_fns);
+    }
+    let unwind_registry = unsafe { unwind_context.register_jit(&jit_module) };
 
-    let finalized_main: *const u8 = jit_module.get_finalized_function(main_func_id);
+    JittedCrate {
+        jit_state: JitState {
+            jit_module,
+            imported_symbols,
+            static_libs,
+            program_args: Vec::new(),
+            asm_symbols,
+            lazy_fn_names,
+            lazy_compiled_fns: FxHashMap::default(),
+            tier_up_threshold,
+            tiered_up_fns: FxHashSet::default(),
+            optimized_modules: Vec::new(),
+            optimized_module_outdated: false,
+            perf_listener,
+            cache,
+        },
+        init_functions,
+        unwind_registry,
+    }
+}
 
-    println!("Rustc codegen cranelift will JIT run the executable, because -Cllvm-args=mode=jit was passed");
+fn collect_mono_items<'tcx>(tcx: TyCtxt<'tcx>) -> Vec<(MonoItem<'tcx>, (RLinkage, Visibility))> {
+    let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+    cgus.iter()
+        .map(|cgu| cgu.items_in_deterministic_order(tcx).into_iter())
+        .flatten()
+        .collect::<FxHashMap<_, (_, _)>>()
+        .into_iter()
+        .collect::<Vec<(_, (_, _))>>()
+}
 
-    let f: extern "C" fn(c_int, *const *const c_char) -> c_int =
-        unsafe { ::std::mem::transmute(finalized_main) };
+/// How the lazy JIT compiles a function.
+#[derive(Copy, Clone)]
+enum LazyTier {
+    /// Compile with the opt level of the session.
+    Default,
+    /// Compile without any optimizations and count the calls to the function. Once the function
+    /// has been called `threshold` times, it is recompiled using
+    /// [`JitState::compile_optimized_fn`]. `instance_ptr` is passed to `__clif_jit_tier_up` to
+    /// identify the function.
+    Baseline {
+        threshold: u32,
+        instance_ptr: *const Instance<'static>,
+    },
+}
 
-    let args = ::std::env::var("CG_CLIF_JIT_ARGS").unwrap_or_else(|_| String::new());
-    let args = std::iter::once(&*tcx.crate_name(LOCAL_CRATE).as_str().to_string())
-        .chain(args.split(' '))
-        .map(|arg| CString::new(arg).unwrap())
-        .collect::<Vec<_>>();
-    let mut argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+impl JitState {
+    /// Compile the function behind a lazy JIT trampoline and redirect all further calls to it.
+    ///
+    /// Several threads may call the trampoline of a function before it is compiled, so a function
+    /// which has been compiled before is never compiled and patched again.
+    fn jit_fn<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        instance_ptr: *const Instance<'static>,
+        resolve_instance: impl Fn(&JitState, *const Instance<'static>) -> Instance<'tcx>,
+    ) -> *const u8 {
+        if let Some(&func_ptr) = self
+            .lazy_compiled_fns
+            .get(&self.lazy_fn_names[&instance_ptr])
+        {
+            return func_ptr;
+        }
 
-    // Push a null pointer as a terminating argument. This is required by POSIX and
-    // useful as some dynamic linkers use it as a marker to jump over.
-    argv.push(std::ptr::null());
+        let instance = resolve_instance(self, instance_ptr);
+        let tier = match self.tier_up_threshold {
+            Some(threshold) => LazyTier::Baseline {
+                threshold,
+                instance_ptr,
+            },
+            None => LazyTier::Default,
+        };
+        let func_ptr = self.compile_lazy_fn(tcx, instance, tier);
+        self.set_compiled_fn(self.lazy_fn_names[&instance_ptr].clone(), func_ptr);
+        func_ptr
+    }
+
+    /// Recompile a function which has been called often enough with optimizations and redirect all
+    /// further calls to it. Calls which are already running keep using the old code.
+    fn tier_up<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        instance_ptr: *const Instance<'static>,
+        instance: Instance<'tcx>,
+    ) {
+        // Multiple threads may reach the threshold at the same time.
+        if !self.tiered_up_fns.insert(instance_ptr) {
+            return;
+        }
 
-    CURRENT_MODULE
-        .with(|current_module| assert!(current_module.borrow_mut().replace(jit_module).is_none()));
+        let func_ptr = self.compile_optimized_fn(tcx, instance);
+        self.set_compiled_fn(tcx.symbol_name(instance).name.to_string(), func_ptr);
+    }
+
+    /// Record `func_ptr` as the latest code of the function `name`. Optimized code calls functions
+    /// of the lazy JIT module through forwarding shims, which are redirected to `func_ptr`.
+    fn set_compiled_fn(&mut self, name: String, func_ptr: *const u8) {
+        for opt_module in &mut self.optimized_modules {
+            let func_id = match opt_module.get_name(&name) {
+                Some(FuncOrDataId::Func(func_id)) => func_id,
+                _ => continue,
+            };
+            let decl = opt_module.declarations().get_function_decl(func_id);
+            if !decl.linkage.is_definable()
+                || opt_module.get_finalized_function(func_id) == func_ptr
+            {
+                continue;
+            }
+            let sig = decl.signature.clone();
+            opt_module.prepare_for_function_redefine(func_id).unwrap();
+            define_forwarding_shim(opt_module, func_id, sig, func_ptr);
+            opt_module.finalize_definitions();
+        }
+        self.lazy_compiled_fns.insert(name, func_ptr);
+    }
 
-    let ret = f(args.len() as c_int, argv.as_ptr());
+    fn compile_lazy_fn<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        instance: Instance<'tcx>,
+        tier: LazyTier,
+    ) -> *const u8 {
+        if let Some(object) = self
+            .cache
+            .as_mut()
+            .and_then(|cache| cache.load_or_compile(tcx, instance))
+        {
+            return self.load_lazy_cached_fn(tcx, instance, object);
+        }
+
+        let mut cx = crate::CodegenCx::new(
+            tcx,
+            &mut self.jit_module,
+            tcx.sess.opts.debuginfo != DebugInfo::None,
+            false,
+        );
+        match tier {
+            LazyTier::Default => {}
+            LazyTier::Baseline { .. } => cx.opt_level = OptLevel::No,
+        }
+
+        let name = tcx.symbol_name(instance).name.to_string();
+        let sig = crate::abi::get_function_sig(tcx, cx.module.isa().triple(), instance);
+        let func_id = cx
+            .module
+            .declare_function(&name, Linkage::Export, &sig)
+            .unwrap();
+        cx.module.prepare_for_function_redefine(func_id).unwrap();
+
+        let compiled_fn = tcx.sess.time("codegen fn", || {
+            let mut codegened_func = crate::base::codegen_fn(&mut cx, instance, Linkage::Export);
+            match tier {
+                LazyTier::Default => {}
+                LazyTier::Baseline {
+                    threshold,
+                    instance_ptr,
+                } => {
+                    insert_call_counter(&mut cx, codegened_func.func_mut(), instance_ptr, threshold)
+                }
+            }
+            crate::base::compile_codegened_fn(&mut cx, instance, codegened_func)
+        });
+
+        let (jit_module, global_asm, debug_context, unwind_context) = cx.finalize();
+        let init_functions = finalize_definitions(
+            tcx,
+            jit_module,
+            global_asm,
+            &self.imported_symbols,
+            &mut self.asm_symbols,
+            &mut self.static_libs,
+            Vec::new(),
+        );
+        run_late_init_functions(init_functions, &self.program_args);
+        if let Some(debug_context) = debug_context {
+            debug_context.register_jit(tcx, jit_module);
+        }
+        if let Some(perf_listener) = &mut self.perf_listener {
+            perf_listener.register_functions(
+                tcx,
+                jit_module,
+                &[(instance.to_string(), compiled_fn)],
+            );
+        }
+        std::mem::forget(unsafe { unwind_context.register_jit(&jit_module) });
 
-    std::process::exit(ret);
+        jit_module.get_finalized_function(func_id)
+    }
+
+    /// Compile a hot function with all optimizations and replace the function in the JIT module
+    /// with a shim forwarding to the optimized code.
+    ///
+    /// The isa of a JIT module can't be changed and the isa of the JIT module of the tiered lazy
+    /// JIT doesn't optimize, so hot functions are compiled into a separate JIT module using an
+    /// optimizing isa. It is created once and reused for all hot functions. Calls from optimized
+    /// code to other functions go through forwarding shims, see [`define_lazy_fn_shims`], unless
+    /// the callee has been optimized too.
+    fn compile_optimized_fn<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        instance: Instance<'tcx>,
+    ) -> *const u8 {
+        if self.optimized_modules.is_empty() || self.optimized_module_outdated {
+            let opt_module = self.new_optimized_module(tcx);
+            self.optimized_modules.push(opt_module);
+            self.optimized_module_outdated = false;
+        }
+        let opt_module = self.optimized_modules.last_mut().unwrap();
+
+        // Optimized code may have called this function through a forwarding shim already.
+        let name = tcx.symbol_name(instance).name.to_string();
+        if let Some(FuncOrDataId::Func(func_id)) = opt_module.get_name(&name) {
+            let decl = opt_module.declarations().get_function_decl(func_id);
+            if decl.linkage.is_definable() {
+                opt_module.prepare_for_function_redefine(func_id).unwrap();
+            }
+        }
+
+        let mut cx = crate::CodegenCx::new(
+            tcx,
+            &mut *opt_module,
+            tcx.sess.opts.debuginfo != DebugInfo::None,
+            false,
+        );
+        cx.opt_level = OptLevel::Aggressive;
+
+        let compiled_fn = tcx.sess.time("codegen hot fn", || {
+            crate::base::codegen_and_compile_fn(&mut cx, instance, Linkage::Export)
+        });
+
+        let (opt_module, global_asm, debug_context, unwind_context) = cx.finalize();
+        define_lazy_fn_shims(opt_module, &self.jit_module, &self.lazy_compiled_fns);
+        let init_functions = finalize_definitions(
+            tcx,
+            opt_module,
+            global_asm,
+            &self.imported_symbols,
+            &mut self.asm_symbols,
+            &mut self.static_libs,
+            Vec::new(),
+        );
+        run_late_init_functions(init_functions, &self.program_args);
+        if let Some(debug_context) = debug_context {
+            debug_context.register_jit(tcx, opt_module);
+        }
+        if let Some(perf_listener) = &mut self.perf_listener {
+            perf_listener.register_functions(
+                tcx,
+                opt_module,
+                &[(instance.to_string(), compiled_fn)],
+            );
+        }
+        std::mem::forget(unsafe { unwind_context.register_jit(&opt_module) });
+
+        let func_ptr = lookup_jit_symbol(opt_module, &name).unwrap();
+
+        let sig = get_function_sig(tcx, self.jit_module.isa().triple(), instance);
+        let func_id = self
+            .jit_module
+            .declare_function(&name, Linkage::Export, &sig)
+            .unwrap();
+        self.jit_module
+            .prepare_for_function_redefine(func_id)
+            .unwrap();
+        define_forwarding_shim(&mut self.jit_module, func_id, sig, func_ptr);
+        self.jit_module.finalize_definitions();
+
+        func_ptr
+    }
+
+    /// Create a JIT module using an optimizing isa, which knows about all imported symbols and all
+    /// data objects of the lazy JIT module.
+    ///
+    /// Hotswapping is enabled, so the forwarding shims for functions of the lazy JIT module can be
+    /// redirected once these functions are compiled again.
+    fn new_optimized_module(&self, tcx: TyCtxt<'_>) -> JITModule {
+        let data_symbols = self
+            .jit_module
+            .declarations()
+            .get_data_objects()
+            .filter_map(|(_data_id, decl)| {
+                Some((
+                    decl.name.clone(),
+                    lookup_jit_symbol(&self.jit_module, &decl.name)?,
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let isa = crate::build_isa_with_opt_level(tcx.sess, OptLevel::Aggressive);
+        let mut jit_builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        jit_builder.hotswap(true);
+        jit_builder.symbols(
+            self.imported_symbols
+                .symbols
+                .iter()
+                .chain(self.asm_symbols.iter())
+                .map(|(name, &addr)| (name.clone(), addr))
+                .chain(data_symbols),
+        );
+        JITModule::new(jit_builder)
+    }
+
+    /// Replace the trampoline of a function with a shim forwarding to its cached code.
+    fn load_lazy_cached_fn<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        instance: Instance<'tcx>,
+        object: Vec<u8>,
+    ) -> *const u8 {
+        let name = tcx.symbol_name(instance).name;
+        let sig = get_function_sig(tcx, self.jit_module.isa().triple(), instance);
+        let func_id = self
+            .jit_module
+            .declare_function(name, Linkage::Export, &sig)
+            .unwrap();
+        self.jit_module
+            .prepare_for_function_redefine(func_id)
+            .unwrap();
+
+        let mut loaded_object = load_cached_fn(tcx, &mut self.jit_module, func_id, name, object);
+        self.jit_module.finalize_definitions();
+        relocate_loaded_object(
+            tcx,
+            &mut loaded_object,
+            &self.jit_module,
+            &self.imported_symbols,
+            &self.asm_symbols,
+        );
+        let init_functions = self.static_libs.link(
+            tcx,
+            loaded_object.undefined_symbols().to_vec(),
+            &self.jit_module,
+            &self.imported_symbols,
+            &self.asm_symbols,
+        );
+        run_late_init_functions(init_functions, &self.program_args);
+
+        loaded_object.symbols()[name]
+    }
+
+    /// Apply a reload session to the running program. New functions get a trampoline, new statics
+    /// are defined and changed functions which have been compiled already are recompiled. Changed
+    /// functions which haven't been compiled yet will be compiled by the reload session once they
+    /// are called. Returns the number of new and of recompiled functions.
+    fn apply_reload<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        old_snapshot: &CrateSnapshot,
+        new_snapshot: &CrateSnapshot,
+        mono_items: &[(MonoItem<'tcx>, (RLinkage, Visibility))],
+    ) -> (usize, usize) {
+        let mut cx = crate::CodegenCx::new(
+            tcx,
+            &mut self.jit_module,
+            tcx.sess.opts.debuginfo != DebugInfo::None,
+            false,
+        );
+
+        let mut new_fns = 0;
+        let mut changed_fns = Vec::new();
+        for &(mono_item, _) in mono_items {
+            match mono_item {
+                MonoItem::Fn(instance) => {
+                    let name = tcx.symbol_name(instance).name;
+                    if old_snapshot.is_new_fn(name) {
+                        let instance_ptr = codegen_shim(&mut cx, instance);
+                        self.lazy_fn_names.insert(instance_ptr, name.to_string());
+                        new_fns += 1;
+                    } else if old_snapshot.is_changed_fn(new_snapshot, name)
+                        && self.lazy_compiled_fns.contains_key(name)
+                    {
+                        changed_fns.push(instance);
+                    }
+                }
+                MonoItem::Static(def_id) => {
+                    let name = tcx.symbol_name(Instance::mono(tcx, def_id)).name;
+                    if old_snapshot.is_new_static(name) {
+                        crate::constant::codegen_static(&mut cx.constants_cx, def_id);
+                        self.optimized_module_outdated = true;
+                    }
+                }
+                // Changes to `global_asm!` are rejected by `CrateSnapshot::incompatible_changes`.
+                MonoItem::GlobalAsm(_) => {}
+            }
+        }
+
+        let (jit_module, global_asm, debug_context, unwind_context) = cx.finalize();
+        let init_functions = finalize_definitions(
+            tcx,
+            jit_module,
+            global_asm,
+            &self.imported_symbols,
+            &mut self.asm_symbols,
+            &mut self.static_libs,
+            Vec::new(),
+        );
+        run_late_init_functions(init_functions, &self.program_args);
+        if let Some(debug_context) = debug_context {
+            debug_context.register_jit(tcx, jit_module);
+        }
+        std::mem::forget(unsafe { unwind_context.register_jit(&jit_module) });
+
+        // The new trampolines have to be defined first, as the changed functions may call the new
+        // functions.
+        for &instance in &changed_fns {
+            let func_ptr = self.compile_lazy_fn(tcx, instance, LazyTier::Default);
+            self.set_compiled_fn(tcx.symbol_name(instance).name.to_string(), func_ptr);
+        }
+
+        (new_fns, changed_fns.len())
+    }
+}
+
+/// Count the calls to `func` in a new entry block and request optimized recompilation once
+/// `threshold` calls have been made.
+///
+/// The counter is incremented atomically, so exactly one call sees the count reach `threshold` and
+/// requests the recompilation, even when the function is called from several threads.
+fn insert_call_counter(
+    cx: &mut CodegenCx<'_, impl Module>,
+    func: &mut Function,
+    instance_ptr: *const Instance<'static>,
+    threshold: u32,
+) {
+    use cranelift_codegen::cursor::{Cursor, FuncCursor};
+    use cranelift_codegen::ir::AtomicRmwOp;
+
+    let pointer_type = cx.module.target_config().pointer_type();
+    let counter: &'static AtomicU32 = Box::leak(Box::new(AtomicU32::new(0)));
+
+    let tier_up = cx
+        .module
+        .declare_function(
+            "__clif_jit_tier_up",
+            Linkage::Import,
+            &Signature {
+                call_conv: cx.module.target_config().default_call_conv,
+                params: vec![AbiParam::new(pointer_type)],
+                returns: vec![],
+            },
+        )
+        .unwrap();
+    let tier_up = cx.module.declare_func_in_func(tier_up, func);
+
+    let old_entry_block = func.layout.entry_block().unwrap();
+    let entry_block = func.dfg.make_block();
+    let tier_up_block = func.dfg.make_block();
+    let params = func
+        .signature
+        .params
+        .iter()
+        .map(|param| param.value_type)
+        .collect::<Vec<_>>();
+    let args = params
+        .into_iter()
+        .map(|ty| func.dfg.append_block_param(entry_block, ty))
+        .collect::<Vec<_>>();
+
+    let mut cursor = FuncCursor::new(func);
+    cursor
+        .layout_mut()
+        .insert_block(entry_block, old_entry_block);
+    cursor.goto_bottom(entry_block);
+    let counter = cursor
+        .ins()
+        .iconst(pointer_type, counter as *const AtomicU32 as i64);
+    let one = cursor.ins().iconst(types::I32, 1);
+    let old_count = cursor.ins().atomic_rmw(
+        types::I32,
+        MemFlags::trusted(),
+        AtomicRmwOp::Add,
+        counter,
+        one,
+    );
+    let reached_threshold =
+        cursor
+            .ins()
+            .icmp_imm(IntCC::Equal, old_count, i64::from(threshold) - 1);
+    cursor.ins().brnz(reached_threshold, tier_up_block, &[]);
+    cursor.ins().jump(old_entry_block, &args);
+
+    cursor.insert_block(tier_up_block);
+    let instance_ptr = cursor.ins().iconst(pointer_type, instance_ptr as i64);
+    cursor.ins().call(tier_up, &[instance_ptr]);
+    cursor.ins().jump(old_entry_block, &args);
+}
+
+/// Called by the call counter of a function compiled by the tiered lazy JIT once the function has
+/// been called often enough. The function is recompiled in the background.
+#[no_mangle]
+extern "C" fn __clif_jit_tier_up(instance_ptr: *const Instance<'static>) {
+    LAZY_JIT_MESSAGE_SENDER
+        .get()
+        .expect("lazy JIT compile server not started")
+        .lock()
+        .unwrap()
+        .send(UnsafeMessage::TierUp { instance_ptr })
+        .expect("lazy JIT compile server exited");
 }
 
+/// Called by the trampoline of a function that hasn't been compiled yet, on any thread of the
+/// jitted program. Blocks until the compile server has compiled the function.
 #[no_mangle]
 extern "C" fn __clif_jit_fn(instance_ptr: *const Instance<'static>) -> *const u8 {
-    rustc_middle::ty::tls::with(|tcx| {
-        // lift is used to ensure the correct lifetime for instance.
-        let instance = tcx.lift(unsafe { *instance_ptr }).unwrap();
-
-        CURRENT_MODULE.with(|jit_module| {
-            let mut jit_module = jit_module.borrow_mut();
-            let jit_module = jit_module.as_mut().unwrap();
-            let mut cx = crate::CodegenCx::new(tcx, jit_module, false, false);
-
-            let name = tcx.symbol_name(instance).name.to_string();
-            let sig = crate::abi::get_function_sig(tcx, cx.module.isa().triple(), instance);
-            let func_id = cx
-                .module
-                .declare_function(&name, Linkage::Export, &sig)
-                .unwrap();
-            cx.module.prepare_for_function_redefine(func_id).unwrap();
+    let (tx, rx) = mpsc::channel();
+    LAZY_JIT_MESSAGE_SENDER
+        .get()
+        .expect("lazy JIT compile server not started")
+        .lock()
+        .unwrap()
+        .send(UnsafeMessage::JitFn { instance_ptr, tx })
+        .expect("lazy JIT compile server exited");
+    rx.recv().expect("lazy JIT compile server exited")
+}
 
-            tcx.sess.time("codegen fn", || {
-                crate::base::codegen_fn(&mut cx, instance, Linkage::Export)
-            });
+/// Finalize all functions defined in `jit_module` since the last call, after loading `global_asm`
+/// into memory. Afterwards all members of static libraries referenced by `jit_module`, the asm or
+/// `referenced_symbols` are linked. Returns the initializers of the newly linked members.
+fn finalize_definitions(
+    tcx: TyCtxt<'_>,
+    jit_module: &mut JITModule,
+    global_asm: String,
+    imported_symbols: &ImportedSymbols,
+    asm_symbols: &mut FxHashMap<String, *const u8>,
+    static_libs: &mut StaticLibs,
+    mut referenced_symbols: Vec<String>,
+) -> Vec<InitFunction> {
+    let loaded_object = GlobalAsm::new(tcx, global_asm).map(|global_asm| {
+        let object = global_asm
+            .assemble_to_vec()
+            .unwrap_or_else(|err| tcx.sess.fatal(&err));
+        let loaded_object = LoadedObject::load("<global_asm>".to_owned(), object)
+            .unwrap_or_else(|err| tcx.sess.fatal(&err));
+        asm_symbols.extend(
+            loaded_object
+                .symbols()
+                .iter()
+                .map(|(name, &addr)| (name.clone(), addr)),
+        );
+        loaded_object
+    });
+
+    if !asm_symbols.is_empty() {
+        define_asm_symbol_shims(jit_module, asm_symbols);
+    }
+
+    jit_module.finalize_definitions();
+
+    // The asm can only be relocated once the functions it references have been finalized.
+    if let Some(mut loaded_object) = loaded_object {
+        referenced_symbols.extend(loaded_object.undefined_symbols().iter().cloned());
+        loaded_object
+            .relocate(
+                |name| {
+                    asm_symbols
+                        .get(name)
+                        .copied()
+                        .or_else(|| lookup_jit_symbol(jit_module, name))
+                        .or_else(|| imported_symbols.symbols.get(name).copied())
+                        .or_else(|| lookup_process_symbol(name))
+                },
+                |name| imported_symbols.tls_symbols.get(name).copied(),
+            )
+            .unwrap_or_else(|err| tcx.sess.fatal(&err));
+    }
+
+    referenced_symbols.extend(imported_names(jit_module));
+    static_libs.link(
+        tcx,
+        referenced_symbols,
+        jit_module,
+        imported_symbols,
+        asm_symbols,
+    )
+}
+
+/// The names of all functions and data objects `jit_module` imports.
+fn imported_names(jit_module: &JITModule) -> Vec<String> {
+    let declarations = jit_module.declarations();
+    let functions = declarations
+        .get_functions()
+        .map(|(_func_id, decl)| (decl.linkage, &decl.name));
+    let data_objects = declarations
+        .get_data_objects()
+        .map(|(_data_id, decl)| (decl.linkage, &decl.name));
+    functions
+        .chain(data_objects)
+        .filter(|&(linkage, _name)| linkage == Linkage::Import)
+        .map(|(_linkage, name)| name.clone())
+        .collect()
+}
+
+/// Run the initializers of static library members which the lazy JIT only links once it compiles
+/// code referencing them. The arguments are leaked, as initializers like the one of libstd keep
+/// them.
+fn run_late_init_functions(init_functions: Vec<InitFunction>, program_args: &[CString]) {
+    if init_functions.is_empty() {
+        return;
+    }
+    let program_args = program_args.to_vec().leak();
+    let mut argv = program_args
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .collect::<Vec<_>>();
+    argv.push(std::ptr::null());
+    run_init_functions(
+        &init_functions,
+        program_args.len() as c_int,
+        argv.leak().as_ptr(),
+    );
+}
+
+/// Define a function forwarding to the asm symbol for every function which is imported by clif ir,
+/// but defined by `global_asm!` or `asm!`. [`JITModule`] only knows about the symbols passed to
+/// the [`JITBuilder`], so it can't resolve those imports by itself.
+fn define_asm_symbol_shims(jit_module: &mut JITModule, asm_symbols: &FxHashMap<String, *const u8>) {
+    let imports = jit_module
+        .declarations()
+        .get_functions()
+        .filter(|(_func_id, decl)| {
+            decl.linkage == Linkage::Import && asm_symbols.contains_key(&decl.name)
+        })
+        .map(|(func_id, decl)| (func_id, decl.name.clone(), decl.signature.clone()))
+        .collect::<Vec<_>>();
+
+    for (func_id, name, sig) in imports {
+        jit_module
+            .declare_function(&name, Linkage::Local, &sig)
+            .unwrap();
+        define_forwarding_shim(jit_module, func_id, sig, asm_symbols[&name]);
+    }
+}
+
+/// Define `func_id` as a function which calls `target` with all its arguments.
+fn define_forwarding_shim(
+    jit_module: &mut JITModule,
+    func_id: FuncId,
+    sig: Signature,
+    target: *const u8,
+) {
+    let pointer_type = jit_module.target_config().pointer_type();
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, func_id.as_u32()), sig.clone());
+    {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut bcx = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+
+        let block = bcx.create_block();
+        bcx.switch_to_block(block);
+        bcx.append_block_params_for_function_params(block);
+        let args = bcx.block_params(block).to_vec();
+
+        let sig_ref = bcx.import_signature(sig);
+        let callee = bcx.ins().iconst(pointer_type, target as i64);
+        let call_inst = bcx.ins().call_indirect(sig_ref, callee, &args);
+        let results = bcx.inst_results(call_inst).to_vec();
+        bcx.ins().return_(&results);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    jit_module
+        .define_function(
+            func_id,
+            &mut ctx,
+            &mut cranelift_codegen::binemit::NullTrapSink {},
+        )
+        .unwrap();
+}
+
+/// Load the object file of a function from the JIT code cache and define `func_id` as a shim
+/// forwarding to it. The object file has to be relocated using [`relocate_loaded_object`] once the
+/// shim has been finalized.
+fn load_cached_fn(
+    tcx: TyCtxt<'_>,
+    jit_module: &mut JITModule,
+    func_id: FuncId,
+    name: &str,
+    object: Vec<u8>,
+) -> LoadedObject {
+    let loaded_object = LoadedObject::load(format!("<JIT code cache entry for {}>", name), object)
+        .unwrap_or_else(|err| tcx.sess.fatal(&err));
+    let addr = *loaded_object.symbols().get(name).unwrap_or_else(|| {
+        tcx.sess.fatal(&format!(
+            "The JIT code cache entry for `{}` doesn't define it",
+            name
+        ))
+    });
+
+    let sig = jit_module
+        .declarations()
+        .get_function_decl(func_id)
+        .signature
+        .clone();
+    define_forwarding_shim(jit_module, func_id, sig, addr);
+
+    loaded_object
+}
+
+/// Relocate an object file loaded after the jitted crate has been finalized against the symbols of
+/// the jitted crate and all its dependencies.
+fn relocate_loaded_object(
+    tcx: TyCtxt<'_>,
+    loaded_object: &mut LoadedObject,
+    jit_module: &JITModule,
+    imported_symbols: &ImportedSymbols,
+    asm_symbols: &FxHashMap<String, *const u8>,
+) {
+    loaded_object
+        .relocate(
+            |name| {
+                imported_symbols
+                    .symbols
+                    .get(name)
+                    .copied()
+                    .or_else(|| asm_symbols.get(name).copied())
+                    .or_else(|| lookup_jit_symbol(jit_module, name))
+                    .or_else(|| lookup_process_symbol(name))
+            },
+            |name| imported_symbols.tls_symbols.get(name).copied(),
+        )
+        .unwrap_or_else(|err| tcx.sess.fatal(&err));
+}
 
-            let (jit_module, global_asm, _debug_context, unwind_context) = cx.finalize();
-            assert!(global_asm.is_empty());
-            jit_module.finalize_definitions();
-            std::mem::forget(unsafe { unwind_context.register_jit(&jit_module) });
-            jit_module.get_finalized_function(func_id)
+/// Define a shim forwarding to the latest code of every function of the lazy JIT module called by
+/// the optimized code in `opt_module`. [`JitState::set_compiled_fn`] redirects the
+/// shims once these functions are compiled again.
+fn define_lazy_fn_shims(
+    opt_module: &mut JITModule,
+    jit_module: &JITModule,
+    lazy_compiled_fns: &FxHashMap<String, *const u8>,
+) {
+    let imports = opt_module
+        .declarations()
+        .get_functions()
+        .filter(|(_func_id, decl)| decl.linkage == Linkage::Import)
+        .filter_map(|(func_id, decl)| {
+            let target = lazy_compiled_fns
+                .get(&decl.name)
+                .copied()
+                .or_else(|| lookup_jit_symbol(jit_module, &decl.name))?;
+            Some((func_id, decl.name.clone(), decl.signature.clone(), target))
         })
-    })
+        .collect::<Vec<_>>();
+
+    for (func_id, name, sig, target) in imports {
+        opt_module
+            .declare_function(&name, Linkage::Local, &sig)
+            .unwrap();
+        define_forwarding_shim(opt_module, func_id, sig, target);
+    }
+}
+
+/// Get the address of a function or data object defined and finalized in `jit_module`.
+fn lookup_jit_symbol(jit_module: &JITModule, name: &str) -> Option<*const u8> {
+    match jit_module.get_name(name)? {
+        FuncOrDataId::Func(func_id) => {
+            let decl = jit_module.declarations().get_function_decl(func_id);
+            if decl.linkage.is_definable() {
+                Some(jit_module.get_finalized_function(func_id))
+            } else {
+                None
+            }
+        }
+        FuncOrDataId::Data(data_id) => {
+            let decl = jit_module.declarations().get_data_decl(data_id);
+            if decl.linkage.is_definable() {
+                Some(jit_module.get_finalized_data(data_id).0)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Look up a symbol in the global symbol namespace of the current process.
+fn lookup_process_symbol(name: &str) -> Option<*const u8> {
+    let name = CString::new(name).ok()?;
+    let addr = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    if addr.is_null() {
+        None
+    } else {
+        Some(addr as *const u8)
+    }
 }
 
-fn load_imported_symbols_for_jit(tcx: TyCtxt<'_>) -> Vec<(String, *const u8)> {
+fn load_imported_symbols_for_jit(
+    tcx: TyCtxt<'_>,
+    crate_type: CrateType,
+) -> (ImportedSymbols, StaticLibs) {
     use rustc_middle::middle::dependency_format::Linkage;
 
     let mut dylib_paths = Vec::new();
+    let mut static_lib_paths = Vec::new();
+
+    // Static libraries are loaded using our own runtime linker, which only supports x86_64 ELF.
+    let can_load_static_libs = super::object_loader::can_load_objects(tcx);
+    let lib_search_paths = rustc_codegen_ssa::back::link::archive_search_paths(tcx.sess);
+    let native_static_libs = |native_libs: &[NativeLib], include_bundled: bool| {
+        let mut paths = Vec::new();
+        for native_lib in native_libs {
+            let is_static = match native_lib.kind {
+                NativeLibKind::StaticBundle => include_bundled,
+                NativeLibKind::StaticNoBundle => true,
+                _ => false,
+            };
+            let cfg_matches = native_lib.cfg.as_ref().map_or(true, |cfg| {
+                rustc_attr::cfg_matches(cfg, &tcx.sess.parse_sess, None)
+            });
+            if let (true, true, Some(name)) = (is_static, cfg_matches, native_lib.name) {
+                paths.push(rustc_codegen_ssa::back::archive::find_library(
+                    name,
+                    &lib_search_paths,
+                    tcx.sess,
+                ));
+            }
+        }
+        paths
+    };
 
     let crate_info = CrateInfo::new(tcx);
     let formats = tcx.dependency_formats(LOCAL_CRATE);
     let data = &formats
         .iter()
-        .find(|(crate_type, _data)| *crate_type == rustc_session::config::CrateType::Executable)
+        .find(|(ty, _data)| *ty == crate_type)
         .unwrap()
         .1;
     for &(cnum, _) in &crate_info.used_crates_dynamic {
         let src = &crate_info.used_crate_source[&cnum];
         match data[cnum.as_usize() - 1] {
             Linkage::NotLinked | Linkage::IncludedFromDylib => {}
+            Linkage::Static if can_load_static_libs => {
+                static_lib_paths.push(src.rlib.as_ref().unwrap().0.clone());
+                // Bundled native libraries are part of the rlib.
+                static_lib_paths.extend(native_static_libs(
+                    &crate_info.native_libraries[&cnum],
+                    false,
+                ));
+            }
             Linkage::Static => {
                 let name = tcx.crate_name(cnum);
                 let mut err = tcx
                     .sess
                     .struct_err(&format!("Can't load static lib {}", name.as_str()));
-                err.note("rustc_codegen_cranelift can only load dylibs in JIT mode.");
+                err.note("rustc_codegen_cranelift can only load static libs in JIT mode on x86_64 Linux.");
                 err.emit();
             }
             Linkage::Dynamic => {
@@ -206,38 +1653,104 @@ fn load_imported_symbols_for_jit(tcx: TyCtxt<'_>) -> Vec<(String, *const u8)> {
             }
         }
     }
+    if can_load_static_libs {
+        static_lib_paths.extend(native_static_libs(&crate_info.used_libraries, true));
+    }
 
-    let mut imported_symbols = Vec::new();
+    let mut imported_symbols = ImportedSymbols {
+        symbols: FxHashMap::default(),
+        tls_symbols: FxHashMap::default(),
+    };
     for path in dylib_paths {
         use object::{Object, ObjectSymbol};
         let lib = libloading::Library::new(&path).unwrap();
         let obj = std::fs::read(path).unwrap();
         let obj = object::File::parse(&obj).unwrap();
-        imported_symbols.extend(obj.dynamic_symbols().filter_map(|symbol| {
-            let name = symbol.name().unwrap().to_string();
-            if name.is_empty() || !symbol.is_global() || symbol.is_undefined() {
-                return None;
-            }
-            let dlsym_name = if cfg!(target_os = "macos") {
-                // On macOS `dlsym` expects the name without leading `_`.
-                assert!(name.starts_with('_'), "{:?}", name);
-                &name[1..]
-            } else {
-                &name
-            };
-            let symbol: libloading::Symbol<'_, *const u8> =
-                unsafe { lib.get(dlsym_name.as_bytes()) }.unwrap();
-            Some((name, *symbol))
-        }));
+        imported_symbols
+            .symbols
+            .extend(obj.dynamic_symbols().filter_map(|symbol| {
+                let name = symbol.name().unwrap().to_string();
+                if name.is_empty() || !symbol.is_global() || symbol.is_undefined() {
+                    return None;
+                }
+                let dlsym_name = if cfg!(target_os = "macos") {
+                    // On macOS `dlsym` expects the name without leading `_`.
+                    assert!(name.starts_with('_'), "{:?}", name);
+                    &name[1..]
+                } else {
+                    &name
+                };
+                let symbol: libloading::Symbol<'_, *const u8> =
+                    unsafe { lib.get(dlsym_name.as_bytes()) }.unwrap();
+                Some((name, *symbol))
+            }));
         std::mem::forget(lib)
     }
 
+    let members = load_static_libs(tcx, static_lib_paths);
+    let mut definitions = FxHashMap::default();
+    for (index, member) in members.iter().enumerate() {
+        // Like the static linker, use the first definition when a symbol is defined multiple
+        // times.
+        for (name, &addr) in member.symbols() {
+            if let Entry::Vacant(entry) = imported_symbols.symbols.entry(name.clone()) {
+                entry.insert(addr);
+                definitions.insert(name.clone(), index);
+            }
+        }
+        for (name, &tls_symbol) in member.tls_symbols() {
+            if let Entry::Vacant(entry) = imported_symbols.tls_symbols.entry(name.clone()) {
+                entry.insert(tls_symbol);
+                definitions.insert(name.clone(), index);
+            }
+        }
+    }
+    let static_libs = StaticLibs {
+        linked: vec![false; members.len()],
+        members,
+        definitions,
+    };
+
     tcx.sess.abort_if_errors();
 
-    imported_symbols
+    (imported_symbols, static_libs)
+}
+
+/// Place all object files in the given rlibs and static libraries in memory. They are only linked
+/// once referenced, see [`StaticLibs`].
+fn load_static_libs(tcx: TyCtxt<'_>, paths: Vec<PathBuf>) -> Vec<LoadedObject> {
+    let mut static_objects = Vec::new();
+    for path in paths {
+        let mut archive = ar::Archive::new(File::open(&path).unwrap_or_else(|err| {
+            tcx.sess
+                .fatal(&format!("Failed to open `{}`: {}", path.display(), err))
+        }));
+        while let Some(entry) = archive.next_entry() {
+            let mut entry = entry.unwrap_or_else(|err| {
+                tcx.sess
+                    .fatal(&format!("Failed to read `{}`: {}", path.display(), err))
+            });
+            let member_name = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+            if member_name == rustc_codegen_ssa::METADATA_FILENAME {
+                continue;
+            }
+
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            let name = format!("{}({})", path.display(), member_name);
+            static_objects
+                .push(LoadedObject::load(name, data).unwrap_or_else(|err| tcx.sess.fatal(&err)));
+        }
+    }
+    static_objects
 }
 
-pub(super) fn codegen_shim<'tcx>(cx: &mut CodegenCx<'tcx, impl Module>, inst: Instance<'tcx>) {
+/// Define a trampoline for `inst`, which requests the compilation of `inst` from the compile
+/// server once called. Returns the instance pointer passed to `__clif_jit_fn` by the trampoline.
+pub(super) fn codegen_shim<'tcx>(
+    cx: &mut CodegenCx<'tcx, impl Module>,
+    inst: Instance<'tcx>,
+) -> *const Instance<'static> {
     let tcx = cx.tcx;
 
     let pointer_type = cx.module.target_config().pointer_type();
@@ -249,7 +1762,7 @@ pub(super) fn codegen_shim<'tcx>(cx: &mut CodegenCx<'tcx, impl Module>, inst: In
         .declare_function(&name, Linkage::Export, &sig)
         .unwrap();
 
-    let instance_ptr = Box::into_raw(Box::new(inst));
+    let instance_ptr = Box::into_raw(Box::new(inst)) as *const Instance<'static>;
 
     let jit_fn = cx
         .module
@@ -282,10 +1795,10 @@ pub(super) fn codegen_shim<'tcx>(cx: &mut CodegenCx<'tcx, impl Module>, inst: In
         .to_vec();
 
     trampoline_builder.switch_to_block(entry_block);
-    let instance_ptr = trampoline_builder
+    let instance_ptr_val = trampoline_builder
         .ins()
         .iconst(pointer_type, instance_ptr as u64 as i64);
-    let jitted_fn = trampoline_builder.ins().call(jit_fn, &[instance_ptr]);
+    let jitted_fn = trampoline_builder.ins().call(jit_fn, &[instance_ptr_val]);
     let jitted_fn = trampoline_builder.func.dfg.inst_results(jitted_fn)[0];
     let call_inst = trampoline_builder
         .ins()
@@ -300,4 +1813,6 @@ pub(super) fn codegen_shim<'tcx>(cx: &mut CodegenCx<'tcx, impl Module>, inst: In
             &mut cranelift_codegen::binemit::NullTrapSink {},
         )
         .unwrap();
+
+    instance_ptr
 }
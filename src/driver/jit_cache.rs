@@ -0,0 +1,138 @@
+//! A persistent cache for the machine code of functions compiled in JIT mode, enabled using
+//! `CG_CLIF_JIT_CACHE_DIR`.
+//!
+//! Every cached function is stored as a relocatable object file containing the function and all
+//! anonymous data it references, like in AOT mode. All other symbols are referenced by name, so the
+//! object can be loaded the same way as the object files of static libraries.
+//!
+//! The cache key consists of the symbol name of the function, a stable hash of its MIR and of
+//! everything the code depends on which is not part of the MIR, like the layout of all types it
+//! uses, its callees, vtables and drop glue, see [`hash_instance`]. The target isa, opt level,
+//! backend config and cg_clif build are part of the key too, see [`codegen_config`].
+
+use std::hash::Hash;
+use std::path::PathBuf;
+
+use rustc_data_structures::fingerprint::Fingerprint;
+use rustc_data_structures::stable_hasher::StableHasher;
+use rustc_session::config::DebugInfo;
+
+use crate::prelude::*;
+
+use super::fn_cache::{codegen_config, hash_instance};
+
+pub(super) struct JitCache {
+    dir: PathBuf,
+    triple: target_lexicon::Triple,
+    /// See [`codegen_config`].
+    config: String,
+}
+
+impl JitCache {
+    /// Open the cache in `CG_CLIF_JIT_CACHE_DIR`, if set and supported.
+    pub(super) fn new(tcx: TyCtxt<'_>, config: &crate::BackendConfig) -> Option<Self> {
+        let dir = PathBuf::from(std::env::var_os("CG_CLIF_JIT_CACHE_DIR")?);
+
+        if !super::object_loader::can_load_objects(tcx) {
+            tcx.sess
+                .warn("The JIT code cache is only supported on x86_64 ELF targets");
+            return None;
+        }
+        // Cached functions are loaded without debuginfo.
+        if tcx.sess.opts.debuginfo != DebugInfo::None {
+            tcx.sess
+                .warn("The JIT code cache is disabled when debuginfo is enabled");
+            return None;
+        }
+
+        if let Err(err) = std::fs::create_dir_all(&dir) {
+            tcx.sess.fatal(&format!(
+                "Failed to create JIT code cache `{}`: {}",
+                dir.display(),
+                err
+            ));
+        }
+
+        let isa = crate::build_isa(tcx.sess);
+        Some(JitCache {
+            dir,
+            triple: isa.triple().clone(),
+            config: codegen_config(tcx, &*isa, config),
+        })
+    }
+
+    /// Get the object file containing `instance` from the cache or compile and cache it.
+    ///
+    /// Returns `None` if the function can't be cached. In that case it has to be compiled into the
+    /// JIT module as usual.
+    pub(super) fn load_or_compile<'tcx>(
+        &mut self,
+        tcx: TyCtxt<'tcx>,
+        instance: Instance<'tcx>,
+    ) -> Option<Vec<u8>> {
+        let path = self.dir.join(format!("{}.o", self.key(tcx, instance)));
+        if let Ok(object) = std::fs::read(&path) {
+            return Some(object);
+        }
+
+        let object = tcx.sess.time("codegen fn for JIT code cache", || {
+            compile_to_object(tcx, instance)
+        })?;
+
+        // Don't cache code for erroneous constants. Compilation will be aborted anyway.
+        if tcx.sess.has_errors() {
+            return Some(object);
+        }
+
+        // Write to a temporary file first, so other rustc processes never read a partially
+        // written object file. Failing to write the cache is not an error.
+        let tmp_path = path.with_extension(format!("o.tmp{}", std::process::id()));
+        if let Err(err) =
+            std::fs::write(&tmp_path, &object).and_then(|()| std::fs::rename(&tmp_path, &path))
+        {
+            tcx.sess.warn(&format!(
+                "Failed to write `{}` to the JIT code cache: {}",
+                path.display(),
+                err
+            ));
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+
+        Some(object)
+    }
+
+    fn key<'tcx>(&self, tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> String {
+        let mut hasher = StableHasher::new();
+        self.config.hash(&mut hasher);
+        hash_instance(tcx, &self.triple, instance).hash(&mut hasher);
+        let hash: Fingerprint = hasher.finish();
+        hash.to_hex()
+    }
+}
+
+/// Compile `instance` into a new object file the same way as in AOT mode.
+fn compile_to_object<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> Option<Vec<u8>> {
+    let name = tcx.symbol_name(instance).name.to_string();
+    let module = crate::backend::make_module(tcx.sess, name);
+    let mut cx = crate::CodegenCx::new(tcx, module, false, true);
+
+    // The function is only referenced by name, so it has to be exported from the object file.
+    crate::base::codegen_and_compile_fn(&mut cx, instance, Linkage::Export);
+
+    let (module, global_asm, _debug, unwind_context) = cx.finalize();
+
+    // Inline asm is loaded separately by the JIT and thread locals of the jitted crate can't be
+    // referenced by loaded object files.
+    if !global_asm.is_empty()
+        || module
+            .declarations()
+            .get_data_objects()
+            .any(|(_data_id, decl)| decl.tls)
+    {
+        return None;
+    }
+
+    let mut product = module.finish();
+    unwind_context.emit(&mut product);
+    Some(product.object.write().unwrap())
+}
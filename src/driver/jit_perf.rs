@@ -0,0 +1,240 @@
+//! Support for profiling jitted code using `perf`. Depending on `CG_CLIF_JIT_PERF` every jitted
+//! function is written to a [perf map](https://github.com/torvalds/linux/blob/master/tools/perf/Documentation/jit-interface.txt)
+//! and/or a [jitdump file](https://github.com/torvalds/linux/blob/master/tools/perf/Documentation/jitdump-specification.txt).
+//!
+//! The perf map only contains the name, address and size of every function and is picked up by
+//! `perf report` automatically. The jitdump file additionally contains the machine code and line
+//! info, but the recorded profile has to be processed with `perf inject --jit` first.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use rustc_span::SourceFileAndLine;
+
+use crate::base::CompiledFunction;
+use crate::prelude::*;
+
+const JITDUMP_MAGIC: u32 = 0x4A695444;
+const JITDUMP_VERSION: u32 = 1;
+const JITDUMP_HEADER_SIZE: u32 = 40;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+pub(super) struct PerfJitListener {
+    perf_map: Option<BufWriter<File>>,
+    jitdump: Option<JitDump>,
+}
+
+struct JitDump {
+    file: BufWriter<File>,
+    next_code_index: u64,
+}
+
+impl PerfJitListener {
+    /// Create the files requested using `CG_CLIF_JIT_PERF`, if any.
+    pub(super) fn new(tcx: TyCtxt<'_>) -> Option<Self> {
+        let kinds = std::env::var("CG_CLIF_JIT_PERF").ok()?;
+
+        let mut listener = PerfJitListener {
+            perf_map: None,
+            jitdump: None,
+        };
+        let pid = std::process::id();
+        for kind in kinds.split(',') {
+            match kind {
+                "map" => {
+                    let path = PathBuf::from(format!("/tmp/perf-{}.map", pid));
+                    listener.perf_map = Some(BufWriter::new(create_file(tcx, &path, true)));
+                }
+                "jitdump" => {
+                    let path = PathBuf::from(format!("/tmp/jit-{}.dump", pid));
+                    let file = create_file(tcx, &path, false);
+                    listener.jitdump = Some(JitDump::new(tcx, file, pid));
+                }
+                _ => tcx.sess.fatal(&format!(
+                    "Unknown value `{}` for CG_CLIF_JIT_PERF. Expected `map`, `jitdump` or \
+                     `map,jitdump`.",
+                    kind,
+                )),
+            }
+        }
+
+        Some(listener)
+    }
+
+    /// Record all given functions, which must have been finalized before. `name` is the name
+    /// profilers should show for the function.
+    pub(super) fn register_functions(
+        &mut self,
+        tcx: TyCtxt<'_>,
+        jit_module: &cranelift_jit::JITModule,
+        functions: &[(String, CompiledFunction)],
+    ) {
+        for (name, function) in functions {
+            let addr = jit_module.get_finalized_function(function.func_id);
+            if let Some(perf_map) = &mut self.perf_map {
+                writeln!(perf_map, "{:x} {:x} {}", addr as usize, function.size, name).unwrap();
+            }
+            if let Some(jitdump) = &mut self.jitdump {
+                jitdump.write_function(tcx, name, addr, function);
+            }
+        }
+
+        // Make sure everything has been written when the jitted program exits the process.
+        if let Some(perf_map) = &mut self.perf_map {
+            perf_map.flush().unwrap();
+        }
+        if let Some(jitdump) = &mut self.jitdump {
+            jitdump.file.flush().unwrap();
+        }
+    }
+}
+
+fn create_file(tcx: TyCtxt<'_>, path: &PathBuf, append: bool) -> File {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .unwrap_or_else(|err| {
+            tcx.sess
+                .fatal(&format!("Failed to create `{}`: {}", path.display(), err))
+        })
+}
+
+/// The timestamp of jitdump records. `perf record` must be passed `-k mono` to use the same clock.
+fn timestamp() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+impl JitDump {
+    fn new(tcx: TyCtxt<'_>, file: File, pid: u32) -> Self {
+        // `perf record` only notices the jitdump file when it gets mapped as executable. The
+        // mapping is kept until the process exits.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                std::os::unix::io::AsRawFd::as_raw_fd(&file),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            tcx.sess.fatal(&format!(
+                "Failed to map jitdump file: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        let elf_mach = match &*tcx.sess.target.arch {
+            "x86" => object::elf::EM_386,
+            "x86_64" => object::elf::EM_X86_64,
+            "arm" => object::elf::EM_ARM,
+            "aarch64" => object::elf::EM_AARCH64,
+            _ => object::elf::EM_NONE,
+        };
+
+        let mut file = BufWriter::new(file);
+        file.write_all(&JITDUMP_MAGIC.to_ne_bytes()).unwrap();
+        file.write_all(&JITDUMP_VERSION.to_ne_bytes()).unwrap();
+        file.write_all(&JITDUMP_HEADER_SIZE.to_ne_bytes()).unwrap();
+        file.write_all(&u32::from(elf_mach).to_ne_bytes()).unwrap();
+        file.write_all(&0u32.to_ne_bytes()).unwrap(); // pad1
+        file.write_all(&pid.to_ne_bytes()).unwrap();
+        file.write_all(&timestamp().to_ne_bytes()).unwrap();
+        file.write_all(&0u64.to_ne_bytes()).unwrap(); // flags
+
+        JitDump {
+            file,
+            next_code_index: 0,
+        }
+    }
+
+    fn write_record_header(&mut self, id: u32, total_size: usize) {
+        self.file.write_all(&id.to_ne_bytes()).unwrap();
+        self.file
+            .write_all(&(total_size as u32).to_ne_bytes())
+            .unwrap();
+        self.file.write_all(&timestamp().to_ne_bytes()).unwrap();
+    }
+
+    fn write_function(
+        &mut self,
+        tcx: TyCtxt<'_>,
+        name: &str,
+        addr: *const u8,
+        function: &CompiledFunction,
+    ) {
+        const RECORD_HEADER_SIZE: usize = 16;
+
+        // The line info must precede the code it belongs to.
+        let lines = function
+            .source_spans
+            .iter()
+            .filter_map(
+                |&(offset, span)| match tcx.sess.source_map().lookup_line(span.lo()) {
+                    Ok(SourceFileAndLine { sf: file, line }) => {
+                        Some((offset, file.name.to_string(), line as u32 + 1))
+                    }
+                    Err(_) => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        if !lines.is_empty() {
+            let entries_size = lines
+                .iter()
+                .map(|(_, file_name, _)| 8 + 4 + 4 + file_name.len() + 1)
+                .sum::<usize>();
+            self.write_record_header(
+                JIT_CODE_DEBUG_INFO,
+                RECORD_HEADER_SIZE + 8 + 8 + entries_size,
+            );
+            self.file.write_all(&(addr as u64).to_ne_bytes()).unwrap();
+            self.file
+                .write_all(&(lines.len() as u64).to_ne_bytes())
+                .unwrap();
+            for (offset, file_name, line) in lines {
+                self.file
+                    .write_all(&(addr as u64 + u64::from(offset)).to_ne_bytes())
+                    .unwrap();
+                self.file.write_all(&line.to_ne_bytes()).unwrap();
+                self.file.write_all(&0u32.to_ne_bytes()).unwrap(); // discriminator
+                self.file.write_all(file_name.as_bytes()).unwrap();
+                self.file.write_all(&[0]).unwrap();
+            }
+        }
+
+        let code = unsafe { std::slice::from_raw_parts(addr, function.size as usize) };
+        self.write_record_header(
+            JIT_CODE_LOAD,
+            RECORD_HEADER_SIZE + 4 + 4 + 8 + 8 + 8 + 8 + name.len() + 1 + code.len(),
+        );
+        let pid = std::process::id();
+        self.file.write_all(&pid.to_ne_bytes()).unwrap();
+        // All functions are compiled on the rustc thread, but perf only needs a thread of the
+        // process.
+        self.file.write_all(&pid.to_ne_bytes()).unwrap();
+        self.file.write_all(&(addr as u64).to_ne_bytes()).unwrap(); // vma
+        self.file.write_all(&(addr as u64).to_ne_bytes()).unwrap(); // code_addr
+        self.file
+            .write_all(&(code.len() as u64).to_ne_bytes())
+            .unwrap();
+        self.file
+            .write_all(&self.next_code_index.to_ne_bytes())
+            .unwrap();
+        self.next_code_index += 1;
+        self.file.write_all(name.as_bytes()).unwrap();
+        self.file.write_all(&[0]).unwrap();
+        self.file.write_all(code).unwrap();
+    }
+}
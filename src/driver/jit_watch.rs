@@ -0,0 +1,257 @@
+//! Support for the watch mode of the lazy JIT, enabled using `-Cllvm-args=jit-watch`.
+//!
+//! The compile server polls the source files of the jitted crate. Once one of them changes, a new
+//! rustc session is started on a separate thread. This session runs the analysis of the changed
+//! crate, compares its mono items with those of the running program and takes over as compile
+//! server, redefining all changed functions in the live [`JITModule`](cranelift_jit::JITModule).
+
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::SystemTime;
+
+use rustc_data_structures::fingerprint::Fingerprint;
+use rustc_interface::interface;
+use rustc_middle::mir::mono::MonoItem;
+use rustc_session::Session;
+use rustc_span::FileName;
+
+use crate::prelude::*;
+
+/// Watches the source files of the jitted crate and starts a reload session once they change.
+pub(super) struct SourceWatcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    /// Disconnected once the reload session started last has finished.
+    pending_reload: Option<mpsc::Receiver<()>>,
+}
+
+impl SourceWatcher {
+    /// Watch all local source files of the current session. `previous` is the watcher of the
+    /// session this session is reloading, if any. Files it already watched keep the modification
+    /// time it last saw, so changes made while this session was running are not missed.
+    pub(super) fn new(sess: &Session, previous: Option<&SourceWatcher>) -> Self {
+        let files = sess
+            .source_map()
+            .files()
+            .iter()
+            .filter(|file| !file.is_imported())
+            .filter_map(|file| match &file.name {
+                FileName::Real(name) => Some(name.local_path().to_path_buf()),
+                _ => None,
+            })
+            .map(|path| {
+                let mtime = previous
+                    .and_then(|previous| previous.files.iter().find(|(p, _)| *p == path))
+                    .map(|&(_, mtime)| mtime)
+                    .unwrap_or_else(|| modification_time(&path));
+                (path, mtime)
+            })
+            .collect();
+
+        SourceWatcher {
+            files,
+            pending_reload: None,
+        }
+    }
+
+    /// Start a reload session if any source file changed since the last call and no reload
+    /// session is running already.
+    pub(super) fn poll(&mut self, sess: &Session) {
+        if let Some(pending_reload) = &self.pending_reload {
+            match pending_reload.try_recv() {
+                Err(mpsc::TryRecvError::Disconnected) => self.pending_reload = None,
+                Ok(()) | Err(mpsc::TryRecvError::Empty) => return,
+            }
+        }
+
+        let mut changed = false;
+        for (path, mtime) in &mut self.files {
+            let new_mtime = modification_time(path);
+            if new_mtime != *mtime {
+                *mtime = new_mtime;
+                changed = true;
+            }
+        }
+
+        if changed {
+            sess.note_without_error("Source files changed, reloading the jitted program");
+            self.pending_reload = Some(start_reload_session(sess));
+        }
+    }
+
+    /// Forget about the running reload session, as it has given up on reloading.
+    pub(super) fn reload_rejected(&mut self) {
+        self.pending_reload = None;
+    }
+}
+
+fn modification_time(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Start a new rustc session with the arguments of the current process on a separate thread. The
+/// returned receiver is disconnected once the session has finished.
+fn start_reload_session(sess: &Session) -> mpsc::Receiver<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let mut callbacks = ReloadCallbacks {
+        sysroot: sess.sysroot.clone(),
+        panic: sess.opts.cg.panic,
+        panic_abort_tests: sess.opts.debugging_opts.panic_abort_tests,
+    };
+
+    let (done_tx, done_rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("jit reload".to_string())
+        .spawn(move || {
+            let _done_tx = done_tx;
+            // Errors have already been reported by the session.
+            let _exit_code = rustc_driver::catch_with_exit_code(|| {
+                let mut run_compiler = rustc_driver::RunCompiler::new(&args, &mut callbacks);
+                run_compiler.set_make_codegen_backend(Some(Box::new(|_| {
+                    Box::new(crate::CraneliftCodegenBackend::default())
+                })));
+                run_compiler.run()
+            });
+        })
+        .unwrap();
+    done_rx
+}
+
+/// Applies the options the rustc driver which started the original session set on top of the
+/// command line arguments.
+struct ReloadCallbacks {
+    sysroot: PathBuf,
+    panic: Option<rustc_target::spec::PanicStrategy>,
+    panic_abort_tests: bool,
+}
+
+impl rustc_driver::Callbacks for ReloadCallbacks {
+    fn config(&mut self, config: &mut interface::Config) {
+        config.opts.maybe_sysroot = Some(self.sysroot.clone());
+        config.opts.cg.panic = self.panic;
+        config.opts.debugging_opts.panic_abort_tests = self.panic_abort_tests;
+    }
+}
+
+/// Everything about the jitted crate which decides whether a reload session can be applied to the
+/// running program and which functions it has to redefine.
+pub(super) struct CrateSnapshot {
+    /// The signature and [`hash_instance`](super::fn_cache::hash_instance) of every function,
+    /// keyed by symbol name.
+    fns: FxHashMap<String, (String, Fingerprint)>,
+    /// The name and layout of every static, keyed by symbol name.
+    statics: FxHashMap<String, (String, String)>,
+    /// The layout of every type used by any function.
+    type_layouts: FxHashMap<String, String>,
+    global_asm: String,
+}
+
+impl CrateSnapshot {
+    pub(super) fn new<'tcx>(tcx: TyCtxt<'tcx>, mono_items: &[MonoItem<'tcx>]) -> Self {
+        let triple = crate::target_triple(tcx.sess);
+
+        let mut snapshot = CrateSnapshot {
+            fns: FxHashMap::default(),
+            statics: FxHashMap::default(),
+            type_layouts: FxHashMap::default(),
+            global_asm: String::new(),
+        };
+        for &mono_item in mono_items {
+            match mono_item {
+                MonoItem::Fn(instance) => {
+                    let sig = get_function_sig(tcx, &triple, instance).to_string();
+                    let hash = super::fn_cache::hash_instance(tcx, &triple, instance);
+                    snapshot
+                        .fns
+                        .insert(tcx.symbol_name(instance).name.to_string(), (sig, hash));
+
+                    let mir = tcx.instance_mir(instance.def);
+                    for local_decl in &mir.local_decls {
+                        let ty = instance.subst_mir_and_normalize_erasing_regions(
+                            tcx,
+                            ParamEnv::reveal_all(),
+                            local_decl.ty,
+                        );
+                        snapshot.add_type_layouts(tcx, ty);
+                    }
+                }
+                MonoItem::Static(def_id) => {
+                    let instance = Instance::mono(tcx, def_id);
+                    let layout = tcx
+                        .layout_of(ParamEnv::reveal_all().and(tcx.type_of(def_id)))
+                        .map(|layout| layout.layout);
+                    snapshot.statics.insert(
+                        tcx.symbol_name(instance).name.to_string(),
+                        (tcx.def_path_str(def_id), format!("{:?}", layout)),
+                    );
+                }
+                MonoItem::GlobalAsm(hir_id) => {
+                    super::global_asm::codegen_global_asm_item(
+                        tcx,
+                        &mut snapshot.global_asm,
+                        hir_id,
+                    );
+                }
+            }
+        }
+        snapshot
+    }
+
+    /// Record the layout of `ty` and of all types it references, like the pointee of a reference.
+    fn add_type_layouts<'tcx>(&mut self, tcx: TyCtxt<'tcx>, ty: Ty<'tcx>) {
+        for arg in ty.walk() {
+            if let ty::subst::GenericArgKind::Type(ty) = arg.unpack() {
+                if let Ok(layout) = tcx.layout_of(ParamEnv::reveal_all().and(ty)) {
+                    self.type_layouts
+                        .entry(ty.to_string())
+                        .or_insert_with(|| format!("{:?}", layout.layout));
+                }
+            }
+        }
+    }
+
+    /// Describe every change between `self` and `new` which can't be applied to the running
+    /// program, as code or data which is still in use depends on it.
+    pub(super) fn incompatible_changes(&self, new: &CrateSnapshot) -> Vec<String> {
+        let mut changes = Vec::new();
+        for (ty, layout) in &new.type_layouts {
+            if matches!(self.type_layouts.get(ty), Some(old_layout) if old_layout != layout) {
+                changes.push(format!("the layout of `{}` changed", ty));
+            }
+        }
+        for (symbol, (name, layout)) in &new.statics {
+            if matches!(self.statics.get(symbol), Some((_, old_layout)) if old_layout != layout) {
+                changes.push(format!("the layout of static `{}` changed", name));
+            }
+        }
+        for (symbol, (sig, _)) in &new.fns {
+            if matches!(self.fns.get(symbol), Some((old_sig, _)) if old_sig != sig) {
+                changes.push(format!("the signature of `{}` changed", symbol));
+            }
+        }
+        if self.global_asm != new.global_asm {
+            changes.push("`global_asm!` changed".to_string());
+        }
+        changes.sort();
+        changes
+    }
+
+    /// Whether the function `symbol` of `new` didn't exist in `self`.
+    pub(super) fn is_new_fn(&self, symbol: &str) -> bool {
+        !self.fns.contains_key(symbol)
+    }
+
+    /// Whether the function `symbol` of `new` exists in `self`, but has changed.
+    pub(super) fn is_changed_fn(&self, new: &CrateSnapshot, symbol: &str) -> bool {
+        matches!(
+            (self.fns.get(symbol), new.fns.get(symbol)),
+            (Some((_, old_hash)), Some((_, new_hash))) if old_hash != new_hash
+        )
+    }
+
+    /// Whether the static `symbol` of `new` didn't exist in `self`.
+    pub(super) fn is_new_static(&self, symbol: &str) -> bool {
+        !self.statics.contains_key(symbol)
+    }
+}
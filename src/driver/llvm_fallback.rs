@@ -0,0 +1,76 @@
+//! Compiles codegen units which use something cg_clif doesn't support using the LLVM backend,
+//! enabled using `CG_CLIF_LLVM_FALLBACK=1`.
+//!
+//! Unsupported constructs are detected while lowering a codegen unit to clif ir, see
+//! [`CodegenCx::unsupported`](crate::CodegenCx::unsupported). Only the affected codegen units are
+//! then compiled by `rustc_codegen_llvm` and the resulting object files are linked together with
+//! those of cg_clif.
+
+use rustc_codegen_llvm::LlvmCodegenBackend;
+use rustc_codegen_ssa::back::write::{start_async_codegen, submit_codegened_module_to_llvm};
+use rustc_codegen_ssa::traits::{CodegenBackend, ExtraBackendMethods};
+use rustc_codegen_ssa::CompiledModule;
+use rustc_middle::dep_graph::{WorkProduct, WorkProductId};
+use rustc_middle::middle::cstore::EncodedMetadata;
+use rustc_span::Symbol;
+
+use crate::prelude::*;
+
+/// Compile `cgus` using the LLVM backend. The compiled modules are returned in the same order.
+///
+/// Like `rustc_codegen_ssa::base::codegen_crate` does for all codegen units, every codegen unit
+/// is lowered to LLVM ir on the rustc thread and then optimized and written by the LLVM worker
+/// threads. The object files are written to the paths cg_clif uses, so cg_clif must not have
+/// written object files for `cgus`.
+pub(super) fn codegen_cgus(
+    tcx: TyCtxt<'_>,
+    metadata: &EncodedMetadata,
+    cgus: &[Symbol],
+    work_products: &mut FxHashMap<WorkProductId, WorkProduct>,
+) -> Vec<CompiledModule> {
+    if cgus.is_empty() {
+        return Vec::new();
+    }
+
+    // `init` passes all `-Cllvm-args` to LLVM, which rejects the options of cg_clif.
+    if !tcx.sess.opts.cg.llvm_args.is_empty() {
+        tcx.sess
+            .fatal("compiling using the LLVM backend can't be combined with `-Cllvm-args`");
+    }
+    let backend = LlvmCodegenBackend::new();
+    backend.init(tcx.sess);
+
+    let ongoing_codegen = start_async_codegen(
+        backend.clone(),
+        tcx,
+        EncodedMetadata {
+            raw_data: metadata.raw_data.clone(),
+        },
+        cgus.len(),
+    );
+    for &cgu_name in cgus {
+        ongoing_codegen.wait_for_signal_to_codegen_item();
+        ongoing_codegen.check_for_errors(tcx.sess);
+
+        let (module, cost) = backend.compile_codegen_unit(tcx, cgu_name);
+        submit_codegened_module_to_llvm(&backend, &ongoing_codegen.coordinator_send, module, cost);
+    }
+    ongoing_codegen.codegen_finished(tcx);
+    ongoing_codegen.check_for_errors(tcx.sess);
+    let (codegen_results, mut llvm_work_products) = ongoing_codegen.join(tcx.sess);
+
+    let mut modules = codegen_results
+        .modules
+        .into_iter()
+        .map(|module| (module.name.clone(), module))
+        .collect::<FxHashMap<_, _>>();
+    cgus.iter()
+        .map(|cgu_name| {
+            let work_product_id = WorkProductId::from_cgu_name(&cgu_name.as_str());
+            if let Some(work_product) = llvm_work_products.remove(&work_product_id) {
+                work_products.insert(work_product_id, work_product);
+            }
+            modules.remove(&*cgu_name.as_str()).unwrap()
+        })
+        .collect()
+}
@@ -10,8 +10,27 @@
 use crate::CodegenMode;
 
 mod aot;
+mod bisect;
+mod fn_cache;
+mod global_asm;
 #[cfg(feature = "jit")]
 mod jit;
+#[cfg(feature = "jit")]
+mod jit_cache;
+#[cfg(feature = "jit")]
+mod jit_perf;
+#[cfg(feature = "jit")]
+mod jit_watch;
+#[cfg(feature = "llvm_fallback")]
+mod llvm_fallback;
+#[cfg(feature = "jit")]
+mod object_loader;
+mod unsupported_report;
+mod worker_pool;
+
+pub(crate) use bisect::{is_bisected_crate, should_inject_trap};
+#[cfg(feature = "jit")]
+pub use jit::{decode_jit_test_rustc_args, JitHandle, JIT_TEST_RUSTC_ARGS_VAR};
 
 pub(crate) fn codegen_crate(
     tcx: TyCtxt<'_>,
@@ -22,7 +41,7 @@ pub(crate) fn codegen_crate(
     tcx.sess.abort_if_errors();
 
     match config.codegen_mode {
-        CodegenMode::Aot => aot::run_aot(tcx, metadata, need_metadata_module),
+        CodegenMode::Aot => aot::run_aot(tcx, metadata, need_metadata_module, config),
         CodegenMode::Jit | CodegenMode::JitLazy => {
             let is_executable = tcx
                 .sess
@@ -33,7 +52,14 @@ pub(crate) fn codegen_crate(
             }
 
             #[cfg(feature = "jit")]
-            let _: ! = jit::run_jit(tcx, config.codegen_mode);
+            {
+                if jit::is_reload_session() {
+                    jit::reload_jit(tcx);
+                    return empty_codegen_results(tcx, metadata);
+                }
+
+                let _: ! = jit::run_jit(tcx, config);
+            }
 
             #[cfg(not(feature = "jit"))]
             tcx.sess
@@ -42,6 +68,58 @@ pub(crate) fn codegen_crate(
     }
 }
 
+/// Whether the current session reloads the crate run by the lazy JIT in watch mode. Such a session
+/// doesn't produce anything to link.
+pub(crate) fn is_jit_reload_session() -> bool {
+    #[cfg(feature = "jit")]
+    {
+        jit::is_reload_session()
+    }
+
+    #[cfg(not(feature = "jit"))]
+    {
+        false
+    }
+}
+
+/// JIT compile the local crate as library and pass it to `callback` instead of writing any object
+/// files. The returned codegen results are empty, as there is nothing to link.
+#[cfg(feature = "jit")]
+pub(crate) fn jit_library(
+    tcx: TyCtxt<'_>,
+    metadata: EncodedMetadata,
+    config: crate::BackendConfig,
+    callback: &(dyn Fn(JitHandle) + Send + Sync),
+) -> Box<dyn Any> {
+    tcx.sess.abort_if_errors();
+
+    callback(jit::jit_library(tcx, config));
+
+    empty_codegen_results(tcx, metadata)
+}
+
+/// The codegen results of a session which doesn't produce anything to link.
+#[cfg(feature = "jit")]
+fn empty_codegen_results(tcx: TyCtxt<'_>, metadata: EncodedMetadata) -> Box<dyn Any> {
+    use rustc_codegen_ssa::back::linker::LinkerInfo;
+    use rustc_codegen_ssa::{CodegenResults, CrateInfo};
+    use rustc_middle::dep_graph::{WorkProduct, WorkProductId};
+
+    Box::new((
+        CodegenResults {
+            crate_name: tcx.crate_name(LOCAL_CRATE),
+            modules: vec![],
+            allocator_module: None,
+            metadata_module: None,
+            metadata,
+            windows_subsystem: None,
+            linker_info: LinkerInfo::new(tcx),
+            crate_info: CrateInfo::new(tcx),
+        },
+        FxHashMap::<WorkProductId, WorkProduct>::default(),
+    ))
+}
+
 fn predefine_mono_items<'tcx>(
     cx: &mut crate::CodegenCx<'tcx, impl Module>,
     mono_items: &[(MonoItem<'tcx>, (RLinkage, Visibility))],
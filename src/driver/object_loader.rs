@@ -0,0 +1,780 @@
+//! A minimal runtime linker for the JIT mode. It loads relocatable object files, like those produced
+//! by assembling `global_asm!` and `asm!` or the members of static libraries, into memory and
+//! relocates them against symbols provided by the JIT driver.
+//!
+//! Only x86_64 ELF objects are supported.
+
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_int};
+
+use object::elf::{
+    R_X86_64_DTPMOD64, R_X86_64_DTPOFF32, R_X86_64_DTPOFF64, R_X86_64_GOTPCRELX, R_X86_64_GOTTPOFF,
+    R_X86_64_PC64, R_X86_64_REX_GOTPCRELX, R_X86_64_TLSGD, R_X86_64_TLSLD, R_X86_64_TPOFF32,
+    R_X86_64_TPOFF64,
+};
+use object::{
+    Architecture, BinaryFormat, Object, ObjectKind, ObjectSection, ObjectSymbol, Relocation,
+    RelocationEncoding, RelocationKind, RelocationTarget, SectionIndex, SectionKind, SymbolIndex,
+    SymbolSection,
+};
+
+use crate::prelude::*;
+
+/// `jmp qword ptr [rip + 0]` followed by the 8 byte jump target.
+const STUB_SIZE: usize = 16;
+const STUB_CODE: [u8; 6] = [0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
+
+const GOT_ENTRY_SIZE: usize = 8;
+
+/// A `tls_index` as passed to `__tls_get_addr` takes up two GOT entries.
+const TLS_INDEX_SIZE: usize = 2 * GOT_ENTRY_SIZE;
+
+/// The terminator appended to every `.eh_frame` section, as `__register_frame` expects it.
+const EH_FRAME_TERMINATOR_SIZE: usize = 4;
+
+/// A function in `.init_array`. It gets passed `argc`, `argv` and `envp` like on glibc.
+pub(super) type InitFunction = extern "C" fn(c_int, *const *const c_char, *const *const c_char);
+
+extern "C" {
+    fn __register_frame(begin: *const u8);
+}
+
+/// Whether object files for the target of the current session can be loaded.
+pub(super) fn can_load_objects(tcx: TyCtxt<'_>) -> bool {
+    tcx.sess.target.arch == "x86_64"
+        && !tcx.sess.target.is_like_osx
+        && !tcx.sess.target.is_like_windows
+}
+
+/// A memory region allocated for a loaded object file. It is never freed, as the loaded code may
+/// still be running until the process exits.
+struct Region {
+    ptr: *mut u8,
+    size: usize,
+}
+
+impl Region {
+    /// Allocate the text and the data region of an object file as a single mapping, so code can
+    /// reference the data of the same object file using pc-relative relocations.
+    fn alloc_text_and_data(
+        text_size: usize,
+        data_size: usize,
+        page_size: usize,
+    ) -> Result<(Region, Region), String> {
+        // The text region is made executable once relocated, so it must not share pages with the
+        // data region.
+        let text_size = align_to(text_size, page_size);
+        let data_size = align_to(data_size, page_size);
+        if text_size + data_size == 0 {
+            let empty = || Region {
+                ptr: std::ptr::null_mut(),
+                size: 0,
+            };
+            return Ok((empty(), empty()));
+        }
+
+        // Map the memory directly instead of using the global allocator, as changing the
+        // protection of memory owned by the allocator is unsound. Symbols outside of the object
+        // file are reached through stubs and GOT entries, so they may be far away.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                text_size + data_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!(
+                "Failed to allocate memory: {}",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let ptr = ptr as *mut u8;
+        Ok((
+            Region {
+                ptr,
+                size: text_size,
+            },
+            Region {
+                ptr: unsafe { ptr.add(text_size) },
+                size: data_size,
+            },
+        ))
+    }
+}
+
+fn align_to(offset: usize, align: usize) -> usize {
+    (offset + align - 1) / align * align
+}
+
+/// The thread local storage of a loaded object file. The dynamic linker doesn't know about loaded
+/// objects, so every thread gets its own copy of the thread locals allocated on first access
+/// instead, which is tracked using a pthread key.
+pub(super) struct TlsModule {
+    /// The initial contents of the thread locals of a thread.
+    template: Vec<u8>,
+    align: usize,
+    key: libc::pthread_key_t,
+}
+
+impl TlsModule {
+    fn new(template: Vec<u8>, align: usize) -> &'static TlsModule {
+        let mut key = 0;
+        let res = unsafe { libc::pthread_key_create(&mut key, Some(free_tls_block)) };
+        assert_eq!(res, 0, "failed to create pthread key for thread locals");
+        // Thread locals may be accessed until the process exits, so the module is never freed.
+        Box::leak(Box::new(TlsModule {
+            template,
+            align,
+            key,
+        }))
+    }
+
+    fn block_for_current_thread(&self) -> *mut u8 {
+        unsafe {
+            let block = libc::pthread_getspecific(self.key) as *mut u8;
+            if !block.is_null() {
+                return block;
+            }
+
+            let mut block = std::ptr::null_mut();
+            let res = libc::posix_memalign(
+                &mut block,
+                self.align.max(std::mem::size_of::<usize>()),
+                self.template.len().max(1),
+            );
+            assert_eq!(res, 0, "failed to allocate thread locals");
+            let block = block as *mut u8;
+            std::ptr::copy_nonoverlapping(self.template.as_ptr(), block, self.template.len());
+            libc::pthread_setspecific(self.key, block as *const libc::c_void);
+            block
+        }
+    }
+}
+
+unsafe extern "C" fn free_tls_block(block: *mut libc::c_void) {
+    libc::free(block);
+}
+
+/// A thread local defined by a loaded object file.
+#[derive(Copy, Clone)]
+pub(super) struct TlsSymbol {
+    module: &'static TlsModule,
+    offset: usize,
+}
+
+/// The argument of `__tls_get_addr`.
+#[repr(C)]
+struct TlsIndex {
+    module: *const TlsModule,
+    offset: usize,
+}
+
+/// Replacement for the `__tls_get_addr` of the dynamic linker, which all general and local dynamic
+/// thread local accesses of loaded objects are redirected to.
+unsafe extern "C" fn tls_get_addr(index: *const TlsIndex) -> *mut u8 {
+    (*(*index).module)
+        .block_for_current_thread()
+        .add((*index).offset)
+}
+
+/// A relocatable object file which has been placed in memory, but which may not have been
+/// relocated yet.
+pub(super) struct LoadedObject {
+    /// The name of the object file used in error messages.
+    name: String,
+    object_data: Vec<u8>,
+    text: Region,
+    section_addrs: FxHashMap<SectionIndex, *mut u8>,
+    symbols: FxHashMap<String, *const u8>,
+    undefined_symbols: Vec<String>,
+    tls_module: Option<&'static TlsModule>,
+    tls_section_offsets: FxHashMap<SectionIndex, usize>,
+    tls_symbols: FxHashMap<String, TlsSymbol>,
+    eh_frames: Vec<*const u8>,
+    /// The priority, address and entry count of all `.init_array` sections.
+    init_arrays: Vec<(u32, *const u8, usize)>,
+    next_stub: *mut u8,
+    next_got_entry: *mut u8,
+}
+
+impl LoadedObject {
+    /// Place all sections of `object_data` in memory. The addresses of the defined symbols are
+    /// available right away, but the object must be [relocated](Self::relocate) before use. `name`
+    /// identifies the object file in error messages.
+    pub(super) fn load(name: String, object_data: Vec<u8>) -> Result<Self, String> {
+        Self::load_sections(name.clone(), object_data)
+            .map_err(|err| format!("Failed to load `{}`: {}", name, err))
+    }
+
+    fn load_sections(name: String, object_data: Vec<u8>) -> Result<Self, String> {
+        let obj = object::File::parse(&object_data)
+            .map_err(|err| format!("Failed to parse object file: {}", err))?;
+        if obj.format() != BinaryFormat::Elf
+            || obj.architecture() != Architecture::X86_64
+            || obj.kind() != ObjectKind::Relocatable
+        {
+            return Err("Only x86_64 ELF relocatable object files can be loaded".to_owned());
+        }
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        // Lay out all sections. Executable sections and the stubs to reach far away functions go
+        // in the text region, all other sections and the GOT entries go in the data region. Thread
+        // local sections form the template of the thread locals of every thread.
+        let mut section_layout = Vec::new();
+        let mut text_size = 0;
+        let mut data_size = 0;
+        let mut tls_layout = Vec::new();
+        let mut tls_size = 0;
+        let mut tls_align = 1;
+        let mut stub_count = 0;
+        let mut got_entry_count = 0;
+        for section in obj.sections() {
+            let name = section.name().unwrap_or("<invalid name>");
+            let align = usize::try_from(section.align()).unwrap().max(1);
+            let is_text = if name == ".eh_frame" || name.starts_with(".init_array") {
+                // These may have a section type `SectionKind` doesn't know about.
+                false
+            } else {
+                match section.kind() {
+                    SectionKind::Text => true,
+                    SectionKind::Data
+                    | SectionKind::ReadOnlyData
+                    | SectionKind::ReadOnlyString
+                    | SectionKind::UninitializedData => false,
+                    SectionKind::Tls | SectionKind::UninitializedTls => {
+                        tls_size = align_to(tls_size, align);
+                        tls_align = tls_align.max(align);
+                        tls_layout.push((section.index(), tls_size));
+                        tls_size += usize::try_from(section.size()).unwrap();
+                        continue;
+                    }
+                    _ => continue,
+                }
+            };
+
+            let size = if is_text {
+                &mut text_size
+            } else {
+                &mut data_size
+            };
+            *size = align_to(*size, align);
+            section_layout.push((section.index(), is_text, *size));
+            *size += usize::try_from(section.size()).unwrap();
+            if name == ".eh_frame" {
+                *size += EH_FRAME_TERMINATOR_SIZE;
+            }
+
+            for (_offset, reloc) in section.relocations() {
+                if let RelocationKind::Elf(r_type) = reloc.kind() {
+                    if is_static_tls_relocation(r_type) {
+                        return Err(format!(
+                            "Thread local relocation {} of the initial or local exec model in \
+                             section `{}` is not supported. Only the general and local dynamic \
+                             models are supported.",
+                            r_type, name,
+                        ));
+                    }
+                }
+                if reloc.kind() == RelocationKind::PltRelative {
+                    stub_count += 1;
+                } else if is_got_relocation(&reloc) {
+                    got_entry_count += 1;
+                } else if is_tls_index_relocation(&reloc) {
+                    got_entry_count += TLS_INDEX_SIZE / GOT_ENTRY_SIZE;
+                }
+            }
+        }
+        let stubs_offset = align_to(text_size, STUB_SIZE);
+        text_size = stubs_offset + stub_count * STUB_SIZE;
+        let got_offset = align_to(data_size, GOT_ENTRY_SIZE);
+        data_size = got_offset + got_entry_count * GOT_ENTRY_SIZE;
+
+        let (text, data) = Region::alloc_text_and_data(text_size, data_size, page_size)?;
+
+        let mut section_addrs = FxHashMap::default();
+        let mut eh_frames = Vec::new();
+        let mut init_arrays = Vec::new();
+        for (index, is_text, offset) in section_layout {
+            let section = obj.section_by_index(index).unwrap();
+            let region = if is_text { &text } else { &data };
+            let addr = unsafe { region.ptr.add(offset) };
+            if section.kind() != SectionKind::UninitializedData {
+                let section_data = section
+                    .data()
+                    .map_err(|err| format!("Failed to read section data: {}", err))?;
+                if !section_data.is_empty() {
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            section_data.as_ptr(),
+                            addr,
+                            section_data.len(),
+                        );
+                    }
+                }
+            }
+            section_addrs.insert(index, addr);
+
+            let name = section.name().unwrap_or("<invalid name>");
+            if name == ".eh_frame" {
+                eh_frames.push(addr as *const u8);
+            } else if name.starts_with(".init_array") {
+                // Like the static linker, run initializers without priority after all others.
+                let priority = match name.strip_prefix(".init_array.") {
+                    Some(priority) => priority
+                        .parse::<u32>()
+                        .map_err(|_| format!("Invalid `.init_array` priority in `{}`", name))?,
+                    None => u32::MAX,
+                };
+                let count = usize::try_from(section.size()).unwrap() / std::mem::size_of::<usize>();
+                init_arrays.push((priority, addr as *const u8, count));
+            }
+        }
+
+        let mut tls_section_offsets = FxHashMap::default();
+        let tls_module = if tls_layout.is_empty() {
+            None
+        } else {
+            let mut template = vec![0; tls_size];
+            for (index, offset) in tls_layout {
+                let section = obj.section_by_index(index).unwrap();
+                if section.kind() == SectionKind::Tls {
+                    let section_data = section
+                        .data()
+                        .map_err(|err| format!("Failed to read section data: {}", err))?;
+                    template[offset..offset + section_data.len()].copy_from_slice(section_data);
+                }
+                tls_section_offsets.insert(index, offset);
+            }
+            Some(TlsModule::new(template, tls_align))
+        };
+
+        let mut symbols = FxHashMap::default();
+        let mut undefined_symbols = Vec::new();
+        let mut tls_symbols = FxHashMap::default();
+        for symbol in obj.symbols() {
+            if symbol.is_local() {
+                continue;
+            }
+            if symbol.is_undefined() {
+                match symbol.name() {
+                    Ok(name) if !name.is_empty() => undefined_symbols.push(name.to_owned()),
+                    _ => {}
+                }
+                continue;
+            }
+            if let SymbolSection::Section(index) = symbol.section() {
+                if let Some(&section_offset) = tls_section_offsets.get(&index) {
+                    let name = symbol
+                        .name()
+                        .map_err(|err| format!("Invalid symbol name: {}", err))?;
+                    tls_symbols.insert(
+                        name.to_owned(),
+                        TlsSymbol {
+                            module: tls_module.unwrap(),
+                            offset: section_offset + usize::try_from(symbol.address()).unwrap(),
+                        },
+                    );
+                    continue;
+                }
+            }
+            let addr = match symbol.section() {
+                SymbolSection::Section(index) => match section_addrs.get(&index) {
+                    Some(&section_addr) => unsafe {
+                        section_addr.add(usize::try_from(symbol.address()).unwrap())
+                    },
+                    None => continue,
+                },
+                SymbolSection::Absolute => symbol.address() as usize as *mut u8,
+                SymbolSection::Common => {
+                    return Err(format!(
+                        "Common symbol `{}` is not supported. Compile with `-fno-common`.",
+                        symbol.name().unwrap_or("<invalid name>"),
+                    ));
+                }
+                _ => continue,
+            };
+            let name = symbol
+                .name()
+                .map_err(|err| format!("Invalid symbol name: {}", err))?;
+            symbols.insert(name.to_owned(), addr as *const u8);
+        }
+
+        let next_stub = if stub_count == 0 {
+            std::ptr::null_mut()
+        } else {
+            unsafe { text.ptr.add(stubs_offset) }
+        };
+        let next_got_entry = if got_entry_count == 0 {
+            std::ptr::null_mut()
+        } else {
+            unsafe { data.ptr.add(got_offset) }
+        };
+
+        drop(obj);
+        Ok(LoadedObject {
+            name,
+            object_data,
+            text,
+            section_addrs,
+            symbols,
+            undefined_symbols,
+            tls_module,
+            tls_section_offsets,
+            tls_symbols,
+            eh_frames,
+            init_arrays,
+            next_stub,
+            next_got_entry,
+        })
+    }
+
+    /// All global symbols defined by this object file.
+    pub(super) fn symbols(&self) -> &FxHashMap<String, *const u8> {
+        &self.symbols
+    }
+
+    /// All symbols referenced, but not defined by this object file.
+    pub(super) fn undefined_symbols(&self) -> &[String] {
+        &self.undefined_symbols
+    }
+
+    /// All global thread locals defined by this object file.
+    pub(super) fn tls_symbols(&self) -> &FxHashMap<String, TlsSymbol> {
+        &self.tls_symbols
+    }
+
+    /// Apply all relocations, make the code executable and register the unwind tables. Undefined
+    /// symbols are resolved using `lookup_symbol` and undefined thread locals using
+    /// `lookup_tls_symbol`.
+    pub(super) fn relocate(
+        &mut self,
+        lookup_symbol: impl Fn(&str) -> Option<*const u8>,
+        lookup_tls_symbol: impl Fn(&str) -> Option<TlsSymbol>,
+    ) -> Result<(), String> {
+        self.relocate_sections(lookup_symbol, lookup_tls_symbol)
+            .map_err(|err| format!("Failed to relocate `{}`: {}", self.name, err))
+    }
+
+    fn relocate_sections(
+        &mut self,
+        lookup_symbol: impl Fn(&str) -> Option<*const u8>,
+        lookup_tls_symbol: impl Fn(&str) -> Option<TlsSymbol>,
+    ) -> Result<(), String> {
+        let object_data = std::mem::take(&mut self.object_data);
+        let obj = object::File::parse(&object_data).unwrap();
+
+        for section in obj.sections() {
+            let section_addr = match self.section_addrs.get(&section.index()) {
+                Some(&section_addr) => section_addr,
+                None => continue,
+            };
+
+            for (offset, reloc) in section.relocations() {
+                let place = unsafe { section_addr.add(usize::try_from(offset).unwrap()) };
+                if let RelocationKind::Elf(r_type) = reloc.kind() {
+                    if is_tls_relocation(r_type) {
+                        let symbol = match reloc.target() {
+                            RelocationTarget::Symbol(index) => {
+                                self.tls_symbol(&obj, index, &lookup_tls_symbol)?
+                            }
+                            target => {
+                                return Err(format!(
+                                    "Thread local relocation against {:?} is not supported",
+                                    target,
+                                ));
+                            }
+                        };
+                        self.apply_tls_relocation(place, symbol, r_type, &reloc)?;
+                        continue;
+                    }
+                }
+                let target = match reloc.target() {
+                    RelocationTarget::Symbol(index) => {
+                        self.symbol_addr(&obj, index, &lookup_symbol)? as i64
+                    }
+                    RelocationTarget::Section(index) => match self.section_addrs.get(&index) {
+                        Some(&addr) => addr as i64,
+                        None => {
+                            return Err(format!(
+                                "Relocation against section {} which wasn't loaded",
+                                index.0,
+                            ));
+                        }
+                    },
+                    RelocationTarget::Absolute => 0,
+                };
+                self.apply_relocation(place, target, &reloc)?;
+            }
+        }
+
+        if self.text.size != 0 {
+            let res = unsafe {
+                libc::mprotect(
+                    self.text.ptr as *mut libc::c_void,
+                    self.text.size,
+                    libc::PROT_READ | libc::PROT_EXEC,
+                )
+            };
+            if res != 0 {
+                return Err(format!(
+                    "Failed to make loaded code executable: {}",
+                    std::io::Error::last_os_error(),
+                ));
+            }
+        }
+
+        for &eh_frame in &self.eh_frames {
+            // The unwinder may access the unwind tables until the process exits, so they are never
+            // deregistered.
+            unsafe { __register_frame(eh_frame) };
+        }
+
+        Ok(())
+    }
+
+    /// All functions in the `.init_array` sections together with their priority. The object must
+    /// have been [relocated](Self::relocate) before.
+    pub(super) fn init_functions(&self) -> Vec<(u32, InitFunction)> {
+        let mut init_functions = Vec::new();
+        for &(priority, init_array, count) in &self.init_arrays {
+            for i in 0..count {
+                let entry =
+                    unsafe { std::ptr::read_unaligned((init_array as *const usize).add(i)) };
+                // Both 0 and -1 are used to mark unused entries.
+                if entry == 0 || entry == usize::MAX {
+                    continue;
+                }
+                init_functions.push((priority, unsafe {
+                    std::mem::transmute::<usize, InitFunction>(entry)
+                }));
+            }
+        }
+        init_functions
+    }
+
+    fn tls_symbol(
+        &self,
+        obj: &object::File<'_>,
+        index: SymbolIndex,
+        lookup_tls_symbol: &impl Fn(&str) -> Option<TlsSymbol>,
+    ) -> Result<TlsSymbol, String> {
+        let symbol = obj
+            .symbol_by_index(index)
+            .map_err(|err| format!("Invalid symbol index {}: {}", index.0, err))?;
+        let name = symbol.name().unwrap_or("<invalid name>");
+        match symbol.section() {
+            SymbolSection::Section(section_index) => {
+                match self.tls_section_offsets.get(&section_index) {
+                    Some(&section_offset) => Ok(TlsSymbol {
+                        module: self.tls_module.unwrap(),
+                        offset: section_offset + usize::try_from(symbol.address()).unwrap(),
+                    }),
+                    None => Err(format!("Symbol `{}` is not a thread local", name)),
+                }
+            }
+            SymbolSection::Undefined => lookup_tls_symbol(name)
+                .ok_or_else(|| format!("Undefined thread local symbol `{}`", name)),
+            _ => Err(format!(
+                "Unsupported thread local symbol `{}`: {:?}",
+                name,
+                symbol.section()
+            )),
+        }
+    }
+
+    fn symbol_addr(
+        &self,
+        obj: &object::File<'_>,
+        index: SymbolIndex,
+        lookup_symbol: &impl Fn(&str) -> Option<*const u8>,
+    ) -> Result<*const u8, String> {
+        let symbol = obj
+            .symbol_by_index(index)
+            .map_err(|err| format!("Invalid symbol index {}: {}", index.0, err))?;
+        let name = symbol.name().unwrap_or("<invalid name>");
+        match symbol.section() {
+            SymbolSection::Section(section_index) => match self.section_addrs.get(&section_index) {
+                Some(&section_addr) => Ok(unsafe {
+                    section_addr.add(usize::try_from(symbol.address()).unwrap()) as *const u8
+                }),
+                None => Err(format!(
+                    "Symbol `{}` is in a section which wasn't loaded",
+                    name
+                )),
+            },
+            SymbolSection::Absolute => Ok(symbol.address() as usize as *const u8),
+            SymbolSection::Undefined if name == "__tls_get_addr" => Ok(tls_get_addr as *const u8),
+            SymbolSection::Undefined => match lookup_symbol(name) {
+                Some(addr) => Ok(addr),
+                None if symbol.is_weak() => Ok(std::ptr::null()),
+                None => Err(format!("Undefined symbol `{}`", name)),
+            },
+            _ => Err(format!(
+                "Unsupported symbol `{}`: {:?}",
+                name,
+                symbol.section()
+            )),
+        }
+    }
+
+    fn apply_relocation(
+        &mut self,
+        place: *mut u8,
+        target: i64,
+        reloc: &Relocation,
+    ) -> Result<(), String> {
+        let value = target.wrapping_add(reloc.addend());
+        let pc_relative = |target: i64| target.wrapping_add(reloc.addend()) - place as i64;
+
+        match (reloc.kind(), reloc.size()) {
+            (RelocationKind::Absolute, 64) => write::<u64>(place, value as u64),
+            (RelocationKind::Absolute, 32) => {
+                if reloc.encoding() == RelocationEncoding::X86Signed {
+                    write::<i32>(place, to_i32(value)?)
+                } else {
+                    let value = u32::try_from(value)
+                        .map_err(|_| format!("Relocation value {:#x} out of range", value))?;
+                    write::<u32>(place, value)
+                }
+            }
+            (RelocationKind::Relative, 32) => write::<i32>(place, to_i32(pc_relative(target))?),
+            (RelocationKind::Relative, 64) | (RelocationKind::Elf(R_X86_64_PC64), _) => {
+                write::<i64>(place, pc_relative(target))
+            }
+            (RelocationKind::PltRelative, 32) => {
+                let pc_relative_value = match to_i32(pc_relative(target)) {
+                    Ok(value) => value,
+                    // The target is too far away for a direct jump, jump through a stub instead.
+                    Err(_) => to_i32(pc_relative(self.alloc_stub(target) as i64))?,
+                };
+                write::<i32>(place, pc_relative_value)
+            }
+            _ if is_got_relocation(reloc) => {
+                let got_entry = self.alloc_got_entry(target);
+                write::<i32>(place, to_i32(pc_relative(got_entry as i64))?)
+            }
+            (kind, size) => {
+                return Err(format!(
+                    "Unsupported relocation kind {:?} of size {}",
+                    kind, size
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_tls_relocation(
+        &mut self,
+        place: *mut u8,
+        symbol: TlsSymbol,
+        r_type: u32,
+        reloc: &Relocation,
+    ) -> Result<(), String> {
+        let offset = symbol.offset as i64 + reloc.addend();
+        match r_type {
+            R_X86_64_TLSGD | R_X86_64_TLSLD => {
+                // Local dynamic accesses get the start of the thread locals of the module and add
+                // the offset of the thread local themself.
+                let index_offset = if r_type == R_X86_64_TLSGD {
+                    symbol.offset
+                } else {
+                    0
+                };
+                let tls_index = self.alloc_tls_index(symbol.module, index_offset);
+                let pc_relative = tls_index as i64 + reloc.addend() - place as i64;
+                write::<i32>(place, to_i32(pc_relative)?);
+            }
+            R_X86_64_DTPOFF32 => write::<i32>(place, to_i32(offset)?),
+            R_X86_64_DTPOFF64 => write::<i64>(place, offset),
+            R_X86_64_DTPMOD64 => write::<u64>(place, symbol.module as *const TlsModule as u64),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    fn alloc_stub(&mut self, target: i64) -> *const u8 {
+        let stub = self.next_stub;
+        unsafe {
+            std::ptr::copy_nonoverlapping(STUB_CODE.as_ptr(), stub, STUB_CODE.len());
+            write::<i64>(stub.add(STUB_CODE.len()), target);
+            self.next_stub = stub.add(STUB_SIZE);
+        }
+        stub
+    }
+
+    fn alloc_got_entry(&mut self, target: i64) -> *const u8 {
+        let got_entry = self.next_got_entry;
+        unsafe {
+            write::<i64>(got_entry, target);
+            self.next_got_entry = got_entry.add(GOT_ENTRY_SIZE);
+        }
+        got_entry
+    }
+
+    fn alloc_tls_index(&mut self, module: &'static TlsModule, offset: usize) -> *const u8 {
+        let tls_index = self.next_got_entry;
+        unsafe {
+            write(tls_index, TlsIndex { module, offset });
+            self.next_got_entry = tls_index.add(TLS_INDEX_SIZE);
+        }
+        tls_index
+    }
+}
+
+fn is_got_relocation(reloc: &Relocation) -> bool {
+    match reloc.kind() {
+        RelocationKind::GotRelative => true,
+        RelocationKind::Elf(r_type) => {
+            r_type == R_X86_64_GOTPCRELX || r_type == R_X86_64_REX_GOTPCRELX
+        }
+        _ => false,
+    }
+}
+
+/// Thread local relocations of the general and local dynamic models. The initial and local exec
+/// models aren't supported, as they require the thread locals to be part of the static TLS block
+/// of the dynamic linker.
+fn is_tls_relocation(r_type: u32) -> bool {
+    match r_type {
+        R_X86_64_TLSGD | R_X86_64_TLSLD | R_X86_64_DTPOFF32 | R_X86_64_DTPOFF64
+        | R_X86_64_DTPMOD64 => true,
+        _ => false,
+    }
+}
+
+/// Thread local relocations of the initial and local exec models.
+fn is_static_tls_relocation(r_type: u32) -> bool {
+    match r_type {
+        R_X86_64_GOTTPOFF | R_X86_64_TPOFF32 | R_X86_64_TPOFF64 => true,
+        _ => false,
+    }
+}
+
+/// Relocations which need a `tls_index` in the GOT.
+fn is_tls_index_relocation(reloc: &Relocation) -> bool {
+    match reloc.kind() {
+        RelocationKind::Elf(r_type) => r_type == R_X86_64_TLSGD || r_type == R_X86_64_TLSLD,
+        _ => false,
+    }
+}
+
+fn to_i32(value: i64) -> Result<i32, String> {
+    i32::try_from(value).map_err(|_| {
+        format!(
+            "Relocation value {:#x} out of range. The loaded object is too far away from the \
+             referenced symbol.",
+            value,
+        )
+    })
+}
+
+fn write<T>(place: *mut u8, value: T) {
+    unsafe { std::ptr::write_unaligned(place as *mut T, value) }
+}
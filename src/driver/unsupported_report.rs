@@ -0,0 +1,97 @@
+//! Reports everything cg_clif doesn't support which a crate uses, enabled using
+//! `-Cllvm-args=unsupported-report`.
+//!
+//! All unsupported constructs are recorded during codegen, see
+//! [`CodegenCx::unsupported`](crate::CodegenCx::unsupported), and written as JSON to
+//! `<crate>.unsupported.json` next to the crate outputs:
+//!
+//! ```json
+//! {
+//!   "crate": "my_crate",
+//!   "sites": [
+//!     {
+//!       "column": 9,
+//!       "file": "src/main.rs",
+//!       "instance": "my_crate::foo",
+//!       "kind": "trap",
+//!       "line": 3,
+//!       "reason": "unsupported llvm intrinsic llvm.x86.foo"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! A site of kind `"trap"` aborts the program when reached at runtime. A site of kind `"error"`
+//! normally aborts compilation. In report mode it is replaced with a trap too, so the whole crate
+//! can be reported, but compilation still fails at the end.
+
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::Json;
+
+use crate::prelude::*;
+use crate::trap::UnsupportedSite;
+
+/// Write the report for `unsupported` and summarize it in a diagnostic.
+pub(super) fn write_report(tcx: TyCtxt<'_>, unsupported: &[UnsupportedSite]) {
+    let source_map = tcx.sess.source_map();
+    let sites = unsupported
+        .iter()
+        .map(|site| {
+            let loc = source_map.lookup_char_pos(site.span.lo());
+            let mut json = BTreeMap::new();
+            let kind = if site.fatal { "error" } else { "trap" };
+            json.insert("kind".to_string(), Json::String(kind.to_string()));
+            json.insert("reason".to_string(), Json::String(site.reason.clone()));
+            json.insert("instance".to_string(), Json::String(site.instance.clone()));
+            json.insert("file".to_string(), Json::String(loc.file.name.to_string()));
+            json.insert("line".to_string(), Json::U64(loc.line as u64));
+            json.insert("column".to_string(), Json::U64(loc.col_display as u64 + 1));
+            Json::Object(json)
+        })
+        .collect();
+
+    let mut report = BTreeMap::new();
+    report.insert(
+        "crate".to_string(),
+        Json::String(tcx.crate_name(LOCAL_CRATE).to_string()),
+    );
+    report.insert("sites".to_string(), Json::Array(sites));
+
+    let path = tcx
+        .output_filenames(LOCAL_CRATE)
+        .with_extension("unsupported.json");
+    if let Err(err) = std::fs::write(&path, Json::Object(report).pretty().to_string()) {
+        tcx.sess.fatal(&format!(
+            "error writing unsupported feature report `{}`: {}",
+            path.display(),
+            err
+        ));
+    }
+
+    let errors = unsupported.iter().filter(|site| site.fatal).count();
+    let traps = unsupported.len() - errors;
+    let see_report = format!("see `{}` for all uses", path.display());
+    if errors != 0 {
+        tcx.sess
+            .struct_err(&format!(
+                "{} uses of unsupported features prevent compilation and {} more abort at runtime",
+                errors, traps
+            ))
+            .note(&see_report)
+            .emit();
+    } else if traps != 0 {
+        tcx.sess
+            .struct_warn(&format!(
+                "{} uses of unsupported features abort at runtime",
+                traps
+            ))
+            .note(&see_report)
+            .emit();
+    } else {
+        tcx.sess.note_without_error(&format!(
+            "no unsupported features are used, the report has been written to `{}`",
+            path.display()
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+//! A pool of threads on which the AOT driver compiles codegen units that have already been lowered
+//! to clif ir.
+//!
+//! Every worker thread needs to hold a jobserver token while it is running a job, so that cg_clif
+//! doesn't use more cores than cargo allows. The rustc thread doesn't have to acquire a token, as
+//! it owns the implicit token of the process. It uses this token to run jobs itself while waiting
+//! for the results in [`WorkerPool::join`]. This ensures that all jobs finish even when no extra
+//! token ever becomes available.
+//!
+//! Tokens are requested through a jobserver helper thread, which starts a worker thread for every
+//! acquired token. Workers exit and release their token once the queue is empty. Requests which
+//! are still outstanding once `join` has taken the last job are cancelled.
+
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use jobserver::HelperThread;
+use rustc_session::Session;
+
+type Job<T> = Box<dyn FnOnce() -> T + Send>;
+
+struct Queue<T> {
+    jobs: Mutex<VecDeque<(usize, Job<T>)>>,
+    /// The amount of jobserver tokens which have been requested, but not yet acquired.
+    requested_tokens: AtomicUsize,
+}
+
+impl<T> Queue<T> {
+    fn pop(&self) -> Option<(usize, Job<T>)> {
+        self.jobs.lock().unwrap().pop_front()
+    }
+}
+
+pub(super) struct WorkerPool<T: Send + 'static> {
+    /// Requests jobserver tokens and starts a worker thread for every acquired token. Dropping it
+    /// cancels all outstanding requests.
+    helper: HelperThread,
+    queue: Arc<Queue<T>>,
+    results_sender: Sender<(usize, std::thread::Result<T>)>,
+    results: Receiver<(usize, std::thread::Result<T>)>,
+    job_count: usize,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub(super) fn new(sess: &Session) -> Self {
+        let (results_sender, results) = channel();
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new(VecDeque::new()),
+            requested_tokens: AtomicUsize::new(0),
+        });
+
+        let helper = {
+            let queue = queue.clone();
+            let results_sender = results_sender.clone();
+            sess.jobserver
+                .clone()
+                .into_helper_thread(move |token| {
+                    queue.requested_tokens.fetch_sub(1, Ordering::SeqCst);
+                    let token = match token {
+                        Ok(token) => token,
+                        // The rustc thread will run the job itself once it is done lowering.
+                        Err(_) => return,
+                    };
+
+                    let queue = queue.clone();
+                    let results_sender = results_sender.clone();
+                    std::thread::Builder::new()
+                        .name("cg_clif codegen worker".to_string())
+                        .spawn(move || {
+                            let _token = token;
+                            while let Some((index, job)) = queue.pop() {
+                                let res = std::panic::catch_unwind(AssertUnwindSafe(job));
+                                if results_sender.send((index, res)).is_err() {
+                                    return;
+                                }
+                            }
+                        })
+                        .expect("failed to spawn codegen worker thread");
+                })
+                .expect("failed to spawn jobserver helper thread")
+        };
+
+        WorkerPool {
+            helper,
+            queue,
+            results_sender,
+            results,
+            job_count: 0,
+        }
+    }
+
+    /// Queue `job` to be run on a worker thread as soon as a jobserver token is available.
+    pub(super) fn spawn(&mut self, job: impl FnOnce() -> T + Send + 'static) {
+        let index = self.job_count;
+        self.job_count += 1;
+
+        let queue_len = {
+            let mut jobs = self.queue.jobs.lock().unwrap();
+            jobs.push_back((index, Box::new(job)));
+            jobs.len()
+        };
+
+        // Workers keep taking jobs from the queue until it is empty, so only request a new token
+        // when there are more queued jobs than outstanding token requests.
+        if self.queue.requested_tokens.load(Ordering::SeqCst) >= queue_len {
+            return;
+        }
+        self.queue.requested_tokens.fetch_add(1, Ordering::SeqCst);
+        self.helper.request_token();
+    }
+
+    /// Wait for all jobs to finish, running queued jobs on the current thread in the mean time.
+    ///
+    /// The results are returned in the order in which the jobs were spawned, independent of the
+    /// order in which they finished. If a job panicked, the panic is resumed on the current thread.
+    pub(super) fn join(self) -> Vec<T> {
+        let WorkerPool {
+            helper,
+            queue,
+            results_sender,
+            results,
+            job_count,
+        } = self;
+
+        while let Some((index, job)) = queue.pop() {
+            let res = std::panic::catch_unwind(AssertUnwindSafe(job));
+            results_sender.send((index, res)).unwrap();
+        }
+
+        // Every job has been taken from the queue, so tokens which are acquired from now on would
+        // only be released again. Cancel the outstanding requests instead of waiting for them.
+        drop(helper);
+        drop(results_sender);
+
+        let mut ordered_results = (0..job_count).map(|_| None).collect::<Vec<Option<T>>>();
+        for _ in 0..job_count {
+            let (index, res) = results
+                .recv()
+                .expect("codegen worker thread exited without finishing its job");
+            match res {
+                Ok(res) => ordered_results[index] = Some(res),
+                Err(payload) => std::panic::resume_unwind(payload),
+            }
+        }
+
+        ordered_results
+            .into_iter()
+            .map(|res| res.unwrap())
+            .collect()
+    }
+}
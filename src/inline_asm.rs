@@ -5,12 +5,15 @@
 use std::fmt::Write;
 
 use rustc_ast::ast::{InlineAsmOptions, InlineAsmTemplatePiece};
+use rustc_data_structures::fx::FxHashSet;
+use rustc_middle::mir::mono::{Linkage as RLinkage, MonoItem};
 use rustc_middle::mir::InlineAsmOperand;
+use rustc_span::Symbol;
 use rustc_target::asm::*;
 
 pub(crate) fn codegen_inline_asm<'tcx>(
     fx: &mut FunctionCx<'_, 'tcx, impl Module>,
-    _span: Span,
+    span: Span,
     template: &[InlineAsmTemplatePiece],
     operands: &[InlineAsmOperand<'tcx>],
     options: InlineAsmOptions,
@@ -22,6 +25,17 @@ pub(crate) fn codegen_inline_asm<'tcx>(
         return;
     }
 
+    let inline_asm_index = fx.inline_asm_index;
+    fx.inline_asm_index += 1;
+    let asm_name = format!(
+        "{}__inline_asm_{}",
+        fx.tcx.symbol_name(fx.instance).name,
+        inline_asm_index
+    );
+
+    let regs = allocate_registers(fx, span, InlineAsmArch::X86_64, operands);
+
+    let mut template_operands = Vec::with_capacity(operands.len());
     let mut slot_size = Size::from_bytes(0);
     let mut clobbered_regs = Vec::new();
     let mut inputs = Vec::new();
@@ -42,23 +56,27 @@ pub(crate) fn codegen_inline_asm<'tcx>(
     };
 
     // FIXME overlap input and output slots to save stack space
-    for operand in operands {
+    for (operand_idx, (operand, &reg)) in operands.iter().zip(&regs).enumerate() {
+        if let Some(reg) = reg {
+            template_operands.push(TemplateOperand::Reg(reg));
+        }
+
         match *operand {
-            InlineAsmOperand::In { reg, ref value } => {
-                let reg = expect_reg(reg);
+            InlineAsmOperand::In { reg: _, ref value } => {
+                let reg = reg.unwrap();
                 clobbered_regs.push((reg, new_slot(reg.reg_class())));
                 inputs.push((
                     reg,
                     new_slot(reg.reg_class()),
-                    crate::base::codegen_operand(fx, value).load_scalar(fx),
+                    crate::base::codegen_operand(fx, value),
                 ));
             }
             InlineAsmOperand::Out {
-                reg,
+                reg: _,
                 late: _,
                 place,
             } => {
-                let reg = expect_reg(reg);
+                let reg = reg.unwrap();
                 clobbered_regs.push((reg, new_slot(reg.reg_class())));
                 if let Some(place) = place {
                     outputs.push((
@@ -69,17 +87,17 @@ pub(crate) fn codegen_inline_asm<'tcx>(
                 }
             }
             InlineAsmOperand::InOut {
-                reg,
+                reg: _,
                 late: _,
                 ref in_value,
                 out_place,
             } => {
-                let reg = expect_reg(reg);
+                let reg = reg.unwrap();
                 clobbered_regs.push((reg, new_slot(reg.reg_class())));
                 inputs.push((
                     reg,
                     new_slot(reg.reg_class()),
-                    crate::base::codegen_operand(fx, in_value).load_scalar(fx),
+                    crate::base::codegen_operand(fx, in_value),
                 ));
                 if let Some(out_place) = out_place {
                     outputs.push((
@@ -89,25 +107,48 @@ pub(crate) fn codegen_inline_asm<'tcx>(
                     ));
                 }
             }
-            InlineAsmOperand::Const { value: _ } => todo!(),
-            InlineAsmOperand::SymFn { value: _ } => todo!(),
-            InlineAsmOperand::SymStatic { def_id: _ } => todo!(),
+            InlineAsmOperand::Const { ref value } => {
+                let const_ = crate::constant::mir_operand_get_const_val(fx, value)
+                    .unwrap_or_else(|| span_bug!(span, "asm const is not a constant"));
+                template_operands.push(TemplateOperand::Text(const_to_asm_string(fx, const_)));
+            }
+            InlineAsmOperand::SymFn { ref value } => {
+                let literal = fx.monomorphize(value.literal);
+                let instance = match *literal.ty.kind() {
+                    ty::FnDef(def_id, substs) => {
+                        Instance::resolve_for_fn_ptr(fx.tcx, ParamEnv::reveal_all(), def_id, substs)
+                            .unwrap()
+                            .polymorphize(fx.tcx)
+                    }
+                    _ => span_bug!(span, "invalid type for asm sym (fn): {:?}", literal.ty),
+                };
+
+                let symbol_name = if has_local_linkage(fx.tcx, instance) {
+                    let wrapper_name = format!("{}_sym{}", asm_name, operand_idx);
+                    define_forwarding_function(fx, &wrapper_name, instance);
+                    wrapper_name
+                } else {
+                    fx.tcx.symbol_name(instance).name.to_string()
+                };
+                template_operands.push(TemplateOperand::Text(symbol_name));
+            }
+            InlineAsmOperand::SymStatic { def_id } => {
+                // Statics never get local linkage unless requested using `#[linkage]`, so the
+                // assembled object can refer to them directly.
+                let instance = Instance::mono(fx.tcx, def_id).polymorphize(fx.tcx);
+                template_operands.push(TemplateOperand::Text(
+                    fx.tcx.symbol_name(instance).name.to_string(),
+                ));
+            }
         }
     }
 
-    let inline_asm_index = fx.inline_asm_index;
-    fx.inline_asm_index += 1;
-    let asm_name = format!(
-        "{}__inline_asm_{}",
-        fx.tcx.symbol_name(fx.instance).name,
-        inline_asm_index
-    );
-
     let generated_asm = generate_asm_wrapper(
         &asm_name,
         InlineAsmArch::X86_64,
         options,
         template,
+        &template_operands,
         clobbered_regs,
         &inputs,
         &outputs,
@@ -117,13 +158,172 @@ pub(crate) fn codegen_inline_asm<'tcx>(
     call_inline_asm(fx, &asm_name, slot_size, inputs, outputs);
 }
 
+/// Pick a register for every register operand.
+///
+/// Explicit registers are used as is. For register classes the first allocatable register of the
+/// class which doesn't overlap with a register used by any other operand is chosen. Input and
+/// output operands never share a register, even for late outputs. This wastes some registers, but
+/// is always correct. All chosen registers are saved and restored by the asm wrapper.
+fn allocate_registers<'tcx>(
+    fx: &FunctionCx<'_, 'tcx, impl Module>,
+    span: Span,
+    arch: InlineAsmArch,
+    operands: &[InlineAsmOperand<'tcx>],
+) -> Vec<Option<InlineAsmReg>> {
+    let operand_reg = |operand: &InlineAsmOperand<'tcx>| match *operand {
+        InlineAsmOperand::In { reg, .. }
+        | InlineAsmOperand::Out { reg, .. }
+        | InlineAsmOperand::InOut { reg, .. } => Some(reg),
+        InlineAsmOperand::Const { .. }
+        | InlineAsmOperand::SymFn { .. }
+        | InlineAsmOperand::SymStatic { .. } => None,
+    };
+
+    let mut used_regs = FxHashSet::default();
+    for operand in operands {
+        if let Some(InlineAsmRegOrRegClass::Reg(reg)) = operand_reg(operand) {
+            reg.overlapping_regs(|overlapping| {
+                used_regs.insert(overlapping);
+            });
+        }
+    }
+
+    let fn_target_features = &fx
+        .tcx
+        .codegen_fn_attrs(fx.instance.def_id())
+        .target_features;
+    let allocatable_regs = allocatable_registers(
+        arch,
+        |feature| {
+            let feature = Symbol::intern(feature);
+            fx.tcx.sess.target_features.contains(&feature) || fn_target_features.contains(&feature)
+        },
+        &fx.tcx.sess.target,
+    );
+
+    operands
+        .iter()
+        .map(|operand| match operand_reg(operand)? {
+            InlineAsmRegOrRegClass::Reg(reg) => Some(reg),
+            InlineAsmRegOrRegClass::RegClass(class) => {
+                // Sort to make the allocation independent of the hash set iteration order.
+                let mut candidates = allocatable_regs
+                    .get(&class)
+                    .into_iter()
+                    .flatten()
+                    .copied()
+                    .filter(|reg| !used_regs.contains(reg))
+                    .collect::<Vec<_>>();
+                candidates.sort_by_key(|reg| reg.name());
+
+                let reg = *candidates.first().unwrap_or_else(|| {
+                    fx.tcx.sess.span_fatal(
+                        span,
+                        &format!("no free register left in register class `{}`", class.name()),
+                    )
+                });
+                reg.overlapping_regs(|overlapping| {
+                    used_regs.insert(overlapping);
+                });
+                Some(reg)
+            }
+        })
+        .collect()
+}
+
+/// What a placeholder in the asm template is replaced with.
+enum TemplateOperand {
+    /// The register allocated for a register operand.
+    Reg(InlineAsmReg),
+    /// The value of a `const` operand or the symbol of a `sym` operand.
+    Text(String),
+}
+
+/// Format the value of a `const` operand the same way as `cg_llvm` does.
+fn const_to_asm_string<'tcx>(
+    fx: &FunctionCx<'_, 'tcx, impl Module>,
+    const_: &'tcx ty::Const<'tcx>,
+) -> String {
+    let value = const_.eval_bits(fx.tcx, ParamEnv::reveal_all(), const_.ty);
+    match *const_.ty.kind() {
+        ty::Uint(_) => value.to_string(),
+        ty::Int(_) => {
+            let size = fx.layout_of(const_.ty).size;
+            (size.sign_extend(value) as i128).to_string()
+        }
+        ty::Float(FloatTy::F32) => f32::from_bits(value as u32).to_string(),
+        ty::Float(FloatTy::F64) => f64::from_bits(value as u64).to_string(),
+        _ => bug!("asm const has bad type {}", const_.ty),
+    }
+}
+
+/// Whether `instance` is defined with local linkage in any codegen unit of the current crate.
+fn has_local_linkage<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> bool {
+    let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+    cgus.iter().any(|cgu| {
+        matches!(
+            cgu.items().get(&MonoItem::Fn(instance)),
+            Some(&(RLinkage::Internal, _)) | Some(&(RLinkage::Private, _))
+        )
+    })
+}
+
+/// Define a global function named `wrapper_name` which forwards all arguments to `instance`.
+///
+/// The asm wrapper is assembled into a separate object file, so it can't directly reference
+/// functions with local linkage.
+fn define_forwarding_function<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx, impl Module>,
+    wrapper_name: &str,
+    instance: Instance<'tcx>,
+) {
+    let tcx = fx.tcx;
+    let module = &mut fx.cx.module;
+
+    let sig = crate::abi::get_function_sig(tcx, module.isa().triple(), instance);
+    let wrapper_func_id = module
+        .declare_function(wrapper_name, Linkage::Hidden, &sig)
+        .unwrap();
+    let callee_func_id = crate::abi::import_function(tcx, module, instance);
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, 0), sig);
+    {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut bcx = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+
+        let block = bcx.create_block();
+        bcx.switch_to_block(block);
+        bcx.append_block_params_for_function_params(block);
+        let args = bcx.block_params(block).to_vec();
+
+        let callee_func_ref = module.declare_func_in_func(callee_func_id, &mut bcx.func);
+        let call_inst = bcx.ins().call(callee_func_ref, &args);
+        let results = bcx.inst_results(call_inst).to_vec();
+        bcx.ins().return_(&results);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module
+        .define_function(
+            wrapper_func_id,
+            &mut ctx,
+            &mut cranelift_codegen::binemit::NullTrapSink {},
+        )
+        .unwrap();
+    fx.cx
+        .unwind_context
+        .add_function(wrapper_func_id, &ctx, fx.cx.module.isa());
+}
+
 fn generate_asm_wrapper(
     asm_name: &str,
     arch: InlineAsmArch,
     options: InlineAsmOptions,
     template: &[InlineAsmTemplatePiece],
+    template_operands: &[TemplateOperand],
     clobbered_regs: Vec<(InlineAsmReg, Size)>,
-    inputs: &[(InlineAsmReg, Size, Value)],
+    inputs: &[(InlineAsmReg, Size, CValue<'_>)],
     outputs: &[(InlineAsmReg, Size, CPlace<'_>)],
 ) -> String {
     let mut generated_asm = String::new();
@@ -165,10 +365,18 @@ fn generate_asm_wrapper(
                 generated_asm.push_str(s);
             }
             InlineAsmTemplatePiece::Placeholder {
-                operand_idx: _,
-                modifier: _,
+                operand_idx,
+                modifier,
                 span: _,
-            } => todo!(),
+            } => match template_operands[*operand_idx] {
+                TemplateOperand::Reg(reg) => {
+                    if options.contains(InlineAsmOptions::ATT_SYNTAX) {
+                        generated_asm.push('%');
+                    }
+                    reg.emit(&mut generated_asm, arch, *modifier).unwrap();
+                }
+                TemplateOperand::Text(ref text) => generated_asm.push_str(text),
+            },
         }
     }
     generated_asm.push('\n');
@@ -206,7 +414,7 @@ fn call_inline_asm<'tcx>(
     fx: &mut FunctionCx<'_, 'tcx, impl Module>,
     asm_name: &str,
     slot_size: Size,
-    inputs: Vec<(InlineAsmReg, Size, Value)>,
+    inputs: Vec<(InlineAsmReg, Size, CValue<'tcx>)>,
     outputs: Vec<(InlineAsmReg, Size, CPlace<'tcx>)>,
 ) {
     let stack_slot = fx.bcx.func.create_stack_slot(StackSlotData {
@@ -214,8 +422,9 @@ fn call_inline_asm<'tcx>(
         offset: None,
         size: u32::try_from(slot_size.bytes()).unwrap(),
     });
-    #[cfg(debug_assertions)]
-    fx.add_comment(stack_slot, "inline asm scratch slot");
+    if fx.clif_comments.enabled() {
+        fx.add_comment(stack_slot, "inline asm scratch slot");
+    }
 
     let inline_asm_func = fx
         .cx
@@ -234,39 +443,45 @@ fn call_inline_asm<'tcx>(
         .cx
         .module
         .declare_func_in_func(inline_asm_func, &mut fx.bcx.func);
-    #[cfg(debug_assertions)]
-    fx.add_comment(inline_asm_func, asm_name);
+    if fx.clif_comments.enabled() {
+        fx.add_comment(inline_asm_func, asm_name);
+    }
 
     for (_reg, offset, value) in inputs {
-        fx.bcx
-            .ins()
-            .stack_store(value, stack_slot, i32::try_from(offset.bytes()).unwrap());
+        let ptr = Pointer::stack_slot(stack_slot).offset_i64(fx, offset.bytes() as i64);
+        CPlace::for_ptr(ptr, value.layout()).write_cvalue(fx, value);
     }
 
     let stack_slot_addr = fx.bcx.ins().stack_addr(fx.pointer_type, stack_slot, 0);
     fx.bcx.ins().call(inline_asm_func, &[stack_slot_addr]);
 
     for (_reg, offset, place) in outputs {
-        let ty = fx.clif_type(place.layout().ty).unwrap();
-        let value = fx
-            .bcx
-            .ins()
-            .stack_load(ty, stack_slot, i32::try_from(offset.bytes()).unwrap());
-        place.write_cvalue(fx, CValue::by_val(value, place.layout()));
+        let ptr = Pointer::stack_slot(stack_slot).offset_i64(fx, offset.bytes() as i64);
+        place.write_cvalue(fx, CValue::by_ref(ptr, place.layout()));
     }
 }
 
-fn expect_reg(reg_or_class: InlineAsmRegOrRegClass) -> InlineAsmReg {
-    match reg_or_class {
-        InlineAsmRegOrRegClass::Reg(reg) => reg,
-        InlineAsmRegOrRegClass::RegClass(class) => unimplemented!("{:?}", class),
+/// The instruction to move the full contents of `reg` from or to memory.
+fn x86_64_move_instruction(reg: InlineAsmReg) -> &'static str {
+    match reg.reg_class() {
+        InlineAsmRegClass::X86(X86InlineAsmRegClass::xmm_reg) => "movups",
+        InlineAsmRegClass::X86(X86InlineAsmRegClass::ymm_reg) => "vmovups",
+        InlineAsmRegClass::X86(X86InlineAsmRegClass::zmm_reg) => "vmovups",
+        InlineAsmRegClass::X86(X86InlineAsmRegClass::kreg) => "kmovq",
+        _ => "mov",
     }
 }
 
 fn save_register(generated_asm: &mut String, arch: InlineAsmArch, reg: InlineAsmReg, offset: Size) {
     match arch {
         InlineAsmArch::X86_64 => {
-            write!(generated_asm, "    mov [rbp+0x{:x}], ", offset.bytes()).unwrap();
+            write!(
+                generated_asm,
+                "    {} [rbp+0x{:x}], ",
+                x86_64_move_instruction(reg),
+                offset.bytes()
+            )
+            .unwrap();
             reg.emit(generated_asm, InlineAsmArch::X86_64, None)
                 .unwrap();
             generated_asm.push('\n');
@@ -283,7 +498,7 @@ fn restore_register(
 ) {
     match arch {
         InlineAsmArch::X86_64 => {
-            generated_asm.push_str("    mov ");
+            write!(generated_asm, "    {} ", x86_64_move_instruction(reg)).unwrap();
             reg.emit(generated_asm, InlineAsmArch::X86_64, None)
                 .unwrap();
             writeln!(generated_asm, ", [rbp+0x{:x}]", offset.bytes()).unwrap();
@@ -0,0 +1,195 @@
+//! Unified diffs between the clif ir of a function at different stages, used by
+//! [`write_clif_stage_diff`](crate::pretty_clif::write_clif_stage_diff).
+//!
+//! Every hunk header ends with the last comment before the hunk, which is usually the MIR statement
+//! the changed instructions were generated for:
+//!
+//! ```diff
+//! --- _ZN7example3foo17h6ad0b9ad93c4e3a1E.unopt.clif
+//! +++ _ZN7example3foo17h6ad0b9ad93c4e3a1E.stack2reg.clif
+//! @@ -24,4 +24,3 @@ ; _2 = _1
+//!      v3 = load.i32 v2
+//! -    store v3, v4
+//! -    v5 = load.i32 v4
+//! +    v5 -> v3
+//!      jump block2
+//! ```
+
+use std::fmt::Write;
+
+/// The number of unchanged lines shown before and after every change.
+const CONTEXT: usize = 3;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Append a unified diff from `old` to `new` to `diff`. Nothing is appended when they are equal.
+pub(crate) fn write_unified_diff(
+    diff: &mut String,
+    old_name: &str,
+    new_name: &str,
+    old: &str,
+    new: &str,
+) {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    let ops = diff_lines(&old, &new);
+    if ops.iter().all(|&op| op == Op::Equal) {
+        return;
+    }
+
+    // The number of lines of `old` and `new` before every op.
+    let mut old_pos = Vec::with_capacity(ops.len() + 1);
+    let mut new_pos = Vec::with_capacity(ops.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for &op in &ops {
+        old_pos.push(old_line);
+        new_pos.push(new_line);
+        if op != Op::Insert {
+            old_line += 1;
+        }
+        if op != Op::Delete {
+            new_line += 1;
+        }
+    }
+    old_pos.push(old_line);
+    new_pos.push(new_line);
+
+    writeln!(diff, "--- {}", old_name).unwrap();
+    writeln!(diff, "+++ {}", new_name).unwrap();
+
+    let changes = (0..ops.len())
+        .filter(|&i| ops[i] != Op::Equal)
+        .collect::<Vec<_>>();
+    let mut group_start = 0;
+    while group_start < changes.len() {
+        // Changes separated by at most twice the context are part of the same hunk.
+        let mut group_end = group_start + 1;
+        while group_end < changes.len()
+            && changes[group_end] - changes[group_end - 1] - 1 <= 2 * CONTEXT
+        {
+            group_end += 1;
+        }
+
+        let lo = changes[group_start].saturating_sub(CONTEXT);
+        let hi = (changes[group_end - 1] + 1 + CONTEXT).min(ops.len());
+        writeln!(
+            diff,
+            "@@ -{} +{} @@{}",
+            hunk_range(old_pos[lo], old_pos[hi]),
+            hunk_range(new_pos[lo], new_pos[hi]),
+            old[..old_pos[lo]]
+                .iter()
+                .rev()
+                .map(|line| line.trim())
+                .find(|line| line.starts_with(';'))
+                .map(|comment| format!(" {}", comment))
+                .unwrap_or_default(),
+        )
+        .unwrap();
+        for i in lo..hi {
+            match ops[i] {
+                Op::Equal => writeln!(diff, " {}", old[old_pos[i]]).unwrap(),
+                Op::Delete => writeln!(diff, "-{}", old[old_pos[i]]).unwrap(),
+                Op::Insert => writeln!(diff, "+{}", new[new_pos[i]]).unwrap(),
+            }
+        }
+
+        group_start = group_end;
+    }
+}
+
+fn hunk_range(start: usize, end: usize) -> String {
+    if start == end {
+        // An empty range refers to the line before it.
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, end - start)
+    }
+}
+
+/// The shortest edit script from `old` to `new`.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Op> {
+    // Most passes only change a small part of a function, so skip the common prefix and suffix
+    // before searching for the shortest edit script.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut ops = vec![Op::Equal; prefix];
+    ops.extend(myers(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    ));
+    ops.extend(std::iter::repeat(Op::Equal).take(suffix));
+    ops
+}
+
+/// Eugene W. Myers' "An O(ND) Difference Algorithm and Its Variations".
+fn myers(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = n + m;
+    // `v[k + offset]` is the furthest position in `old` reached on diagonal `k`.
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace = Vec::new();
+
+    let mut end_d = 0;
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                end_d = d;
+                break 'search;
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..=end_d).rev() {
+        let v = &trace[d as usize];
+        let k = x - y;
+        let idx = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            ops.push(if x == prev_x { Op::Insert } else { Op::Delete });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
@@ -6,18 +6,22 @@
     never_type,
     try_blocks,
     hash_drain_filter,
-    str_split_once
+    str_split_once,
+    once_cell
 )]
 #![warn(rust_2018_idioms)]
 #![warn(unused_lifetimes)]
 #![warn(unreachable_pub)]
 
-#[cfg(feature = "jit")]
+extern crate jobserver;
 extern crate libc;
 extern crate snap;
 #[macro_use]
 extern crate rustc_middle;
 extern crate rustc_ast;
+extern crate rustc_attr;
+#[cfg(feature = "llvm_fallback")]
+extern crate rustc_codegen_llvm;
 extern crate rustc_codegen_ssa;
 extern crate rustc_data_structures;
 extern crate rustc_errors;
@@ -25,6 +29,8 @@
 extern crate rustc_hir;
 extern crate rustc_incremental;
 extern crate rustc_index;
+extern crate rustc_interface;
+extern crate rustc_serialize;
 extern crate rustc_session;
 extern crate rustc_span;
 extern crate rustc_target;
@@ -42,7 +48,7 @@
 use rustc_middle::dep_graph::{WorkProduct, WorkProductId};
 use rustc_middle::middle::cstore::{EncodedMetadata, MetadataLoader};
 use rustc_middle::ty::query::Providers;
-use rustc_session::config::OutputFilenames;
+use rustc_session::config::{OptLevel, OutputFilenames};
 use rustc_session::Session;
 
 use cranelift_codegen::settings::{self, Configurable};
@@ -50,11 +56,13 @@
 use crate::constant::ConstantCx;
 use crate::prelude::*;
 
+#[cfg(feature = "jit")]
+pub use crate::driver::{decode_jit_test_rustc_args, JitHandle, JIT_TEST_RUSTC_ARGS_VAR};
+
 mod abi;
 mod allocator;
 mod analyze;
 mod archive;
-mod atomic_shim;
 mod backend;
 mod base;
 mod cast;
@@ -62,10 +70,12 @@
 mod common;
 mod constant;
 mod debuginfo;
+mod disasm;
 mod discriminant;
 mod driver;
 mod inline_asm;
 mod intrinsics;
+mod ir_diff;
 mod linkage;
 mod main_shim;
 mod metadata;
@@ -73,8 +83,10 @@
 mod optimize;
 mod pointer;
 mod pretty_clif;
+mod reduce;
 mod toolchain;
 mod trap;
+mod trap_metadata;
 mod unsize;
 mod value_and_place;
 mod vtable;
@@ -137,8 +149,18 @@ struct CodegenCx<'tcx, M: Module> {
     constants_cx: ConstantCx,
     cached_context: Context,
     vtables: FxHashMap<(Ty<'tcx>, Option<ty::PolyExistentialTraitRef<'tcx>>), DataId>,
-    debug_context: Option<DebugContext<'tcx>>,
-    unwind_context: UnwindContext<'tcx>,
+    debug_context: Option<DebugContext>,
+    type_debug_context: crate::debuginfo::TypeDebugContext<'tcx>,
+    unwind_context: UnwindContext,
+    /// The opt level for the cg_clif specific optimizations. This is the opt level of the session,
+    /// except for the tiered lazy JIT.
+    opt_level: OptLevel,
+    /// When set, unsupported constructs are recorded here and replaced with a trap, instead of
+    /// aborting compilation.
+    unsupported: Option<Vec<crate::trap::UnsupportedSite>>,
+    /// When set, trap sites are recorded in the object file instead of printing a message before
+    /// every trap, see [`crate::trap_metadata`].
+    trap_metadata: bool,
 }
 
 impl<'tcx, M: Module> CodegenCx<'tcx, M> {
@@ -157,11 +179,15 @@ fn new(tcx: TyCtxt<'tcx>, module: M, debug_info: bool, pic_eh_frame: bool) -> Se
             cached_context: Context::new(),
             vtables: FxHashMap::default(),
             debug_context,
+            type_debug_context: Default::default(),
             unwind_context,
+            opt_level: tcx.sess.opts.optimize,
+            unsupported: None,
+            trap_metadata: false,
         }
     }
 
-    fn finalize(mut self) -> (M, String, Option<DebugContext<'tcx>>, UnwindContext<'tcx>) {
+    fn finalize(mut self) -> (M, String, Option<DebugContext>, UnwindContext) {
         self.constants_cx.finalize(self.tcx, &mut self.module);
         (
             self.module,
@@ -201,27 +227,115 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
 #[derive(Copy, Clone, Debug, Default)]
 pub struct BackendConfig {
     pub codegen_mode: CodegenMode,
+    /// When set, the lazy JIT first compiles functions without optimizations and recompiles them
+    /// with optimizations once they have been called this many times.
+    pub jit_tier_up_threshold: Option<u32>,
+    /// When set, the lazy JIT watches the source files of the crate and applies changes to them to
+    /// the running program.
+    pub jit_watch: bool,
+    /// When set, codegen units which use something cg_clif doesn't support are compiled using the
+    /// LLVM backend instead.
+    pub llvm_fallback: bool,
+    /// When set, everything cg_clif doesn't support is replaced with a trap and reported in a JSON
+    /// file next to the crate outputs.
+    pub unsupported_report: bool,
 }
 
 impl BackendConfig {
     fn from_opts(opts: &[String]) -> Result<Self, String> {
         let mut config = BackendConfig::default();
+        // Not an `-Cllvm-args` option, as those are passed to LLVM too when it is used.
+        config.llvm_fallback = std::env::var("CG_CLIF_LLVM_FALLBACK").as_deref() == Ok("1");
         for opt in opts {
             if let Some((name, value)) = opt.split_once('=') {
                 match name {
                     "mode" => config.codegen_mode = value.parse()?,
+                    "jit-tier-up-threshold" => match value.parse::<u32>() {
+                        Ok(threshold) if threshold > 0 => {
+                            config.jit_tier_up_threshold = Some(threshold)
+                        }
+                        _ => {
+                            return Err(format!(
+                                "Invalid value `{}` for `jit-tier-up-threshold`",
+                                value
+                            ))
+                        }
+                    },
                     _ => return Err(format!("Unknown option `{}`", name)),
                 }
+            } else if opt == "jit-watch" {
+                config.jit_watch = true;
+            } else if opt == "unsupported-report" {
+                config.unsupported_report = true;
             } else {
                 return Err(format!("Invalid option `{}`", opt));
             }
         }
+        if config.jit_tier_up_threshold.is_some()
+            && !matches!(config.codegen_mode, CodegenMode::JitLazy)
+        {
+            return Err("`jit-tier-up-threshold` requires `mode=jit-lazy`".to_string());
+        }
+        if config.jit_watch {
+            if !matches!(config.codegen_mode, CodegenMode::JitLazy) {
+                return Err("`jit-watch` requires `mode=jit-lazy`".to_string());
+            }
+            if config.jit_tier_up_threshold.is_some() {
+                return Err(
+                    "`jit-watch` can't be combined with `jit-tier-up-threshold`".to_string()
+                );
+            }
+        }
+        if config.llvm_fallback {
+            if !matches!(config.codegen_mode, CodegenMode::Aot) {
+                return Err("`CG_CLIF_LLVM_FALLBACK` requires `mode=aot`".to_string());
+            }
+            if cfg!(not(feature = "llvm_fallback")) {
+                return Err(
+                    "`CG_CLIF_LLVM_FALLBACK` requires cg_clif to be built with the \
+                            `llvm_fallback` feature"
+                        .to_string(),
+                );
+            }
+        }
+        if config.unsupported_report && !matches!(config.codegen_mode, CodegenMode::Aot) {
+            return Err("`unsupported-report` requires `mode=aot`".to_string());
+        }
         Ok(config)
     }
 }
 
+#[derive(Default)]
 pub struct CraneliftCodegenBackend {
     pub config: Option<BackendConfig>,
+    /// When set, crates compiled with `-Cllvm-args=mode=jit` are JIT compiled as library and
+    /// passed to this callback instead of being run. This allows embedding the JIT in a program
+    /// using the rustc driver API.
+    #[cfg(feature = "jit")]
+    pub jit_library_callback: Option<Box<dyn Fn(JitHandle) + Send + Sync>>,
+}
+
+impl CraneliftCodegenBackend {
+    fn config(&self, sess: &Session) -> BackendConfig {
+        if let Some(config) = self.config {
+            config
+        } else {
+            BackendConfig::from_opts(&sess.opts.cg.llvm_args).unwrap_or_else(|err| sess.fatal(&err))
+        }
+    }
+
+    /// Whether the crate is JIT compiled as library and thus doesn't need to be linked.
+    fn is_jit_library(&self, sess: &Session) -> bool {
+        #[cfg(feature = "jit")]
+        {
+            if self.jit_library_callback.is_some() {
+                return !matches!(self.config(sess).codegen_mode, CodegenMode::Aot);
+            }
+        }
+
+        let _ = sess;
+        false
+    }
 }
 
 impl CodegenBackend for CraneliftCodegenBackend {
@@ -248,12 +362,17 @@ fn codegen_crate<'tcx>(
         metadata: EncodedMetadata,
         need_metadata_module: bool,
     ) -> Box<dyn Any> {
-        let config = if let Some(config) = self.config {
-            config
-        } else {
-            BackendConfig::from_opts(&tcx.sess.opts.cg.llvm_args)
-                .unwrap_or_else(|err| tcx.sess.fatal(&err))
-        };
+        let config = self.config(tcx.sess);
+
+        #[cfg(feature = "jit")]
+        {
+            if let Some(callback) = &self.jit_library_callback {
+                if !matches!(config.codegen_mode, CodegenMode::Aot) {
+                    return driver::jit_library(tcx, metadata, config, &**callback);
+                }
+            }
+        }
+
         let res = driver::codegen_crate(tcx, metadata, need_metadata_module, config);
 
         res
@@ -277,6 +396,10 @@ fn link(
     ) -> Result<(), ErrorReported> {
         use rustc_codegen_ssa::back::link::link_binary;
 
+        if self.is_jit_library(sess) || driver::is_jit_reload_session() {
+            return Ok(());
+        }
+
         let target_cpu = crate::target_triple(sess).to_string();
         link_binary::<crate::archive::ArArchiveBuilder<'_>>(
             sess,
@@ -295,6 +418,13 @@ fn target_triple(sess: &Session) -> target_lexicon::Triple {
 }
 
 fn build_isa(sess: &Session) -> Box<dyn isa::TargetIsa + 'static> {
+    build_isa_with_opt_level(sess, sess.opts.optimize)
+}
+
+fn build_isa_with_opt_level(
+    sess: &Session,
+    opt_level: OptLevel,
+) -> Box<dyn isa::TargetIsa + 'static> {
     use target_lexicon::BinaryFormat;
 
     let target_triple = crate::target_triple(sess);
@@ -323,8 +453,7 @@ fn build_isa(sess: &Session) -> Box<dyn isa::TargetIsa + 'static> {
 
     flags_builder.set("enable_simd", "true").unwrap();
 
-    use rustc_session::config::OptLevel;
-    match sess.opts.optimize {
+    match opt_level {
         OptLevel::No => {
             flags_builder.set("opt_level", "none").unwrap();
         }
@@ -339,12 +468,11 @@ fn build_isa(sess: &Session) -> Box<dyn isa::TargetIsa + 'static> {
 
     let flags = settings::Flags::new(flags_builder);
 
-    let variant = if cfg!(feature = "oldbe") {
-        cranelift_codegen::isa::BackendVariant::Legacy
-    } else {
-        cranelift_codegen::isa::BackendVariant::MachInst
-    };
-    let mut isa_builder = cranelift_codegen::isa::lookup_variant(target_triple, variant).unwrap();
+    let mut isa_builder = cranelift_codegen::isa::lookup_variant(
+        target_triple,
+        cranelift_codegen::isa::BackendVariant::MachInst,
+    )
+    .unwrap();
     // Don't use "haswell", as it implies `has_lzcnt`.macOS CI is still at Ivy Bridge EP, so `lzcnt`
     // is interpreted as `bsr`.
     isa_builder.enable("nehalem").unwrap();
@@ -354,5 +482,5 @@ fn build_isa(sess: &Session) -> Box<dyn isa::TargetIsa + 'static> {
 /// This is the entrypoint for a hot plugged rustc_codegen_cranelift
 #[no_mangle]
 pub fn __rustc_codegen_backend() -> Box<dyn CodegenBackend> {
-    Box::new(CraneliftCodegenBackend { config: None })
+    Box::new(CraneliftCodegenBackend::default())
 }
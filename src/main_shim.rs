@@ -4,12 +4,13 @@
 use crate::prelude::*;
 
 /// Create the `main` function which will initialize the rust runtime and call
-/// users main function.
+/// users main function. When `trap_handler` is true, it installs the trap handler of
+/// [`crate::trap_metadata`] first.
 pub(crate) fn maybe_create_entry_wrapper(
     tcx: TyCtxt<'_>,
     module: &mut impl Module,
-    unwind_context: &mut UnwindContext<'_>,
-    use_jit: bool,
+    unwind_context: &mut UnwindContext,
+    trap_handler: bool,
 ) {
     let (main_def_id, use_start_lang_item) = match tcx.entry_fn(LOCAL_CRATE) {
         Some((def_id, entry_ty)) => (
@@ -33,16 +34,16 @@ pub(crate) fn maybe_create_entry_wrapper(
         unwind_context,
         main_def_id,
         use_start_lang_item,
-        use_jit,
+        trap_handler,
     );
 
     fn create_entry_fn(
         tcx: TyCtxt<'_>,
         m: &mut impl Module,
-        unwind_context: &mut UnwindContext<'_>,
+        unwind_context: &mut UnwindContext,
         rust_main_def_id: DefId,
         use_start_lang_item: bool,
-        use_jit: bool,
+        trap_handler: bool,
     ) {
         let main_ret_ty = tcx.fn_sig(rust_main_def_id).output();
         // Given that `main()` has no arguments,
@@ -86,7 +87,9 @@ fn create_entry_fn(
             let arg_argc = bcx.append_block_param(block, m.target_config().pointer_type());
             let arg_argv = bcx.append_block_param(block, m.target_config().pointer_type());
 
-            crate::atomic_shim::init_global_lock(m, &mut bcx, use_jit);
+            if trap_handler {
+                crate::trap_metadata::codegen_install_trap_handler(m, unwind_context, &mut bcx);
+            }
 
             let main_func_ref = m.declare_func_in_func(main_func_id, &mut bcx.func);
 
@@ -1,5 +1,7 @@
 //! Various optimizations specific to cg_clif
 
+use rustc_session::config::OptLevel;
+
 use crate::prelude::*;
 
 mod code_layout;
@@ -8,6 +10,8 @@
 
 pub(crate) fn optimize_function<'tcx>(
     tcx: TyCtxt<'tcx>,
+    opt_level: OptLevel,
+    isa: &dyn isa::TargetIsa,
     instance: Instance<'tcx>,
     ctx: &mut Context,
     cold_blocks: &EntitySet<Block>,
@@ -16,10 +20,17 @@ pub(crate) fn optimize_function<'tcx>(
     // The code_layout optimization is very cheap.
     self::code_layout::optimize_function(ctx, cold_blocks);
 
-    if tcx.sess.opts.optimize == rustc_session::config::OptLevel::No {
+    if opt_level == OptLevel::No {
         return; // FIXME classify optimizations over opt levels
     }
     self::stack2reg::optimize_function(ctx, clif_comments);
-    crate::pretty_clif::write_clif_file(tcx, "stack2reg", None, instance, &ctx, &*clif_comments);
-    crate::base::verify_func(tcx, &*clif_comments, &ctx.func);
+    crate::pretty_clif::write_clif_file(
+        tcx.output_filenames(LOCAL_CRATE),
+        tcx.symbol_name(instance).name,
+        "stack2reg",
+        isa,
+        &ctx,
+        clif_comments,
+    );
+    crate::base::verify_func(tcx, isa, &*clif_comments, &ctx.func);
 }
@@ -54,7 +54,6 @@ pub(crate) fn dangling(align: Align) -> Self {
         }
     }
 
-    #[cfg(debug_assertions)]
     pub(crate) fn base_and_offset(self) -> (PointerBase, Offset32) {
         (self.base, self.offset)
     }
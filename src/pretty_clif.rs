@@ -54,6 +54,7 @@
 
 use std::fmt;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use cranelift_codegen::{
     entity::SecondaryMap,
@@ -62,20 +63,29 @@
 };
 
 use rustc_middle::ty::layout::FnAbiExt;
-use rustc_session::config::OutputType;
+use rustc_middle::ty::print::with_no_trimmed_paths;
+use rustc_session::config::{OutputFilenames, OutputType};
 use rustc_target::abi::call::FnAbi;
 
 use crate::prelude::*;
 
 #[derive(Debug)]
 pub(crate) struct CommentWriter {
+    enabled: bool,
+    dump_ir: bool,
+    /// The clif ir of every stage written so far when `CG_CLIF_DUMP_DIFF=1`.
+    dumped_stages: Vec<(String, String)>,
+    /// See [`crate::reduce::reproducer_file`].
+    reproducer_file: Option<PathBuf>,
     global_comments: Vec<String>,
     entity_comments: FxHashMap<AnyEntity, String>,
 }
 
 impl CommentWriter {
     pub(crate) fn new<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> Self {
-        let global_comments = if cfg!(debug_assertions) {
+        let dump_ir = should_write_ir(tcx) && should_dump_fn(tcx, instance);
+        let enabled = dump_ir || should_emit_clif(tcx) || crate::driver::is_bisected_crate(tcx);
+        let global_comments = if enabled {
             vec![
                 format!("symbol {}", tcx.symbol_name(instance).name),
                 format!("instance {:?}", instance),
@@ -90,14 +100,32 @@ pub(crate) fn new<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> Self {
         };
 
         CommentWriter {
+            enabled,
+            dump_ir,
+            dumped_stages: vec![],
+            reproducer_file: crate::reduce::reproducer_file(tcx, tcx.symbol_name(instance).name),
             global_comments,
             entity_comments: FxHashMap::default(),
         }
     }
 }
 
-#[cfg(debug_assertions)]
 impl CommentWriter {
+    /// Whether the clif ir is written at all. Comments should only be added when this is true.
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether the clif ir of this function is dumped after every stage, see [`should_dump_fn`].
+    pub(crate) fn dump_ir(&self) -> bool {
+        self.dump_ir
+    }
+
+    /// Where to write a reduced test case when this function fails to verify or compile.
+    pub(crate) fn reproducer_file(&self) -> Option<&Path> {
+        self.reproducer_file.as_deref()
+    }
+
     pub(crate) fn add_global_comment<S: Into<String>>(&mut self, comment: S) {
         self.global_comments.push(comment.into());
     }
@@ -185,7 +213,6 @@ fn write_instruction(
     }
 }
 
-#[cfg(debug_assertions)]
 impl<M: Module> FunctionCx<'_, '_, M> {
     pub(crate) fn add_global_comment<S: Into<String>>(&mut self, comment: S) {
         self.clif_comments.add_global_comment(comment);
@@ -200,25 +227,91 @@ pub(crate) fn add_comment<S: Into<String> + AsRef<str>, E: Into<AnyEntity>>(
     }
 }
 
-pub(crate) fn should_write_ir(tcx: TyCtxt<'_>) -> bool {
-    cfg!(debug_assertions)
-        || tcx
-            .sess
-            .opts
-            .output_types
-            .contains_key(&OutputType::LlvmAssembly)
+/// Whether the clif ir of every function is written to a separate file after every stage. Debug
+/// builds of cg_clif always do this, release builds only when `CG_CLIF_DUMP_FILTER` is set.
+pub(crate) fn should_write_ir(_tcx: TyCtxt<'_>) -> bool {
+    cfg!(debug_assertions) || std::env::var_os("CG_CLIF_DUMP_FILTER").is_some()
 }
 
-pub(crate) fn write_ir_file<'tcx>(
+/// Whether the function `instance` matches `CG_CLIF_DUMP_FILTER`. All functions match when it isn't
+/// set.
+fn should_dump_fn<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> bool {
+    match std::env::var("CG_CLIF_DUMP_FILTER") {
+        Ok(filter) => fn_matches_filter(tcx, instance, &filter),
+        Err(_) => true,
+    }
+}
+
+/// Whether the function `instance` matches `filter`, a comma separated list of glob patterns which
+/// are matched against both the symbol name and the path of the function, like
+/// `my_crate::*,*drop_in_place*`.
+pub(crate) fn fn_matches_filter<'tcx>(
     tcx: TyCtxt<'tcx>,
+    instance: Instance<'tcx>,
+    filter: &str,
+) -> bool {
+    let symbol_name = tcx.symbol_name(instance).name;
+    let path = with_no_trimmed_paths(|| tcx.def_path_str(instance.def_id()));
+    // Paths of local items don't start with the crate name.
+    let path = if instance.def_id().is_local() {
+        format!("{}::{}", tcx.crate_name(LOCAL_CRATE), path)
+    } else {
+        path
+    };
+    filter
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .any(|pattern| glob_matches(pattern, symbol_name) || glob_matches(pattern, &path))
+}
+
+/// Match `text` against `pattern`, where `*` matches any sequence of characters and `?` matches a
+/// single character.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // The position after the last `*` and the position in `text` it currently matches up to.
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether to write a unified diff between the stages of every dumped function instead of the
+/// clif ir of every stage, see [`write_clif_stage_diff`].
+fn should_diff_stages() -> bool {
+    std::env::var("CG_CLIF_DUMP_DIFF").as_deref() == Ok("1")
+}
+
+/// Whether the clif ir of every codegen unit is written to a `.clif` file. rustc doesn't accept
+/// unknown output types, so this uses `--emit=llvm-ir`.
+pub(crate) fn should_emit_clif(tcx: TyCtxt<'_>) -> bool {
+    tcx.sess
+        .opts
+        .output_types
+        .contains_key(&OutputType::LlvmAssembly)
+}
+
+pub(crate) fn write_ir_file(
+    output_filenames: &OutputFilenames,
     name: &str,
     write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
 ) {
-    if !should_write_ir(tcx) {
-        return;
-    }
-
-    let clif_output_dir = tcx.output_filenames(LOCAL_CRATE).with_extension("clif");
+    let clif_output_dir = output_filenames.with_extension("clif");
 
     match std::fs::create_dir(&clif_output_dir) {
         Ok(()) => {}
@@ -233,51 +326,116 @@ pub(crate) fn write_ir_file<'tcx>(
         write(&mut file)?;
     };
     if let Err(err) = res {
-        tcx.sess.warn(&format!("error writing ir file: {}", err));
+        // This may run on a codegen worker thread, where no `Session` is available.
+        rustc_session::early_warn(
+            rustc_session::config::ErrorOutputType::default(),
+            &format!("error writing ir file: {}", err),
+        );
     }
 }
 
-pub(crate) fn write_clif_file<'tcx>(
-    tcx: TyCtxt<'tcx>,
+pub(crate) fn write_clif_file(
+    output_filenames: &OutputFilenames,
+    symbol_name: &str,
     postfix: &str,
-    isa: Option<&dyn cranelift_codegen::isa::TargetIsa>,
-    instance: Instance<'tcx>,
+    isa: &dyn cranelift_codegen::isa::TargetIsa,
     context: &cranelift_codegen::Context,
-    mut clif_comments: &CommentWriter,
+    clif_comments: &mut CommentWriter,
 ) {
-    write_ir_file(
-        tcx,
-        &format!("{}.{}.clif", tcx.symbol_name(instance).name, postfix),
-        |file| {
-            let value_ranges = isa.map(|isa| {
-                context
-                    .build_value_labels_ranges(isa)
-                    .expect("value location ranges")
-            });
-
-            let mut clif = String::new();
-            cranelift_codegen::write::decorate_function(
-                &mut clif_comments,
-                &mut clif,
-                &context.func,
-                &DisplayFunctionAnnotations {
-                    isa: Some(&*crate::build_isa(tcx.sess)),
-                    value_ranges: value_ranges.as_ref(),
-                },
-            )
-            .unwrap();
-
-            writeln!(file, "test compile")?;
-            writeln!(file, "set is_pic")?;
-            writeln!(file, "set enable_simd")?;
-            writeln!(file, "target {} haswell", crate::target_triple(tcx.sess))?;
-            writeln!(file)?;
-            file.write_all(clif.as_bytes())?;
-            Ok(())
+    if !clif_comments.dump_ir {
+        return;
+    }
+
+    // Value ranges are only known once the function has been compiled.
+    let value_ranges = context.mach_compile_result.as_ref().map(|_| {
+        context
+            .build_value_labels_ranges(isa)
+            .expect("value location ranges")
+    });
+
+    let mut clif = clif_header(isa);
+    cranelift_codegen::write::decorate_function(
+        &mut &*clif_comments,
+        &mut clif,
+        &context.func,
+        &DisplayFunctionAnnotations {
+            isa: Some(isa),
+            value_ranges: value_ranges.as_ref(),
         },
+    )
+    .unwrap();
+
+    if should_diff_stages() {
+        clif_comments
+            .dumped_stages
+            .push((postfix.to_string(), clif));
+        return;
+    }
+
+    write_ir_file(
+        output_filenames,
+        &format!("{}.{}.clif", symbol_name, postfix),
+        |file| file.write_all(clif.as_bytes()),
     );
 }
 
+/// Write a unified diff between every two consecutive stages passed to [`write_clif_file`] to
+/// `<symbol>.diff` when `CG_CLIF_DUMP_DIFF=1`. The MIR statement comments are kept, so every change
+/// can be traced back to the MIR it was generated for.
+pub(crate) fn write_clif_stage_diff(
+    output_filenames: &OutputFilenames,
+    symbol_name: &str,
+    clif_comments: &CommentWriter,
+) {
+    if clif_comments.dumped_stages.is_empty() {
+        return;
+    }
+
+    let mut diff = String::new();
+    for stages in clif_comments.dumped_stages.windows(2) {
+        let (old_postfix, old) = &stages[0];
+        let (new_postfix, new) = &stages[1];
+        crate::ir_diff::write_unified_diff(
+            &mut diff,
+            &format!("{}.{}.clif", symbol_name, old_postfix),
+            &format!("{}.{}.clif", symbol_name, new_postfix),
+            old,
+            new,
+        );
+    }
+    write_ir_file(output_filenames, &format!("{}.diff", symbol_name), |file| {
+        file.write_all(diff.as_bytes())
+    });
+}
+
+/// The header of a clif file, which makes it loadable by the Cranelift filetest tooling.
+pub(crate) fn clif_header(isa: &dyn cranelift_codegen::isa::TargetIsa) -> String {
+    format!(
+        "test compile\nset is_pic\nset enable_simd\ntarget {} haswell\n\n",
+        isa.triple()
+    )
+}
+
+/// Append `func` together with the comments in `clif_comments` to `clif`.
+pub(crate) fn write_clif_function(
+    clif: &mut String,
+    isa: &dyn cranelift_codegen::isa::TargetIsa,
+    func: &Function,
+    mut clif_comments: &CommentWriter,
+) {
+    cranelift_codegen::write::decorate_function(
+        &mut clif_comments,
+        clif,
+        func,
+        &DisplayFunctionAnnotations {
+            isa: Some(isa),
+            value_ranges: None,
+        },
+    )
+    .unwrap();
+    clif.push('\n');
+}
+
 impl<M: Module> fmt::Debug for FunctionCx<'_, '_, M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{:?}", self.instance.substs)?;
@@ -0,0 +1,406 @@
+//! Reduces functions which fail to verify or which Cranelift fails to compile to minimal test
+//! cases, enabled using `CG_CLIF_REDUCE=1`.
+//!
+//! The failing function is shrunk by replacing terminators with traps, removing unreachable
+//! blocks, removing instructions, replacing instruction results and block params with constants
+//! for as long as it keeps failing the same way. A reduced function has to fail with the same first
+//! verifier error, the same codegen error or panic at the same location. Functions which Cranelift
+//! fails to compile are only reduced to functions which pass the verifier, as any crash can be
+//! reproduced using invalid clif ir. The reduced function is written as a standalone `test compile`
+//! file to `<crate>.clif/<symbol>.reduced.clif`, which can be run using `clif-util test`.
+
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use cranelift_codegen::cursor::{Cursor, FuncCursor};
+use cranelift_codegen::flowgraph::ControlFlowGraph;
+use cranelift_codegen::ir::Opcode;
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_codegen::CodegenError;
+use cranelift_module::ModuleError;
+
+use crate::prelude::*;
+
+/// The path to write the reduced test case for `symbol_name` to, if enabled.
+pub(crate) fn reproducer_file(tcx: TyCtxt<'_>, symbol_name: &str) -> Option<PathBuf> {
+    if std::env::var("CG_CLIF_REDUCE").as_deref() != Ok("1") {
+        return None;
+    }
+    Some(
+        tcx.output_filenames(LOCAL_CRATE)
+            .with_extension("clif")
+            .join(format!("{}.reduced.clif", symbol_name)),
+    )
+}
+
+/// Make `func` fail the verifier if `instance` matches `CG_CLIF_REDUCE_INJECT_ERROR`, a filter like
+/// `CG_CLIF_DUMP_FILTER`. This is used to test the reducer.
+pub(crate) fn maybe_inject_verifier_error<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    instance: Instance<'tcx>,
+    func: &mut Function,
+) {
+    match std::env::var("CG_CLIF_REDUCE_INJECT_ERROR") {
+        Ok(filter) if crate::pretty_clif::fn_matches_filter(tcx, instance, &filter) => {}
+        _ => return,
+    }
+
+    // Add an `i8` to an `i64`.
+    let entry_block = func.layout.entry_block().unwrap();
+    let mut pos = FuncCursor::new(func).at_first_insertion_point(entry_block);
+    let lhs = pos.ins().iconst(types::I8, 0);
+    let rhs = pos.ins().iconst(types::I64, 0);
+    pos.ins().iadd(lhs, rhs);
+}
+
+/// How a function fails. A reduced function has to fail the same way.
+enum Failure {
+    Verifier(String),
+    Codegen(String),
+    Panic { location: String, message: String },
+}
+
+impl Failure {
+    fn is_same(&self, other: &Failure) -> bool {
+        match (self, other) {
+            (Failure::Verifier(a), Failure::Verifier(b)) => a == b,
+            (Failure::Codegen(a), Failure::Codegen(b)) => a == b,
+            (Failure::Panic { location: a, .. }, Failure::Panic { location: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+
+    fn from_codegen_error(err: CodegenError) -> Self {
+        match err {
+            CodegenError::Verifier(errors) => Failure::Verifier(
+                errors
+                    .0
+                    .first()
+                    .map(|error| error.message.clone())
+                    .unwrap_or_default(),
+            ),
+            err => Failure::Codegen(err.to_string()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Failure::Verifier(message) => format!("verifier error: {}", message),
+            Failure::Codegen(message) => format!("codegen error: {}", message),
+            Failure::Panic { location, message } => {
+                format!("panicked at {}: {}", location, message)
+            }
+        }
+    }
+}
+
+/// Reduce `func`, which fails the verifier with `errors`, and write it to `reproducer_file`.
+pub(crate) fn reduce_verifier_error(
+    isa: &dyn TargetIsa,
+    func: &Function,
+    clif_comments: &crate::pretty_clif::CommentWriter,
+    errors: &cranelift_codegen::verifier::VerifierErrors,
+    reproducer_file: &Path,
+) -> std::io::Result<()> {
+    let failure = match errors.0.first() {
+        Some(error) => Failure::Verifier(error.message.clone()),
+        None => return write_test_case(isa, func, clif_comments, reproducer_file),
+    };
+    let func = reduce(func, |func| {
+        verify(func).map_or(false, |candidate| candidate.is_same(&failure))
+    });
+    write_test_case(isa, &func, clif_comments, reproducer_file)
+}
+
+/// Define the function in `context`, like `Module::define_function`. If Cranelift fails to compile
+/// it, the function is reduced and the compilation fails with a panic mentioning the reduced test
+/// case. Functions which compile fine are only compiled once.
+pub(crate) fn define_function_or_reduce(
+    module: &mut impl Module,
+    func_id: FuncId,
+    context: &mut Context,
+    trap_sink: &mut crate::trap_metadata::TrapSink,
+    clif_comments: &crate::pretty_clif::CommentWriter,
+    symbol_name: &str,
+    reproducer_file: &Path,
+) {
+    // Compiling modifies the function, so keep the original to start reducing from.
+    let func = context.func.clone();
+    let failure = match catch_panic(|| module.define_function(func_id, context, trap_sink)) {
+        Ok(Ok(_)) => return,
+        Ok(Err(ModuleError::Compilation(err))) => Failure::from_codegen_error(err),
+        Ok(Err(err)) => panic!("{:?}", err),
+        Err(failure) => failure,
+    };
+
+    let isa = module.isa();
+    let reduced = reduce(&func, |func| {
+        verify(func).is_none()
+            && compile(isa, func).map_or(false, |candidate| candidate.is_same(&failure))
+    });
+    match write_test_case(isa, &reduced, clif_comments, reproducer_file) {
+        Ok(()) => panic!(
+            "compiling `{}` failed with {}\nA reduced test case has been written to `{}`",
+            symbol_name,
+            failure.describe(),
+            reproducer_file.display(),
+        ),
+        Err(err) => panic!(
+            "compiling `{}` failed with {}\nError writing reduced test case: {}",
+            symbol_name,
+            failure.describe(),
+            err,
+        ),
+    }
+}
+
+fn verify(func: &Function) -> Option<Failure> {
+    let flags = cranelift_codegen::settings::Flags::new(cranelift_codegen::settings::builder());
+    let errors = cranelift_codegen::verify_function(func, &flags).err()?;
+    Some(Failure::Verifier(
+        errors
+            .0
+            .first()
+            .map(|error| error.message.clone())
+            .unwrap_or_default(),
+    ))
+}
+
+thread_local! {
+    static CATCHING_PANICS: Cell<bool> = Cell::new(false);
+    static PANIC_LOCATION: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Compile `func` in a fresh context, catching all panics.
+fn compile(isa: &dyn TargetIsa, func: &Function) -> Option<Failure> {
+    let mut context = Context::for_function(func.clone());
+    match catch_panic(|| context.compile(isa)) {
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => Some(Failure::from_codegen_error(err)),
+        Err(failure) => Some(failure),
+    }
+}
+
+/// Run `f`, turning a panic into a [`Failure`].
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, Failure> {
+    // Don't print a panic message for every failing candidate. Only panics on the current thread
+    // are silenced, as other threads may still be compiling other functions.
+    static SILENCE_CAUGHT_PANICS: Once = Once::new();
+    SILENCE_CAUGHT_PANICS.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING_PANICS.with(|catching| catching.get()) {
+                let location = info.location().map(|location| location.to_string());
+                PANIC_LOCATION.with(|panic_location| *panic_location.borrow_mut() = location);
+            } else {
+                default_hook(info);
+            }
+        }));
+    });
+
+    CATCHING_PANICS.with(|catching| catching.set(true));
+    let res = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING_PANICS.with(|catching| catching.set(false));
+
+    res.map_err(|payload| Failure::Panic {
+        location: PANIC_LOCATION
+            .with(|location| location.borrow_mut().take())
+            .unwrap_or_default(),
+        message: payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default(),
+    })
+}
+
+fn write_test_case(
+    isa: &dyn TargetIsa,
+    func: &Function,
+    clif_comments: &crate::pretty_clif::CommentWriter,
+    reproducer_file: &Path,
+) -> std::io::Result<()> {
+    let mut clif = crate::pretty_clif::clif_header(isa);
+    crate::pretty_clif::write_clif_function(&mut clif, isa, func, clif_comments);
+    if let Some(dir) = reproducer_file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(reproducer_file, clif)
+}
+
+/// Shrink `func` for as long as `still_fails` holds for the shrunk function.
+fn reduce(func: &Function, still_fails: impl Fn(&Function) -> bool) -> Function {
+    let mut func = func.clone();
+    loop {
+        let mut changed = false;
+
+        let insts = func
+            .layout
+            .blocks()
+            .flat_map(|block| func.layout.block_insts(block))
+            .collect::<Vec<_>>();
+        for &inst in insts.iter().rev() {
+            if func.layout.inst_block(inst).is_none() {
+                continue;
+            }
+            let mut candidate = func.clone();
+            if reduce_inst(&mut candidate, inst) && still_fails(&candidate) {
+                func = candidate;
+                changed = true;
+            }
+        }
+
+        let mut candidate = func.clone();
+        if remove_unreachable_blocks(&mut candidate) && still_fails(&candidate) {
+            func = candidate;
+            changed = true;
+        }
+
+        let params = func
+            .layout
+            .blocks()
+            .skip(1) // The params of the entry block are the function params.
+            .flat_map(|block| {
+                (0..func.dfg.num_block_params(block))
+                    .rev()
+                    .map(move |param| (block, param))
+            })
+            .collect::<Vec<_>>();
+        for (block, param) in params {
+            let mut candidate = func.clone();
+            if replace_block_param_with_const(&mut candidate, block, param)
+                && still_fails(&candidate)
+            {
+                func = candidate;
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return func;
+        }
+    }
+}
+
+/// Replace a terminator with a trap, remove a conditional branch or an instruction without results
+/// or replace the results of an instruction with constants.
+fn reduce_inst(func: &mut Function, inst: Inst) -> bool {
+    let opcode = func.dfg[inst].opcode();
+    if opcode.is_terminator() {
+        if opcode == Opcode::Trap {
+            return false;
+        }
+        func.dfg.replace(inst).trap(TrapCode::User(0));
+        return true;
+    }
+
+    let results = func.dfg.inst_results(inst).to_vec();
+    if results.is_empty() {
+        func.layout.remove_inst(inst);
+        return true;
+    }
+
+    if matches!(
+        opcode,
+        Opcode::Iconst | Opcode::F32const | Opcode::F64const | Opcode::Bconst
+    ) || !results
+        .iter()
+        .all(|&res| is_const_type(func.dfg.value_type(res)))
+    {
+        return false;
+    }
+    let mut pos = FuncCursor::new(func).at_inst(inst);
+    let consts = results
+        .iter()
+        .map(|&res| {
+            let ty = pos.func.dfg.value_type(res);
+            const_for_type(&mut pos, ty)
+        })
+        .collect::<Vec<_>>();
+    pos.func.dfg.detach_results(inst);
+    pos.func.layout.remove_inst(inst);
+    for (res, const_) in results.into_iter().zip(consts) {
+        pos.func.dfg.change_to_alias(res, const_);
+    }
+    true
+}
+
+fn remove_unreachable_blocks(func: &mut Function) -> bool {
+    let entry_block = match func.layout.entry_block() {
+        Some(entry_block) => entry_block,
+        None => return false,
+    };
+    let cfg = ControlFlowGraph::with_function(func);
+    let mut reachable = EntitySet::new();
+    let mut worklist = vec![entry_block];
+    while let Some(block) = worklist.pop() {
+        if !reachable.contains(block) {
+            reachable.insert(block);
+            worklist.extend(cfg.succ_iter(block));
+        }
+    }
+
+    let unreachable = func
+        .layout
+        .blocks()
+        .filter(|&block| !reachable.contains(block))
+        .collect::<Vec<_>>();
+    for &block in &unreachable {
+        while let Some(inst) = func.layout.first_inst(block) {
+            func.layout.remove_inst(inst);
+        }
+        func.layout.remove_block(block);
+    }
+    !unreachable.is_empty()
+}
+
+/// Replace block param number `param` of `block` with a constant and remove the corresponding
+/// argument from all branches to `block`.
+fn replace_block_param_with_const(func: &mut Function, block: Block, param: usize) -> bool {
+    let value = func.dfg.block_params(block)[param];
+    let ty = func.dfg.value_type(value);
+    let first_inst = match func.layout.first_inst(block) {
+        Some(first_inst) if is_const_type(ty) => first_inst,
+        _ => return false,
+    };
+
+    let cfg = ControlFlowGraph::with_function(func);
+    for pred in cfg.pred_iter(block) {
+        let num_fixed_args = func.dfg[pred.inst]
+            .opcode()
+            .constraints()
+            .num_fixed_value_arguments();
+        let mut args = match func.dfg[pred.inst].take_value_list() {
+            Some(args) => args,
+            None => continue,
+        };
+        if num_fixed_args + param < args.len(&func.dfg.value_lists) {
+            args.remove(num_fixed_args + param, &mut func.dfg.value_lists);
+        }
+        func.dfg[pred.inst].put_value_list(args);
+    }
+
+    func.dfg.remove_block_param(value);
+    let mut pos = FuncCursor::new(func).at_inst(first_inst);
+    let const_ = const_for_type(&mut pos, ty);
+    pos.func.dfg.change_to_alias(value, const_);
+    true
+}
+
+fn is_const_type(ty: Type) -> bool {
+    (ty.is_int() && ty.bits() <= 64) || ty == types::F32 || ty == types::F64 || ty.is_bool()
+}
+
+fn const_for_type(pos: &mut FuncCursor<'_>, ty: Type) -> Value {
+    if ty.is_int() {
+        pos.ins().iconst(ty, 0)
+    } else if ty == types::F32 {
+        pos.ins().f32const(0.0)
+    } else if ty == types::F64 {
+        pos.ins().f64const(0.0)
+    } else {
+        pos.ins().bconst(ty, false)
+    }
+}
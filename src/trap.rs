@@ -1,4 +1,5 @@
-//! Helpers used to print a message and abort in case of certain panics and some detected UB.
+//! Helpers used to print or record a message and abort in case of certain panics and some
+//! detected UB.
 
 use crate::prelude::*;
 
@@ -17,8 +18,7 @@ fn codegen_print(fx: &mut FunctionCx<'_, '_, impl Module>, msg: &str) {
         )
         .unwrap();
     let puts = fx.cx.module.declare_func_in_func(puts, &mut fx.bcx.func);
-    #[cfg(debug_assertions)]
-    {
+    if fx.clif_comments.enabled() {
         fx.add_comment(puts, "puts");
     }
 
@@ -28,10 +28,61 @@ fn codegen_print(fx: &mut FunctionCx<'_, '_, impl Module>, msg: &str) {
     fx.bcx.ins().call(puts, &[msg_ptr]);
 }
 
+/// Emit a trap with `msg` as reason. The message is either recorded in
+/// [`FunctionCx::trap_messages`] or printed before trapping. When `fill_block` is false, `trapnz`
+/// is used, so instructions can still be added to the current block afterwards.
+fn codegen_trap(
+    fx: &mut FunctionCx<'_, '_, impl Module>,
+    code: TrapCode,
+    msg: &str,
+    fill_block: bool,
+) -> Inst {
+    if fx.trap_messages.is_none() {
+        codegen_print(fx, msg);
+    }
+
+    let trap = if fill_block {
+        fx.bcx.ins().trap(code)
+    } else {
+        let true_ = fx.bcx.ins().iconst(types::I32, 1);
+        fx.bcx.ins().trapnz(true_, code)
+    };
+
+    if fx.trap_messages.is_some() {
+        let srcloc = fx.bcx.func.srclocs[trap];
+        let loc = fx
+            .tcx
+            .sess
+            .source_map()
+            .lookup_char_pos(trap_span(fx, trap).lo());
+        let message = crate::trap_metadata::TrapMessage {
+            location: format!("{}:{}:{}", loc.file.name, loc.line, loc.col_display + 1),
+            reason: msg.to_string(),
+        };
+        crate::trap_metadata::add_trap_message(
+            fx.trap_messages.as_mut().unwrap(),
+            srcloc,
+            code,
+            message,
+        );
+    }
+
+    trap
+}
+
+/// The span of the code `trap` was emitted for.
+fn trap_span(fx: &FunctionCx<'_, '_, impl Module>, trap: Inst) -> Span {
+    let srcloc = fx.bcx.func.srclocs[trap];
+    if srcloc.is_default() {
+        fx.mir.span
+    } else {
+        fx.source_info_set[srcloc.bits() as usize].span
+    }
+}
+
 /// Trap code: user1
 pub(crate) fn trap_abort(fx: &mut FunctionCx<'_, '_, impl Module>, msg: impl AsRef<str>) {
-    codegen_print(fx, msg.as_ref());
-    fx.bcx.ins().trap(TrapCode::User(1));
+    codegen_trap(fx, TrapCode::User(1), msg.as_ref(), true);
 }
 
 /// Use this for example when a function call should never return. This will fill the current block,
@@ -39,8 +90,7 @@ pub(crate) fn trap_abort(fx: &mut FunctionCx<'_, '_, impl Module>, msg: impl AsR
 ///
 /// Trap code: user65535
 pub(crate) fn trap_unreachable(fx: &mut FunctionCx<'_, '_, impl Module>, msg: impl AsRef<str>) {
-    codegen_print(fx, msg.as_ref());
-    fx.bcx.ins().trap(TrapCode::UnreachableCodeReached);
+    codegen_trap(fx, TrapCode::UnreachableCodeReached, msg.as_ref(), true);
 }
 
 /// Like `trap_unreachable` but returns a fake value of the specified type.
@@ -51,9 +101,7 @@ pub(crate) fn trap_unreachable_ret_value<'tcx>(
     dest_layout: TyAndLayout<'tcx>,
     msg: impl AsRef<str>,
 ) -> CValue<'tcx> {
-    codegen_print(fx, msg.as_ref());
-    let true_ = fx.bcx.ins().iconst(types::I32, 1);
-    fx.bcx.ins().trapnz(true_, TrapCode::UnreachableCodeReached);
+    codegen_trap(fx, TrapCode::UnreachableCodeReached, msg.as_ref(), false);
     CValue::by_ref(Pointer::const_addr(fx, 0), dest_layout)
 }
 
@@ -63,9 +111,55 @@ pub(crate) fn trap_unreachable_ret_value<'tcx>(
 ///
 /// Trap code: user65535
 pub(crate) fn trap_unimplemented(fx: &mut FunctionCx<'_, '_, impl Module>, msg: impl AsRef<str>) {
-    codegen_print(fx, msg.as_ref());
-    let true_ = fx.bcx.ins().iconst(types::I32, 1);
-    fx.bcx.ins().trapnz(true_, TrapCode::User(!0));
+    let trap = codegen_trap(fx, TrapCode::User(!0), msg.as_ref(), false);
+    let span = trap_span(fx, trap);
+    record_unsupported(fx, span, msg.as_ref(), false);
+}
+
+/// Use this when something is not supported at all. This aborts compilation, unless unsupported
+/// constructs are recorded in [`CodegenCx::unsupported`](crate::CodegenCx::unsupported). In that
+/// case this behaves like `trap_unimplemented`.
+///
+/// Trap code: user65535
+pub(crate) fn unsupported(
+    fx: &mut FunctionCx<'_, '_, impl Module>,
+    span: Span,
+    msg: impl AsRef<str>,
+) {
+    if fx.cx.unsupported.is_none() {
+        fx.tcx.sess.span_fatal(span, msg.as_ref());
+    }
+
+    codegen_trap(fx, TrapCode::User(!0), msg.as_ref(), false);
+    record_unsupported(fx, span, msg.as_ref(), true);
+}
+
+/// A use of something cg_clif doesn't support, see
+/// [`CodegenCx::unsupported`](crate::CodegenCx::unsupported).
+pub(crate) struct UnsupportedSite {
+    /// The function containing the unsupported construct.
+    pub(crate) instance: String,
+    pub(crate) span: Span,
+    pub(crate) reason: String,
+    /// Whether compilation would have been aborted, instead of replacing it with a trap.
+    pub(crate) fatal: bool,
+}
+
+fn record_unsupported(
+    fx: &mut FunctionCx<'_, '_, impl Module>,
+    span: Span,
+    reason: &str,
+    fatal: bool,
+) {
+    let instance = fx.instance;
+    if let Some(unsupported) = &mut fx.cx.unsupported {
+        unsupported.push(UnsupportedSite {
+            instance: instance.to_string(),
+            span,
+            reason: reason.to_string(),
+            fatal,
+        });
+    }
 }
 
 /// Like `trap_unimplemented` but returns a fake value of the specified type.
@@ -0,0 +1,436 @@
+//! Records trap sites in a dedicated section of the object file instead of printing a message
+//! before every trap. This is only supported for x86_64 Linux with glibc in AOT mode, see
+//! [`is_supported`]. Otherwise a message is printed using `puts` before every trap.
+//!
+//! Every trap site is recorded in the `cgclif_traps` section as five pointer sized words:
+//!
+//! 0. the address of the function containing the trap
+//! 1. the offset of the trap instruction from the start of the function
+//! 2. the trap code as nul-terminated string, like `user1`
+//! 3. the source location `file:line:column` as nul-terminated string, or null when unknown
+//! 4. the reason of the trap as nul-terminated string
+//!
+//! The records contain absolute addresses, so the section is writable. A read-only section would
+//! need text relocations in position independent executables.
+//!
+//! The `main` function of executables installs a SIGILL and SIGTRAP handler, see
+//! [`codegen_install_trap_handler`]. It looks up the trapping instruction in the records between
+//! the `__start_cgclif_traps` and `__stop_cgclif_traps` symbols defined by the linker, prints the
+//! reason and location of the trap together with a backtrace and then raises the signal again
+//! with the default action.
+//!
+//! As the trap handler references `__start_cgclif_traps`, the linker keeps all trap records even
+//! with `--gc-sections`, and with them the `.text` section of every object file containing a
+//! trap. Cranelift emits all functions of an object file into a single `.text` section, so the
+//! linker can't remove individual functions either way.
+
+use cranelift_codegen::binemit::{self, CodeOffset};
+
+use crate::prelude::*;
+
+const TRAP_SECTION: &str = "cgclif_traps";
+const RECORD_WORDS: u32 = 5;
+
+// Layout of the glibc and kernel types used by the trap handler on x86_64 Linux.
+const SIGILL: i64 = 4;
+const SIGTRAP: i64 = 5;
+const SA_SIGINFO: i32 = 0x4;
+const SA_RESETHAND: i32 = 0x8000_0000_u32 as i32;
+const SIGACTION_SIZE: u32 = 152;
+const SIGACTION_FLAGS_OFFSET: i32 = 136;
+/// The offset of `uc_mcontext.gregs[REG_RIP]` in `ucontext_t`.
+const UCONTEXT_PC_OFFSET: i32 = 168;
+const BACKTRACE_FRAMES: u32 = 64;
+const STDERR: i64 = 2;
+
+/// Whether trap sites are recorded in the trap section of the object file. The trap handler
+/// decoding them is only implemented for x86_64 Linux with glibc.
+pub(crate) fn is_supported(tcx: TyCtxt<'_>) -> bool {
+    let target = &tcx.sess.target;
+    target.arch == "x86_64" && target.os == "linux" && target.env == "gnu"
+}
+
+/// The reason of a trap emitted by cg_clif and the location of the code it was emitted for.
+pub(crate) struct TrapMessage {
+    pub(crate) location: String,
+    pub(crate) reason: String,
+}
+
+/// Cranelift only reports the source location and the trap code of every trap site, so the
+/// messages are keyed by those. Use [`add_trap_message`] to add messages.
+pub(crate) type TrapMessages = FxHashMap<(SourceLoc, TrapCode), TrapMessage>;
+
+/// Add the message of a trap site to `messages`. Traps with the same trap code emitted for the same
+/// MIR statement can't be told apart, so the first message is kept for all of them. The message
+/// notes when these traps have different reasons.
+pub(crate) fn add_trap_message(
+    messages: &mut TrapMessages,
+    srcloc: SourceLoc,
+    code: TrapCode,
+    message: TrapMessage,
+) {
+    match messages.entry((srcloc, code)) {
+        std::collections::hash_map::Entry::Occupied(mut entry) => {
+            let first = entry.get_mut();
+            if first.reason != message.reason && !first.reason.ends_with(AMBIGUOUS_TRAP_NOTE) {
+                first.reason.push_str(AMBIGUOUS_TRAP_NOTE);
+            }
+        }
+        std::collections::hash_map::Entry::Vacant(entry) => {
+            entry.insert(message);
+        }
+    }
+}
+
+const AMBIGUOUS_TRAP_NOTE: &str = " (or another trap at this location)";
+
+/// Collects all trap sites of a function while Cranelift emits it.
+#[derive(Default)]
+pub(crate) struct TrapSink {
+    traps: Vec<(CodeOffset, SourceLoc, TrapCode)>,
+}
+
+impl binemit::TrapSink for TrapSink {
+    fn trap(&mut self, offset: CodeOffset, srcloc: SourceLoc, code: TrapCode) {
+        self.traps.push((offset, srcloc, code));
+    }
+}
+
+/// Define the trap records for all traps of the function `func_id` collected in `trap_sink`.
+pub(crate) fn define_trap_records(
+    module: &mut impl Module,
+    func_id: FuncId,
+    symbol_name: &str,
+    trap_sink: TrapSink,
+    messages: &TrapMessages,
+) {
+    if trap_sink.traps.is_empty() {
+        return;
+    }
+
+    let ptr_size = u32::from(module.target_config().pointer_bytes());
+    let big_endian = module.isa().triple().endianness() == Ok(target_lexicon::Endianness::Big);
+
+    let mut data = Vec::<u8>::new();
+    for &(offset, _srcloc, _code) in &trap_sink.traps {
+        data.extend(std::iter::repeat(0).take(ptr_size as usize));
+        let offset = u64::from(offset);
+        if big_endian {
+            data.extend(&offset.to_be_bytes()[8 - ptr_size as usize..]);
+        } else {
+            data.extend(&offset.to_le_bytes()[..ptr_size as usize]);
+        }
+        data.extend(std::iter::repeat(0).take(((RECORD_WORDS - 2) * ptr_size) as usize));
+    }
+
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(data.into_boxed_slice());
+    data_ctx.set_align(u64::from(ptr_size));
+    // FIXME set correct segment for Mach-O files
+    data_ctx.set_segment_section("", TRAP_SECTION);
+
+    let func_ref = module.declare_func_in_data(func_id, &mut data_ctx);
+    let mut strings = FxHashMap::default();
+    for (i, &(_offset, srcloc, code)) in trap_sink.traps.iter().enumerate() {
+        let record = i as u32 * RECORD_WORDS * ptr_size;
+        data_ctx.write_function_addr(record, func_ref);
+
+        let message = messages.get(&(srcloc, code));
+        let mut write_str = |word: u32, string: &str| {
+            let global_value = *strings.entry(string.to_string()).or_insert_with(|| {
+                let data_id = define_c_str(module, string);
+                module.declare_data_in_data(data_id, &mut data_ctx)
+            });
+            data_ctx.write_data_addr(record + word * ptr_size, global_value, 0);
+        };
+        write_str(2, &code.to_string());
+        if let Some(message) = message {
+            write_str(3, &message.location);
+            write_str(4, &message.reason);
+        } else {
+            // Traps inserted by Cranelift itself.
+            write_str(4, &format!("trap in {}", symbol_name));
+        }
+    }
+
+    // Writable to avoid text relocations, see the module docs.
+    let data_id = module.declare_anonymous_data(true, false).unwrap();
+    module.define_data(data_id, &data_ctx).unwrap();
+}
+
+fn define_c_str(module: &mut impl Module, string: &str) -> DataId {
+    let mut bytes = string.as_bytes().to_vec();
+    bytes.push(0);
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(bytes.into_boxed_slice());
+    let data_id = module.declare_anonymous_data(false, false).unwrap();
+    module.define_data(data_id, &data_ctx).unwrap();
+    data_id
+}
+
+/// Install the trap handler for SIGILL and SIGTRAP using `sigaction`. This is called at the start
+/// of the `main` function generated by [`crate::main_shim`].
+///
+/// The trap handler only calls `write`, `strlen`, `raise`, `backtrace` and `backtrace_symbols_fd`.
+/// The latter two are safe to call in a signal handler once `backtrace` has been called before,
+/// which is done here.
+pub(crate) fn codegen_install_trap_handler(
+    m: &mut impl Module,
+    unwind_context: &mut UnwindContext,
+    bcx: &mut FunctionBuilder<'_>,
+) {
+    let ptr_ty = m.target_config().pointer_type();
+    let handler_id = define_trap_handler(m, unwind_context);
+    let handler = m.declare_func_in_func(handler_id, &mut bcx.func);
+    let sigaction = import_libc_fn(
+        m,
+        bcx,
+        "sigaction",
+        &[types::I32, ptr_ty, ptr_ty],
+        &[types::I32],
+    );
+
+    let act = bcx.create_stack_slot(StackSlotData {
+        kind: StackSlotKind::ExplicitSlot,
+        size: (SIGACTION_SIZE + 15) / 16 * 16,
+        offset: None,
+    });
+    let zero = bcx.ins().iconst(types::I64, 0);
+    for offset in (0..SIGACTION_SIZE).step_by(8) {
+        bcx.ins().stack_store(zero, act, offset as i32);
+    }
+    let handler_addr = bcx.ins().func_addr(ptr_ty, handler);
+    bcx.ins().stack_store(handler_addr, act, 0);
+    let flags = bcx
+        .ins()
+        .iconst(types::I32, i64::from(SA_SIGINFO | SA_RESETHAND));
+    bcx.ins().stack_store(flags, act, SIGACTION_FLAGS_OFFSET);
+
+    // The first call of `backtrace` loads libgcc_s, which allocates and takes locks. Neither is
+    // allowed in a signal handler, so do it before installing the trap handler.
+    let backtrace = import_libc_fn(m, bcx, "backtrace", &[ptr_ty, types::I32], &[types::I32]);
+    let frames = bcx.create_stack_slot(StackSlotData {
+        kind: StackSlotKind::ExplicitSlot,
+        size: u32::from(m.target_config().pointer_bytes()),
+        offset: None,
+    });
+    let frames = bcx.ins().stack_addr(ptr_ty, frames, 0);
+    let max_frames = bcx.ins().iconst(types::I32, 1);
+    bcx.ins().call(backtrace, &[frames, max_frames]);
+
+    let act = bcx.ins().stack_addr(ptr_ty, act, 0);
+    let null = bcx.ins().iconst(ptr_ty, 0);
+    for &signal in &[SIGILL, SIGTRAP] {
+        let signal = bcx.ins().iconst(types::I32, signal);
+        bcx.ins().call(sigaction, &[signal, act, null]);
+    }
+}
+
+/// Define `__cg_clif_trap_handler(signal, info, ucontext)`.
+fn define_trap_handler(m: &mut impl Module, unwind_context: &mut UnwindContext) -> FuncId {
+    let ptr_ty = m.target_config().pointer_type();
+    let ptr_size = i32::from(m.target_config().pointer_bytes());
+    let sig = Signature {
+        params: vec![
+            AbiParam::new(types::I32),
+            AbiParam::new(ptr_ty),
+            AbiParam::new(ptr_ty),
+        ],
+        returns: vec![],
+        call_conv: CallConv::triple_default(m.isa().triple()),
+    };
+    let func_id = m
+        .declare_function("__cg_clif_trap_handler", Linkage::Local, &sig)
+        .unwrap();
+
+    // Weak, so linking doesn't fail when there are no trap records at all.
+    let records_start = m
+        .declare_data(
+            &format!("__start_{}", TRAP_SECTION),
+            Linkage::Preemptible,
+            false,
+            false,
+        )
+        .unwrap();
+    let records_end = m
+        .declare_data(
+            &format!("__stop_{}", TRAP_SECTION),
+            Linkage::Preemptible,
+            false,
+            false,
+        )
+        .unwrap();
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, 0), sig);
+    {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut bcx = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+
+        let write = import_libc_fn(
+            m,
+            &mut bcx,
+            "write",
+            &[types::I32, ptr_ty, ptr_ty],
+            &[ptr_ty],
+        );
+        let strlen = import_libc_fn(m, &mut bcx, "strlen", &[ptr_ty], &[ptr_ty]);
+        let backtrace = import_libc_fn(
+            m,
+            &mut bcx,
+            "backtrace",
+            &[ptr_ty, types::I32],
+            &[types::I32],
+        );
+        let backtrace_symbols_fd = import_libc_fn(
+            m,
+            &mut bcx,
+            "backtrace_symbols_fd",
+            &[ptr_ty, types::I32, types::I32],
+            &[],
+        );
+        let raise = import_libc_fn(m, &mut bcx, "raise", &[types::I32], &[types::I32]);
+
+        let printer = Printer { write, strlen };
+
+        let entry_block = bcx.create_block();
+        let loop_block = bcx.create_block();
+        let check_block = bcx.create_block();
+        let found_block = bcx.create_block();
+        let location_block = bcx.create_block();
+        let not_found_block = bcx.create_block();
+        let backtrace_block = bcx.create_block();
+
+        bcx.switch_to_block(entry_block);
+        bcx.append_block_params_for_function_params(entry_block);
+        let signal = bcx.block_params(entry_block)[0];
+        let ucontext = bcx.block_params(entry_block)[2];
+        let pc = bcx
+            .ins()
+            .load(ptr_ty, MemFlags::trusted(), ucontext, UCONTEXT_PC_OFFSET);
+        let records_start = m.declare_data_in_func(records_start, &mut bcx.func);
+        let records_start = bcx.ins().symbol_value(ptr_ty, records_start);
+        let records_end = m.declare_data_in_func(records_end, &mut bcx.func);
+        let records_end = bcx.ins().symbol_value(ptr_ty, records_end);
+        bcx.ins().jump(loop_block, &[records_start]);
+
+        bcx.switch_to_block(loop_block);
+        let record = bcx.append_block_param(loop_block, ptr_ty);
+        let done = bcx
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, record, records_end);
+        bcx.ins().brnz(done, not_found_block, &[]);
+        bcx.ins().jump(check_block, &[]);
+
+        bcx.switch_to_block(check_block);
+        let function = bcx.ins().load(ptr_ty, MemFlags::trusted(), record, 0);
+        let offset = bcx
+            .ins()
+            .load(ptr_ty, MemFlags::trusted(), record, ptr_size);
+        let trap_pc = bcx.ins().iadd(function, offset);
+        let is_trap = bcx.ins().icmp(IntCC::Equal, trap_pc, pc);
+        bcx.ins().brnz(is_trap, found_block, &[]);
+        let next_record = bcx
+            .ins()
+            .iadd_imm(record, i64::from(RECORD_WORDS as i32 * ptr_size));
+        bcx.ins().jump(loop_block, &[next_record]);
+
+        bcx.switch_to_block(found_block);
+        let load_word = |bcx: &mut FunctionBuilder<'_>, word: i32| {
+            bcx.ins()
+                .load(ptr_ty, MemFlags::trusted(), record, word * ptr_size)
+        };
+        printer.print_str(m, &mut bcx, "trap: ");
+        let reason = load_word(&mut bcx, 4);
+        printer.print_c_str(&mut bcx, reason);
+        printer.print_str(m, &mut bcx, " (");
+        let code = load_word(&mut bcx, 2);
+        printer.print_c_str(&mut bcx, code);
+        printer.print_str(m, &mut bcx, ")\n");
+        let location = load_word(&mut bcx, 3);
+        bcx.ins().brz(location, backtrace_block, &[]);
+        bcx.ins().jump(location_block, &[]);
+
+        bcx.switch_to_block(location_block);
+        printer.print_str(m, &mut bcx, "  at ");
+        printer.print_c_str(&mut bcx, location);
+        printer.print_str(m, &mut bcx, "\n");
+        bcx.ins().jump(backtrace_block, &[]);
+
+        bcx.switch_to_block(not_found_block);
+        printer.print_str(m, &mut bcx, "trap at an unknown location\n");
+        bcx.ins().jump(backtrace_block, &[]);
+
+        bcx.switch_to_block(backtrace_block);
+        printer.print_str(m, &mut bcx, "backtrace:\n");
+        let frames = bcx.create_stack_slot(StackSlotData {
+            kind: StackSlotKind::ExplicitSlot,
+            size: BACKTRACE_FRAMES * ptr_size as u32,
+            offset: None,
+        });
+        let frames = bcx.ins().stack_addr(ptr_ty, frames, 0);
+        let max_frames = bcx.ins().iconst(types::I32, i64::from(BACKTRACE_FRAMES));
+        let call = bcx.ins().call(backtrace, &[frames, max_frames]);
+        let frame_count = bcx.inst_results(call)[0];
+        let stderr = bcx.ins().iconst(types::I32, STDERR);
+        bcx.ins()
+            .call(backtrace_symbols_fd, &[frames, frame_count, stderr]);
+        // The handler has been reset to the default action by `SA_RESETHAND`. The signal is
+        // blocked until the handler returns, so the process is terminated right after that.
+        bcx.ins().call(raise, &[signal]);
+        bcx.ins().return_(&[]);
+
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    m.define_function(
+        func_id,
+        &mut ctx,
+        &mut cranelift_codegen::binemit::NullTrapSink {},
+    )
+    .unwrap();
+    unwind_context.add_function(func_id, &ctx, m.isa());
+
+    func_id
+}
+
+/// Prints to stderr using `write`, which is async-signal-safe unlike the printing functions of
+/// libstd.
+struct Printer {
+    write: FuncRef,
+    strlen: FuncRef,
+}
+
+impl Printer {
+    fn print_c_str(&self, bcx: &mut FunctionBuilder<'_>, string: Value) {
+        let call = bcx.ins().call(self.strlen, &[string]);
+        let len = bcx.inst_results(call)[0];
+        let stderr = bcx.ins().iconst(types::I32, STDERR);
+        bcx.ins().call(self.write, &[stderr, string, len]);
+    }
+
+    fn print_str(&self, m: &mut impl Module, bcx: &mut FunctionBuilder<'_>, string: &str) {
+        let data_id = define_c_str(m, string);
+        let global_value = m.declare_data_in_func(data_id, &mut bcx.func);
+        let string = bcx
+            .ins()
+            .symbol_value(m.target_config().pointer_type(), global_value);
+        self.print_c_str(bcx, string);
+    }
+}
+
+fn import_libc_fn(
+    m: &mut impl Module,
+    bcx: &mut FunctionBuilder<'_>,
+    name: &str,
+    params: &[Type],
+    returns: &[Type],
+) -> FuncRef {
+    let sig = Signature {
+        params: params.iter().copied().map(AbiParam::new).collect(),
+        returns: returns.iter().copied().map(AbiParam::new).collect(),
+        call_conv: CallConv::triple_default(m.isa().triple()),
+    };
+    let func_id = m.declare_function(name, Linkage::Import, &sig).unwrap();
+    m.declare_func_in_func(func_id, &mut bcx.func)
+}
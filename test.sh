@@ -1,6 +1,16 @@
 #!/bin/bash
 set -e
 
+# The LLVM fallback can only be tested when cg_clif is built with it.
+if [[ " $* " == *" --llvm-fallback "* ]]; then
+    export CG_CLIF_TEST_LLVM_FALLBACK=1
+fi
+
+# scripts/tests.sh runs the jit_driver example from the target dir of the current channel.
+if [[ " $* " == *" --debug "* ]]; then
+    export CHANNEL=debug
+fi
+
 ./build.sh --sysroot none "$@"
 
 rm -r target/out || true